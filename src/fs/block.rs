@@ -30,7 +30,7 @@ use core::option::Option::{self, None, Some};
 use core::ptr::write_bytes;
 use core::result::Result::Ok;
 
-use crate::fs::{BlockDevice, DevResult, Storage};
+use crate::fs::{BlockDevice, CacheProvider, DevResult, Storage};
 use crate::{Slice, SliceMut};
 
 pub struct BlockBuffer {
@@ -72,7 +72,7 @@ impl BlockCache {
         let _ = self.0.take();
     }
     #[inline]
-    pub fn read_single<B: BlockDevice>(&mut self, dev: &Storage<B>, b: &mut Block, pos: u32) -> DevResult<()> {
+    pub fn read_single<B: BlockDevice, C: CacheProvider>(&mut self, dev: &Storage<B, C>, b: &mut Block, pos: u32) -> DevResult<()> {
         match self.0.replace(pos) {
             Some(v) if v != pos => dev.read_single(b, pos),
             None => dev.read_single(b, pos),
@@ -95,6 +95,14 @@ impl BlockBuffer {
         }
     }
 
+    /// Returns every Block for use as a plain transfer buffer, which drops
+    /// any loaded or dirty state.
+    #[inline]
+    pub(super) fn blocks_mut(&mut self) -> &mut [Block] {
+        self.status = 0;
+        &mut self.buf
+    }
+
     #[inline]
     pub fn is_dirty(&self, slot: u8) -> bool {
         match slot {
@@ -137,7 +145,7 @@ impl BlockBuffer {
             _ => unsafe { unreachable_unchecked() },
         }
     }
-    pub fn flush<B: BlockDevice>(&mut self, dev: &Storage<B>, start: u32) -> DevResult<()> {
+    pub fn flush<B: BlockDevice, C: CacheProvider>(&mut self, dev: &Storage<B, C>, start: u32) -> DevResult<()> {
         let s = self.status;
         self.status = s & 0xF;
         match unsafe { s.unchecked_shr(4) } {
@@ -184,7 +192,7 @@ impl BlockBuffer {
         Ok(())
     }
     #[inline]
-    pub fn read<B: BlockDevice>(&mut self, dev: &Storage<B>, count: u8, start: u32) -> DevResult<()> {
+    pub fn read<B: BlockDevice, C: CacheProvider>(&mut self, dev: &Storage<B, C>, count: u8, start: u32) -> DevResult<()> {
         let n = count.min(BlockBuffer::COUNT - 1) as usize;
         dev.read(unsafe { self.buf.get_unchecked_mut(0..n) }, start)?;
         self.status = match n {
@@ -225,13 +233,13 @@ impl BlockEntryIter {
         self.buf.buffer((pos - self.pos()) as u8)
     }
     #[inline]
-    pub fn flush<B: BlockDevice>(&mut self, dev: &Storage<B>) -> DevResult<()> {
+    pub fn flush<B: BlockDevice, C: CacheProvider>(&mut self, dev: &Storage<B, C>) -> DevResult<()> {
         if let Some(v) = self.prev.take() {
             self.buf.flush(dev, v)?;
         }
         Ok(())
     }
-    pub fn load<B: BlockDevice>(&mut self, dev: &Storage<B>, pos: u32) -> DevResult<()> {
+    pub fn load<B: BlockDevice, C: CacheProvider>(&mut self, dev: &Storage<B, C>, pos: u32) -> DevResult<()> {
         if self.last != 0 && pos < self.last {
             return Ok(());
         }
@@ -243,7 +251,7 @@ impl BlockEntryIter {
         Ok(())
     }
     #[inline]
-    pub fn load_and_flush<B: BlockDevice>(&mut self, dev: &Storage<B>, pos: u32) -> DevResult<()> {
+    pub fn load_and_flush<B: BlockDevice, C: CacheProvider>(&mut self, dev: &Storage<B, C>, pos: u32) -> DevResult<()> {
         self.flush(dev)?;
         self.load(dev, pos)
     }
@@ -0,0 +1,323 @@
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+
+#![no_implicit_prelude]
+
+extern crate core;
+#[cfg(feature = "pico")]
+extern crate rpsp;
+
+use core::cell::{Cell, UnsafeCell};
+use core::clone::Clone;
+use core::convert::From;
+use core::default::Default;
+use core::marker::{Copy, Sync};
+#[cfg(feature = "pico")]
+use core::mem::forget;
+use core::ops::{Deref, DerefMut, Drop};
+use core::option::Option::{self, None, Some};
+use core::ptr::NonNull;
+use core::result::Result::{Err, Ok};
+#[cfg(not(feature = "pico"))]
+use core::hint::spin_loop;
+#[cfg(not(feature = "pico"))]
+use core::sync::atomic::{AtomicU8, Ordering};
+
+#[cfg(feature = "pico")]
+use rpsp::locks::{Spinlock28, Spinlock29, Spinlock30};
+
+use crate::fs::{Block, DevResult, DeviceError, LongName};
+
+// Shared References are annoying, but this is the best way to handle this as
+// the Pico does not operate properly when creating large objects.
+//
+// The SharedCache keeps its buffers here and locks them with the hardware
+// Spinlocks, so it can be used by every Storage on both cores.
+static CACHE: Shared = Shared(UnsafeCell::new(CacheBuffers::new()));
+// Off the Pico there are no hardware Spinlocks, so each slot is a bit here.
+#[cfg(not(feature = "pico"))]
+static LOCKS: AtomicU8 = AtomicU8::new(0u8);
+
+/// The Buffers used by the fs layer, which are claimed individually through
+/// a CacheProvider.
+///
+/// The 'lfn' is only used for Directory search functions, 'block_a' is used for
+/// small File read/writes. This may be kept locked when a File is converted to
+/// a Reader and will be released when the Reader is dropped. 'block_b' is only
+/// used for the Directory search cache.
+pub struct CacheBuffers {
+    a:   Block,
+    b:   Block,
+    lfn: LongName,
+}
+/// CacheProvider backed by a static set of Buffers guarded by the hardware
+/// Spinlocks 28, 29 and 30 (or atomic flags when not built for the Pico).
+///
+/// This is the default and is shared by all Storage instances using it, which
+/// can be on either core.
+pub struct SharedCache;
+/// CacheProvider that owns its Buffers, for use by a single Storage (or
+/// multiple by reference) on the same core or on the host.
+///
+/// Claiming a buffer that is already in use returns a 'FileInUse' error
+/// instead of waiting, such as when a Reader still holds 'block_a', which
+/// fails most Volume calls and mounting a Volume until it's dropped.
+pub struct LocalCache {
+    buf:  UnsafeCell<CacheBuffers>,
+    used: Cell<u8>,
+}
+pub struct BlockPtr(NonNull<Block>, Option<Claim>);
+
+pub enum CacheSlot {
+    BlockA,
+    BlockB,
+    LongName,
+}
+
+/// Supplies and locks the Buffers used by a Storage and its Volumes.
+///
+/// # Safety
+///
+/// `buffers` must always return the same valid pointer for the life of the
+/// provider and `claim` must only return Ok once the caller has exclusive
+/// access to the slot, which lasts until the matching `release` call.
+pub unsafe trait CacheProvider {
+    fn buffers(&self) -> NonNull<CacheBuffers>;
+    fn claim(&self, slot: CacheSlot) -> DevResult<()>;
+    unsafe fn release(&self, slot: CacheSlot);
+}
+
+pub(super) struct LongNamePtr(NonNull<LongName>, Claim);
+
+struct Claim {
+    slot:  CacheSlot,
+    free:  unsafe fn(NonNull<()>, CacheSlot),
+    cache: NonNull<()>,
+}
+struct Shared(UnsafeCell<CacheBuffers>);
+
+impl Claim {
+    #[inline]
+    fn new<C: CacheProvider>(c: &C, slot: CacheSlot) -> DevResult<Claim> {
+        let _ = c.claim(slot)?;
+        Ok(Claim {
+            slot,
+            free: release::<C>,
+            cache: NonNull::from(c).cast(),
+        })
+    }
+
+    #[inline]
+    fn release(&self) {
+        unsafe { (self.free)(self.cache, self.slot) }
+    }
+}
+impl BlockPtr {
+    #[inline]
+    pub(super) fn claim<C: CacheProvider>(c: &C, b: bool) -> DevResult<BlockPtr> {
+        let (s, p) = if b {
+            (CacheSlot::BlockB, unsafe { &raw mut (*c.buffers().as_ptr()).b })
+        } else {
+            (CacheSlot::BlockA, unsafe { &raw mut (*c.buffers().as_ptr()).a })
+        };
+        Ok(BlockPtr(unsafe { NonNull::new_unchecked(p) }, Some(Claim::new(c, s)?)))
+    }
+
+    #[inline]
+    pub(super) unsafe fn nolock<C: CacheProvider>(c: &C) -> BlockPtr {
+        BlockPtr(unsafe { NonNull::new_unchecked(&raw mut (*c.buffers().as_ptr()).a) }, None)
+    }
+}
+impl CacheSlot {
+    #[inline]
+    fn mask(&self) -> u8 {
+        match self {
+            CacheSlot::BlockA => 0x1,
+            CacheSlot::BlockB => 0x2,
+            CacheSlot::LongName => 0x4,
+        }
+    }
+}
+impl LocalCache {
+    #[inline]
+    pub const fn new() -> LocalCache {
+        LocalCache {
+            buf:  UnsafeCell::new(CacheBuffers::new()),
+            used: Cell::new(0u8),
+        }
+    }
+}
+impl LongNamePtr {
+    #[inline]
+    pub(super) fn claim<C: CacheProvider>(c: &C) -> DevResult<LongNamePtr> {
+        Ok(LongNamePtr(
+            unsafe { NonNull::new_unchecked(&raw mut (*c.buffers().as_ptr()).lfn) },
+            Claim::new(c, CacheSlot::LongName)?,
+        ))
+    }
+}
+impl CacheBuffers {
+    #[inline]
+    pub const fn new() -> CacheBuffers {
+        CacheBuffers {
+            a:   Block::new(),
+            b:   Block::new(),
+            lfn: LongName::empty(),
+        }
+    }
+}
+
+unsafe impl CacheProvider for SharedCache {
+    #[inline]
+    fn buffers(&self) -> NonNull<CacheBuffers> {
+        unsafe { NonNull::new_unchecked(CACHE.0.get()) }
+    }
+    #[cfg(feature = "pico")]
+    #[inline]
+    fn claim(&self, slot: CacheSlot) -> DevResult<()> {
+        match slot {
+            CacheSlot::BlockA => forget(Spinlock29::claim()),
+            CacheSlot::BlockB => forget(Spinlock28::claim()),
+            CacheSlot::LongName => forget(Spinlock30::claim()),
+        }
+        Ok(())
+    }
+    #[cfg(not(feature = "pico"))]
+    #[inline]
+    fn claim(&self, slot: CacheSlot) -> DevResult<()> {
+        let m = slot.mask();
+        while LOCKS.fetch_or(m, Ordering::Acquire) & m != 0 {
+            spin_loop();
+        }
+        Ok(())
+    }
+    #[cfg(feature = "pico")]
+    #[inline]
+    unsafe fn release(&self, slot: CacheSlot) {
+        match slot {
+            CacheSlot::BlockA => unsafe { Spinlock29::free() },
+            CacheSlot::BlockB => unsafe { Spinlock28::free() },
+            CacheSlot::LongName => unsafe { Spinlock30::free() },
+        }
+    }
+    #[cfg(not(feature = "pico"))]
+    #[inline]
+    unsafe fn release(&self, slot: CacheSlot) {
+        LOCKS.fetch_and(!slot.mask(), Ordering::Release);
+    }
+}
+unsafe impl CacheProvider for LocalCache {
+    #[inline]
+    fn buffers(&self) -> NonNull<CacheBuffers> {
+        unsafe { NonNull::new_unchecked(self.buf.get()) }
+    }
+    #[inline]
+    fn claim(&self, slot: CacheSlot) -> DevResult<()> {
+        let (v, m) = (self.used.get(), slot.mask());
+        if v & m != 0 {
+            return Err(DeviceError::FileInUse);
+        }
+        self.used.set(v | m);
+        Ok(())
+    }
+    #[inline]
+    unsafe fn release(&self, slot: CacheSlot) {
+        self.used.set(self.used.get() & !slot.mask());
+    }
+}
+unsafe impl<T: CacheProvider> CacheProvider for &T {
+    #[inline]
+    fn buffers(&self) -> NonNull<CacheBuffers> {
+        (**self).buffers()
+    }
+    #[inline]
+    fn claim(&self, slot: CacheSlot) -> DevResult<()> {
+        (**self).claim(slot)
+    }
+    #[inline]
+    unsafe fn release(&self, slot: CacheSlot) {
+        unsafe { (**self).release(slot) }
+    }
+}
+
+impl Copy for CacheSlot {}
+impl Clone for CacheSlot {
+    #[inline]
+    fn clone(&self) -> CacheSlot {
+        *self
+    }
+}
+
+impl Default for LocalCache {
+    #[inline]
+    fn default() -> LocalCache {
+        LocalCache::new()
+    }
+}
+
+impl Drop for BlockPtr {
+    #[inline]
+    fn drop(&mut self) {
+        if let Some(c) = self.1.as_ref() {
+            c.release()
+        }
+    }
+}
+impl Deref for BlockPtr {
+    type Target = Block;
+
+    #[inline]
+    fn deref(&self) -> &Block {
+        unsafe { self.0.as_ref() }
+    }
+}
+impl DerefMut for BlockPtr {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut Block {
+        unsafe { &mut *self.0.as_ptr() }
+    }
+}
+
+impl Drop for LongNamePtr {
+    #[inline]
+    fn drop(&mut self) {
+        self.1.release()
+    }
+}
+impl Deref for LongNamePtr {
+    type Target = LongName;
+
+    #[inline]
+    fn deref(&self) -> &LongName {
+        unsafe { self.0.as_ref() }
+    }
+}
+impl DerefMut for LongNamePtr {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut LongName {
+        unsafe { &mut *self.0.as_ptr() }
+    }
+}
+
+unsafe impl Sync for Shared {}
+
+#[inline]
+unsafe fn release<C: CacheProvider>(c: NonNull<()>, slot: CacheSlot) {
+    unsafe { c.cast::<C>().as_ref().release(slot) }
+}
@@ -20,18 +20,23 @@
 #![no_implicit_prelude]
 
 extern crate core;
+#[cfg(feature = "pico")]
 extern crate rpsp;
 
 use core::cell::UnsafeCell;
+#[cfg(feature = "pico")]
 use core::convert::From;
-use core::fmt::{self, Debug, Formatter};
-use core::result::Result::{self, Err};
+use core::error::Error as CoreError;
+use core::fmt::{self, Debug, Display, Formatter};
+use core::matches;
+use core::result::Result::{self, Err, Ok};
 use core::slice::{from_raw_parts, from_raw_parts_mut};
 
+#[cfg(feature = "pico")]
 use rpsp::io;
 
 use crate::Slice;
-use crate::fs::{Block, Cache, Volume};
+use crate::fs::{Block, BlockPtr, CacheProvider, Format, LongNamePtr, SharedCache, Volume, gpt};
 
 pub enum DeviceError {
     // Standard IO Errors
@@ -44,6 +49,8 @@ pub enum DeviceError {
     UnexpectedEoF,
     NotADirectory,
     NonEmptyDirectory,
+    FileInUse,
+    AlreadyExists,
 
     // Hardware-ish/Io Errors
     Read,
@@ -69,8 +76,9 @@ pub enum DeviceError {
     UnsupportedVolume(u8),
 }
 
-pub struct Storage<B: BlockDevice> {
-    dev: UnsafeCell<B>,
+pub struct Storage<B: BlockDevice, C: CacheProvider = SharedCache> {
+    dev:   UnsafeCell<B>,
+    cache: C,
 }
 
 pub trait BlockDevice {
@@ -78,6 +86,14 @@ pub trait BlockDevice {
     fn write(&mut self, b: &[Block], start: u32) -> DevResult<()>;
     fn read(&mut self, b: &mut [Block], start: u32) -> DevResult<()>;
 
+    /// Returns true if the device can't be written to, like when the write
+    /// protect switch of a card is set.
+    ///
+    /// Volumes on a read only device are always mounted read only.
+    #[inline]
+    fn is_read_only(&mut self) -> bool {
+        false
+    }
     #[inline]
     fn write_single(&mut self, b: &Block, start: u32) -> DevResult<()> {
         let v = unsafe { from_raw_parts(b, 1) };
@@ -90,15 +106,33 @@ pub trait BlockDevice {
     }
 }
 
+#[cfg(feature = "pico")]
 pub type Error = io::Error<DeviceError>;
 
+#[cfg(feature = "pico")]
 pub type DevError = io::Error<DeviceError>;
 pub type DevResult<T> = Result<T, DeviceError>;
 
 impl<B: BlockDevice> Storage<B> {
+    /// Create a Storage that uses the SharedCache, which is locked by the
+    /// hardware Spinlocks.
     #[inline]
     pub const fn new(dev: B) -> Storage<B> {
-        Storage { dev: UnsafeCell::new(dev) }
+        Storage {
+            dev:   UnsafeCell::new(dev),
+            cache: SharedCache,
+        }
+    }
+}
+impl<B: BlockDevice, C: CacheProvider> Storage<B, C> {
+    /// Create a Storage that uses the supplied CacheProvider for its Block and
+    /// name Buffers instead of the SharedCache.
+    ///
+    /// Use a 'LocalCache' (or a reference to one) to keep the Buffers with
+    /// this Storage, so it does not contend with any other Storage.
+    #[inline]
+    pub const fn with_cache(dev: B, cache: C) -> Storage<B, C> {
+        Storage { dev: UnsafeCell::new(dev), cache }
     }
 
     #[inline]
@@ -106,13 +140,25 @@ impl<B: BlockDevice> Storage<B> {
         unsafe { &mut *self.dev.get() }
     }
     #[inline]
-    pub fn root<'a>(&'a self) -> DevResult<Volume<'a, B>> {
+    pub fn cache(&self) -> &C {
+        &self.cache
+    }
+    #[inline]
+    pub fn root<'a>(&'a self) -> DevResult<Volume<'a, B, C>> {
         self.volume(0)
     }
     #[inline]
     pub fn write(&self, b: &[Block], start: u32) -> DevResult<()> {
         self.device().write(b, start)
     }
+    /// Format the device with a single FAT partition using the supplied
+    /// options.
+    ///
+    /// This will destroy ANY existing data on the device.
+    #[inline]
+    pub fn format(&self, opts: &Format) -> DevResult<()> {
+        opts.format(self)
+    }
     #[inline]
     pub fn read(&self, b: &mut [Block], start: u32) -> DevResult<()> {
         self.device().read(b, start)
@@ -121,20 +167,72 @@ impl<B: BlockDevice> Storage<B> {
     pub fn write_single(&self, b: &Block, start: u32) -> DevResult<()> {
         self.device().write_single(b, start)
     }
+    /// Mount the Volume with the boot sector at the supplied LBA, ignoring any
+    /// partition table on the device.
+    #[inline]
+    pub fn volume_at<'a>(&'a self, lba: u32) -> DevResult<Volume<'a, B, C>> {
+        let mut b = self.block_a()?;
+        let _ = self.read_single(&mut b, lba)?;
+        if !is_boot_sector(&b) {
+            return Err(DeviceError::InvalidFileSystem);
+        }
+        Volume::new(self, &mut b, lba, 0)
+    }
     #[inline]
     pub fn read_single(&self, b: &mut Block, start: u32) -> DevResult<()> {
         self.device().read_single(b, start)
     }
+    /// Mount the Volume at the partition index as read only, which makes sure
+    /// nothing on the device is written while it's mounted.
+    ///
+    /// See 'volume' for the details on the partition index.
     #[inline]
-    pub fn volume<'a>(&'a self, index: usize) -> DevResult<Volume<'a, B>> {
-        if index > 3 {
-            return Err(DeviceError::NotFound);
-        }
-        let mut b = Cache::block_a();
+    pub fn volume_read_only<'a>(&'a self, index: usize) -> DevResult<Volume<'a, B, C>> {
+        let mut v = self.volume(index)?;
+        v.set_read_only(true);
+        Ok(v)
+    }
+
+    #[inline]
+    pub(super) fn lfn(&self) -> DevResult<LongNamePtr> {
+        LongNamePtr::claim(&self.cache)
+    }
+    #[inline]
+    pub(super) fn block_a(&self) -> DevResult<BlockPtr> {
+        BlockPtr::claim(&self.cache, false)
+    }
+    #[inline]
+    pub(super) fn block_b(&self) -> DevResult<BlockPtr> {
+        BlockPtr::claim(&self.cache, true)
+    }
+    #[inline]
+    pub(super) unsafe fn block_a_nolock(&self) -> BlockPtr {
+        unsafe { BlockPtr::nolock(&self.cache) }
+    }
+    /// Mount the Volume at the partition index. The index is the MBR entry
+    /// (0 - 3) or the GPT partition entry when the device uses a GPT.
+    ///
+    /// Only Microsoft Basic Data and EFI System GPT partitions can be mounted.
+    /// Devices without a partition table only have the Volume at index 0.
+    pub fn volume<'a>(&'a self, index: usize) -> DevResult<Volume<'a, B, C>> {
+        let mut b = self.block_a()?;
         let _ = self.read_single(&mut b, 0)?;
         if b.read_u16(510) != 0xAA55 {
             return Err(DeviceError::InvalidPartition);
         }
+        // NOTE(sf): Unpartitioned "superfloppy" devices have the boot sector
+        //           at LBA 0, which only has a single Volume.
+        if is_boot_sector(&b) {
+            return if index == 0 { Volume::new(self, &mut b, 0, 0) } else { Err(DeviceError::NotFound) };
+        }
+        // 0xEE - Protective MBR, the real partition table is the GPT.
+        if b.read_u8(0x1C2) == 0xEE {
+            let (s, n) = gpt::partition(self, &mut b, index)?;
+            return Volume::new(self, &mut b, s, n);
+        }
+        if index > 3 {
+            return Err(DeviceError::NotFound);
+        }
         let i = 0x1BE + (16 * index);
         if i + 16 > Block::SIZE {
             return Err(DeviceError::NotFound);
@@ -170,6 +268,8 @@ impl Debug for DeviceError {
             DeviceError::UnexpectedEoF => f.write_str("UnexpectedEoF"),
             DeviceError::NotADirectory => f.write_str("NotADirectory"),
             DeviceError::NonEmptyDirectory => f.write_str("NonEmptyDirectory"),
+            DeviceError::FileInUse => f.write_str("FileInUse"),
+            DeviceError::AlreadyExists => f.write_str("AlreadyExists"),
             DeviceError::BadData => f.write_str("BadData"),
             DeviceError::NoSpace => f.write_str("NoSpace"),
             DeviceError::Hardware(v) => f.debug_tuple("Hardware").field(v).finish(),
@@ -194,6 +294,15 @@ impl Debug for DeviceError {
     }
 }
 
+impl Display for DeviceError {
+    #[inline]
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        Debug::fmt(self, f)
+    }
+}
+impl CoreError for DeviceError {}
+
+#[cfg(feature = "pico")]
 impl From<DeviceError> for DevError {
     #[inline]
     fn from(v: DeviceError) -> DevError {
@@ -217,3 +326,27 @@ impl From<DeviceError> for DevError {
         }
     }
 }
+
+fn is_boot_sector(b: &[u8]) -> bool {
+    // 0x0 - Jump Instruction
+    match (b.read_u8(0), b.read_u8(2)) {
+        (0xEB, 0x90) | (0xE9, _) => (),
+        _ => return false,
+    }
+    // 0x3 - OEM Name, exFAT has no BPB so nothing else can be checked.
+    if b.read_slice(3, 8) == b"EXFAT   " {
+        return b.read_u16(510) == 0xAA55;
+    }
+    // 0x1FE - Boot Sector Signature
+    // 0xB - Bytes per Logical Sector
+    // 0xD - Logical Sectors per Cluster
+    // 0xE - Count of Reserved Logical Sectors
+    // 0x10 - Number of FATs
+    // 0x15 - Media Descriptor
+    b.read_u16(510) == 0xAA55
+        && b.read_u16(11) as usize == Block::SIZE
+        && b.read_u8(13).is_power_of_two()
+        && b.read_u16(14) > 0
+        && matches!(b.read_u8(16), 1 | 2)
+        && b.read_u8(21) >= 0xF0
+}
@@ -0,0 +1,242 @@
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+
+#![no_implicit_prelude]
+
+extern crate core;
+extern crate embedded_io;
+extern crate embedded_storage;
+
+use core::cmp::Ord;
+use core::convert::TryInto;
+use core::option::Option::Some;
+use core::ptr::copy_nonoverlapping;
+use core::result::Result::{self, Err, Ok};
+
+use embedded_io::{ErrorKind, ErrorType, Read, Seek, SeekFrom, Write};
+use embedded_storage::{ReadStorage, Storage as WriteStorage};
+
+use crate::fs::{Block, BlockDevice, CacheProvider, DevResult, DeviceError, File, FileSync, Reader, Storage};
+
+/// Byte stream over a BlockDevice, for use with the 'embedded-io' traits.
+///
+/// Reads and writes that don't cover a whole Block read the Block first, so
+/// the stream is fastest when used with Block sized and aligned buffers.
+pub struct BlockIo<B: BlockDevice> {
+    dev: B,
+    pos: u64,
+    buf: Block,
+}
+
+impl<B: BlockDevice> BlockIo<B> {
+    #[inline]
+    pub const fn new(dev: B) -> BlockIo<B> {
+        BlockIo { dev, pos: 0u64, buf: Block::new() }
+    }
+
+    #[inline]
+    pub fn position(&self) -> u64 {
+        self.pos
+    }
+    #[inline]
+    pub fn device(&mut self) -> &mut B {
+        &mut self.dev
+    }
+    #[inline]
+    pub fn into_inner(self) -> B {
+        self.dev
+    }
+}
+
+impl embedded_io::Error for DeviceError {
+    #[inline]
+    fn kind(&self) -> ErrorKind {
+        match self {
+            DeviceError::NotFound => ErrorKind::NotFound,
+            DeviceError::Timeout => ErrorKind::TimedOut,
+            DeviceError::NoSpace => ErrorKind::OutOfMemory,
+            DeviceError::FileInUse => ErrorKind::AddrInUse,
+            DeviceError::AlreadyExists => ErrorKind::AlreadyExists,
+            DeviceError::NotReadable | DeviceError::NotWritable => ErrorKind::PermissionDenied,
+            DeviceError::UnsupportedVolume(_) | DeviceError::UnsupportedFileSystem => ErrorKind::Unsupported,
+            DeviceError::NotAFile | DeviceError::NotADirectory | DeviceError::Overflow | DeviceError::InvalidIndex | DeviceError::NameTooLong | DeviceError::InvalidOptions => ErrorKind::InvalidInput,
+            DeviceError::BadData
+            | DeviceError::InvalidChain
+            | DeviceError::InvalidVolume
+            | DeviceError::InvalidCluster
+            | DeviceError::InvalidChecksum
+            | DeviceError::InvalidPartition
+            | DeviceError::InvalidFileSystem => ErrorKind::InvalidData,
+            _ => ErrorKind::Other,
+        }
+    }
+}
+
+impl<B: BlockDevice> ErrorType for BlockIo<B> {
+    type Error = DeviceError;
+}
+impl<B: BlockDevice> Seek for BlockIo<B> {
+    fn seek(&mut self, s: SeekFrom) -> Result<u64, DeviceError> {
+        let n = self.dev.blocks()? as u64 * Block::SIZE as u64;
+        let r = match s {
+            SeekFrom::Start(v) => Some(v),
+            SeekFrom::End(v) => n.checked_add_signed(v),
+            SeekFrom::Current(v) => self.pos.checked_add_signed(v),
+        };
+        self.pos = match r {
+            Some(v) if v <= n => v,
+            _ => return Err(DeviceError::InvalidIndex),
+        };
+        Ok(self.pos)
+    }
+}
+impl<B: BlockDevice> Read for BlockIo<B> {
+    #[inline]
+    fn read(&mut self, b: &mut [u8]) -> Result<usize, DeviceError> {
+        let n = read_at(&mut self.dev, &mut self.buf, self.pos, b)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+impl<B: BlockDevice> Write for BlockIo<B> {
+    #[inline]
+    fn flush(&mut self) -> Result<(), DeviceError> {
+        Ok(())
+    }
+    #[inline]
+    fn write(&mut self, b: &[u8]) -> Result<usize, DeviceError> {
+        let n = write_at(&mut self.dev, &mut self.buf, self.pos, b)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<B: BlockDevice, S: FileSync, C: CacheProvider> ErrorType for File<'_, B, S, C> {
+    type Error = DeviceError;
+}
+impl<B: BlockDevice, S: FileSync, C: CacheProvider> Seek for File<'_, B, S, C> {
+    #[inline]
+    fn seek(&mut self, s: SeekFrom) -> Result<u64, DeviceError> {
+        match s {
+            SeekFrom::End(v) => self.seek_at(self.size(), v),
+            SeekFrom::Start(v) => self.seek_at(0, v.try_into().or(Err(DeviceError::Overflow))?),
+            SeekFrom::Current(v) => self.seek_at(self.cursor() as u32, v),
+        }
+    }
+}
+impl<B: BlockDevice, S: FileSync, C: CacheProvider> Read for File<'_, B, S, C> {
+    #[inline]
+    fn read(&mut self, b: &mut [u8]) -> Result<usize, DeviceError> {
+        self.read(b)
+    }
+}
+impl<B: BlockDevice, S: FileSync, C: CacheProvider> Write for File<'_, B, S, C> {
+    #[inline]
+    fn flush(&mut self) -> Result<(), DeviceError> {
+        self.flush()
+    }
+    #[inline]
+    fn write(&mut self, b: &[u8]) -> Result<usize, DeviceError> {
+        self.write(b)
+    }
+}
+
+impl<B: BlockDevice, C: CacheProvider> ErrorType for Reader<'_, B, C> {
+    type Error = DeviceError;
+}
+impl<B: BlockDevice, C: CacheProvider> Seek for Reader<'_, B, C> {
+    #[inline]
+    fn seek(&mut self, s: SeekFrom) -> Result<u64, DeviceError> {
+        match s {
+            SeekFrom::End(v) => self.seek_at(self.size(), v),
+            SeekFrom::Start(v) => self.seek_at(0, v.try_into().or(Err(DeviceError::Overflow))?),
+            SeekFrom::Current(v) => self.seek_at(self.cursor() as u32, v),
+        }
+    }
+}
+impl<B: BlockDevice, C: CacheProvider> Read for Reader<'_, B, C> {
+    #[inline]
+    fn read(&mut self, b: &mut [u8]) -> Result<usize, DeviceError> {
+        self.read(b)
+    }
+}
+
+/// Storage as an 'embedded-storage' device.
+///
+/// Reads and writes use their own Block instead of a cache Block, so they
+/// don't wait on a SharedCache slot held by another Storage.
+impl<B: BlockDevice, C: CacheProvider> ReadStorage for Storage<B, C> {
+    type Error = DeviceError;
+
+    #[inline]
+    fn capacity(&self) -> usize {
+        self.device().blocks().map_or(0, |v| (v as usize).saturating_mul(Block::SIZE))
+    }
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), DeviceError> {
+        let _ = check_range(self, offset, bytes.len())?;
+        let _ = read_at(self.device(), &mut Block::new(), offset as u64, bytes)?;
+        Ok(())
+    }
+}
+impl<B: BlockDevice, C: CacheProvider> WriteStorage for Storage<B, C> {
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), DeviceError> {
+        let _ = check_range(self, offset, bytes.len())?;
+        let _ = write_at(self.device(), &mut Block::new(), offset as u64, bytes)?;
+        Ok(())
+    }
+}
+
+#[inline]
+fn check_range<B: BlockDevice, C: CacheProvider>(dev: &Storage<B, C>, offset: u32, n: usize) -> DevResult<()> {
+    match (offset as usize).checked_add(n) {
+        Some(v) if v <= ReadStorage::capacity(dev) => Ok(()),
+        _ => Err(DeviceError::InvalidIndex),
+    }
+}
+fn read_at<B: BlockDevice>(dev: &mut B, t: &mut Block, pos: u64, b: &mut [u8]) -> DevResult<usize> {
+    let e = dev.blocks()? as u64 * Block::SIZE as u64;
+    let (mut i, mut p) = (pos, 0usize);
+    while p < b.len() && i < e {
+        let o = (i % Block::SIZE as u64) as usize;
+        let n = (Block::SIZE - o).min(b.len() - p);
+        let _ = dev.read_single(t, (i / Block::SIZE as u64) as u32)?;
+        unsafe { copy_nonoverlapping(t.as_ptr().add(o), b.as_mut_ptr().add(p), n) };
+        (i, p) = (i + n as u64, p + n);
+    }
+    Ok(p)
+}
+fn write_at<B: BlockDevice>(dev: &mut B, t: &mut Block, pos: u64, b: &[u8]) -> DevResult<usize> {
+    if dev.is_read_only() {
+        return Err(DeviceError::NotWritable);
+    }
+    let e = dev.blocks()? as u64 * Block::SIZE as u64;
+    let (mut i, mut p) = (pos, 0usize);
+    while p < b.len() && i < e {
+        let (k, o) = ((i / Block::SIZE as u64) as u32, (i % Block::SIZE as u64) as usize);
+        let n = (Block::SIZE - o).min(b.len() - p);
+        if n < Block::SIZE {
+            // Keep the rest of the Block intact.
+            let _ = dev.read_single(t, k)?;
+        }
+        unsafe { copy_nonoverlapping(b.as_ptr().add(p), t.as_mut_ptr().add(o), n) };
+        let _ = dev.write_single(t, k)?;
+        (i, p) = (i + n as u64, p + n);
+    }
+    Ok(p)
+}
@@ -0,0 +1,295 @@
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+
+#![no_implicit_prelude]
+
+extern crate core;
+
+use core::convert::AsRef;
+use core::result::Result::{Err, Ok};
+
+use crate::fs::{Block, BlockDevice, CacheProvider, DevResult, DeviceError, Storage, VolumeName};
+use crate::SliceMut;
+
+const FAT16_MIN: u32 = 0x20D0u32; // Smallest size that results in a valid FAT16 cluster count.
+const FAT16_MAX: u32 = 0x400000u32; // 2GB with 32k Clusters.
+const FAT32_MIN: u32 = 0x10428u32; // Smallest size that results in a valid FAT32 cluster count.
+
+pub enum FatType {
+    Auto,
+    Fat16,
+    Fat32,
+}
+
+pub struct Format {
+    kind:   FatType,
+    label:  VolumeName,
+    serial: u32,
+}
+
+struct Layout {
+    fat:      u32,
+    root:     u32,
+    start:    u32,
+    blocks:   u32,
+    reserved: u32,
+    clusters: u32,
+    size:     u8,
+    fat32:    bool,
+}
+
+impl Format {
+    #[inline]
+    pub const fn new() -> Format {
+        Format {
+            kind:   FatType::Auto,
+            label:  VolumeName::NO_NAME,
+            serial: 0u32,
+        }
+    }
+
+    #[inline]
+    pub fn kind(mut self, v: FatType) -> Format {
+        self.kind = v;
+        self
+    }
+    #[inline]
+    pub fn serial(mut self, v: u32) -> Format {
+        self.serial = v;
+        self
+    }
+    #[inline]
+    pub fn label(mut self, v: impl AsRef<str>) -> Format {
+        self.label = VolumeName::from_str(v);
+        self
+    }
+
+    /// Format the whole device with a single partition containing a FAT16
+    /// or FAT32 Volume.
+    ///
+    /// This will destroy ANY existing data on the device.
+    pub fn format<B: BlockDevice, C: CacheProvider>(&self, dev: &Storage<B, C>) -> DevResult<()> {
+        let n = dev.device().blocks()?;
+        let l = Layout::new(&self.kind, n)?;
+        let mut b = Block::new();
+        // Write the MBR partition table.
+        b.clear();
+        l.write_mbr(&mut b);
+        let _ = dev.write_single(&b, 0)?;
+        b.clear();
+        // Clear the reserved area and the FATs, the Boot sector will be written
+        // last so that a failed format will not mount.
+        for i in l.start..l.start + l.reserved + (l.fat * 2) + l.root {
+            let _ = dev.write_single(&b, i)?;
+        }
+        if l.fat32 {
+            // Zero the root Directory cluster.
+            let i = l.start + l.reserved + (l.fat * 2);
+            for x in i..i + l.size as u32 {
+                let _ = dev.write_single(&b, x)?;
+            }
+        }
+        // Write the FAT headers for both FATs.
+        for i in 0..2 {
+            l.write_fat(&mut b);
+            let _ = dev.write_single(&b, l.start + l.reserved + (i * l.fat))?;
+        }
+        // Write the Volume label entry into the root Directory, only if one
+        // was set as the BPB already says "NO NAME".
+        b.clear();
+        if !self.label.is_empty() && self.label.as_raw() != VolumeName::NO_NAME.as_raw() {
+            b.write_from(0, self.label.as_raw());
+            b.write_u8(11, 0x8);
+            let _ = dev.write_single(&b, l.start + l.reserved + (l.fat * 2))?;
+        }
+        if l.fat32 {
+            // 0x1 - FSInfo Sector
+            // 0x7 - Backup FSInfo Sector
+            l.write_info(&mut b);
+            let _ = dev.write_single(&b, l.start + 1)?;
+            let _ = dev.write_single(&b, l.start + 7)?;
+            // 0x6 - Backup Boot Sector
+            l.write_boot(&mut b, self);
+            let _ = dev.write_single(&b, l.start + 6)?;
+        }
+        l.write_boot(&mut b, self);
+        dev.write_single(&b, l.start)
+    }
+}
+impl Layout {
+    fn new(kind: &FatType, n: u32) -> DevResult<Layout> {
+        // NOTE(sf): Align partitions to 4MB on larger cards, as that's what
+        //           the SD spec recommends for erase blocks. Smaller ones get
+        //           the standard 1MB alignment.
+        let s = if n > 0x400000 { 0x2000 } else { 0x800 };
+        if n <= s {
+            return Err(DeviceError::NoSpace);
+        }
+        let t = n - s;
+        let f = match kind {
+            FatType::Fat16 if t < FAT16_MIN || t > FAT16_MAX => return Err(DeviceError::InvalidOptions),
+            FatType::Fat32 if t < FAT32_MIN => return Err(DeviceError::InvalidOptions),
+            FatType::Fat16 => false,
+            FatType::Fat32 => true,
+            FatType::Auto if t < FAT16_MIN => return Err(DeviceError::NoSpace),
+            // NOTE(sf): Use FAT16 up to 512MB, as FAT32 wastes a lot of space
+            //           on small Volumes for the reserved sectors.
+            FatType::Auto => t > 0x100000,
+        };
+        let (k, r, e) = if f { (cluster_size_32(t), 0x20, 0) } else { (cluster_size_16(t), 0x1, 0x20) };
+        // From the Microsoft FAT specification, this overestimates the FAT
+        // size a little bit, which is fine.
+        let v = if f { ((0x100 * k as u32) + 2) / 2 } else { (0x100 * k as u32) + 2 };
+        let z = (t - (r + e) + (v - 1)) / v;
+        let c = (t - (r + e + (z * 2))) / k as u32;
+        match c {
+            0..0xFF5 => return Err(DeviceError::NoSpace),
+            0xFF5..0xFFF5 if f => return Err(DeviceError::InvalidOptions),
+            0xFFF5.. if !f => return Err(DeviceError::InvalidOptions),
+            _ => (),
+        }
+        Ok(Layout {
+            fat:      z,
+            root:     e,
+            start:    s,
+            blocks:   t,
+            reserved: r,
+            clusters: c,
+            size:     k,
+            fat32:    f,
+        })
+    }
+
+    fn write_fat(&self, b: &mut Block) {
+        b.clear();
+        if self.fat32 {
+            b.write_u32(0, 0xFFFFFF8);
+            b.write_u32(4, 0xFFFFFFF);
+            // Root Directory Cluster EOF.
+            b.write_u32(8, 0xFFFFFFF);
+        } else {
+            b.write_u16(0, 0xFFF8);
+            b.write_u16(2, 0xFFFF);
+        }
+    }
+    fn write_mbr(&self, b: &mut Block) {
+        // 0x1BE - First Partition Entry
+        b.write_u8(0x1BE, 0);
+        // Using LBA only, so the CHS values are set to the max.
+        b.write_u8(0x1BF, 0xFE);
+        b.write_u16(0x1C0, 0xFFFF);
+        // 0x0C - FAT32 (LBA)
+        // 0x0E - FAT16 (LBA)
+        b.write_u8(0x1C2, if self.fat32 { 0xC } else { 0xE });
+        b.write_u8(0x1C3, 0xFE);
+        b.write_u16(0x1C4, 0xFFFF);
+        b.write_u32(0x1C6, self.start);
+        b.write_u32(0x1CA, self.blocks);
+        b.write_u16(510, 0xAA55);
+    }
+    fn write_info(&self, b: &mut Block) {
+        b.clear();
+        b.write_u32(0, 0x41615252);
+        b.write_u32(484, 0x61417272);
+        // 0x1E8 - Last Known Number of Free Data Clusters
+        // 0x1EC - Most Recent Allocated Cluster
+        b.write_u32(488, self.clusters - 1);
+        b.write_u32(492, 0x2);
+        b.write_u32(508, 0xAA550000);
+    }
+    fn write_boot(&self, b: &mut Block, f: &Format) {
+        b.clear();
+        // 0x0 - Jump Instruction
+        b.write_u8(0, 0xEB);
+        b.write_u8(1, if self.fat32 { 0x58 } else { 0x3C });
+        b.write_u8(2, 0x90);
+        // 0x3 - OEM Name
+        b.write_from(3, b"MSWIN4.1");
+        // 0xB - Bytes per Logical Sector
+        b.write_u16(11, Block::SIZE as u16);
+        // 0xD - Logical Sectors per Cluster
+        b.write_u8(13, self.size);
+        // 0xE - Count of Reserved Logical Sectors
+        b.write_u16(14, self.reserved as u16);
+        // 0x10 - Number of FAT
+        b.write_u8(16, 2);
+        // 0x11 - Max Root Directory Entries
+        b.write_u16(17, if self.fat32 { 0 } else { (self.root * 0x10) as u16 });
+        // 0x13 - Total Logical Sectors
+        b.write_u16(19, if self.fat32 || self.blocks > 0xFFFF { 0 } else { self.blocks as u16 });
+        // 0x15 - Media Descriptor
+        b.write_u8(21, 0xF8);
+        // 0x16 - Logical Sectors per FAT
+        b.write_u16(22, if self.fat32 { 0 } else { self.fat as u16 });
+        // 0x18 - Sectors per Track
+        // 0x1A - Number of Heads
+        b.write_u16(24, 0x3F);
+        b.write_u16(26, 0xFF);
+        // 0x1C - Hidden Sectors
+        b.write_u32(28, self.start);
+        // 0x20 - Total Logical Sectors (FAT32)
+        b.write_u32(32, if self.fat32 || self.blocks > 0xFFFF { self.blocks } else { 0 });
+        let i = if self.fat32 {
+            // 0x24 - Logical Sectors per FAT (FAT32)
+            b.write_u32(36, self.fat);
+            // 0x28 - Mirroring Flags
+            // 0x2A - Version
+            // 0x2C - Cluster Root Start
+            b.write_u32(44, 0x2);
+            // 0x30 - FS Information Sector
+            b.write_u16(48, 0x1);
+            // 0x32 - Backup Boot Sector
+            b.write_u16(50, 0x6);
+            0x40
+        } else {
+            0x24
+        };
+        // Drive Number, Extended Boot Signature, Serial, Label and FS Type
+        b.write_u8(i, 0x80);
+        b.write_u8(i + 2, 0x29);
+        b.write_u32(i + 3, f.serial);
+        b.write_from(i + 7, f.label.as_raw());
+        b.write_from(i + 18, if self.fat32 { b"FAT32   " } else { b"FAT16   " });
+        b.write_u16(510, 0xAA55);
+    }
+}
+
+#[inline]
+fn cluster_size_16(n: u32) -> u8 {
+    // Microsoft recommended Cluster sizes for FAT16.
+    match n {
+        0..=0x7FA8 => 0x2,
+        0x7FA9..=0x40000 => 0x4,
+        0x40001..=0x80000 => 0x8,
+        0x80001..=0x100000 => 0x10,
+        0x100001..=0x200000 => 0x20,
+        _ => 0x40,
+    }
+}
+#[inline]
+fn cluster_size_32(n: u32) -> u8 {
+    // Microsoft recommended Cluster sizes for FAT32.
+    match n {
+        0..=0x82000 => 0x1,
+        0x82001..=0x1000000 => 0x8,
+        0x1000001..=0x2000000 => 0x10,
+        0x2000001..=0x4000000 => 0x20,
+        _ => 0x40,
+    }
+}
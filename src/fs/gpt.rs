@@ -0,0 +1,128 @@
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+
+#![no_implicit_prelude]
+
+extern crate core;
+
+use core::cmp::Ord;
+use core::iter::Iterator;
+use core::option::Option::{None, Some};
+use core::result::Result::{Err, Ok};
+
+use crate::fs::{Block, BlockDevice, CacheProvider, DevResult, DeviceError, Storage};
+use crate::{Slice, SliceMut};
+
+// EBD0A0A2-B9E5-4433-87C0-68B6B72699C7
+const GUID_BASIC: [u8; 16] = [0xA2, 0xA0, 0xD0, 0xEB, 0xE5, 0xB9, 0x33, 0x44, 0x87, 0xC0, 0x68, 0xB6, 0xB7, 0x26, 0x99, 0xC7];
+// C12A7328-F81F-11D2-BA4B-00A0C93EC93B
+const GUID_EFI: [u8; 16] = [0x28, 0x73, 0x2A, 0xC1, 0x1F, 0xF8, 0xD2, 0x11, 0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9, 0x3B];
+
+// NOTE(sf): The spec has no upper bound on the entry array, but anything
+//           over 1MB isn't a real table and would take forever to CRC.
+const MAX_ENTRIES: usize = 0x100000usize;
+
+/// Read the GPT header and partition entry array and return the start LBA and
+/// size of the partition at the supplied index.
+///
+/// Both the header and partition entry array CRC32s are validated.
+pub(super) fn partition<B: BlockDevice, C: CacheProvider>(dev: &Storage<B, C>, b: &mut Block, index: usize) -> DevResult<(u32, u32)> {
+    let _ = dev.read_single(b, 1)?;
+    // 0x0 - Signature "EFI PART"
+    if b.read_slice(0, 8) != b"EFI PART" {
+        return Err(DeviceError::InvalidPartition);
+    }
+    // 0xC - Header Size
+    // 0x10 - Header CRC32
+    let (h, c) = (b.read_u32(12) as usize, b.read_u32(16));
+    if h < 0x5C || h > Block::SIZE {
+        return Err(DeviceError::InvalidPartition);
+    }
+    // The CRC is calculated with the CRC field zeroed out.
+    b.write_u32(16, 0);
+    if crc32(0xFFFFFFFF, b.read_slice(0, h)) ^ 0xFFFFFFFF != c {
+        return Err(DeviceError::InvalidChecksum);
+    }
+    // 0x48 - Partition Entry Array LBA
+    // 0x50 - Number of Partition Entries
+    // 0x54 - Size of a Partition Entry
+    // 0x58 - Partition Entry Array CRC32
+    if b.read_u32(76) != 0 {
+        return Err(DeviceError::Overflow);
+    }
+    let (i, n, s, k) = (b.read_u32(72), b.read_u32(80), b.read_u32(84), b.read_u32(88));
+    // NOTE(sf): The entry size must be a power of two and at least 128, we
+    //           also limit it to a Block so an entry never spans Blocks.
+    if !matches_size(s) {
+        return Err(DeviceError::InvalidPartition);
+    }
+    if index >= n as usize {
+        return Err(DeviceError::NotFound);
+    }
+    let t = match (n as usize).checked_mul(s as usize) {
+        Some(v) if v <= MAX_ENTRIES => v,
+        _ => return Err(DeviceError::InvalidPartition),
+    };
+    let (e, mut r, mut v) = (index * s as usize, 0xFFFFFFFFu32, None);
+    for x in 0..t.div_ceil(Block::SIZE) {
+        let _ = dev.read_single(b, i + x as u32)?;
+        r = crc32(r, b.read_slice(0, (t - (x * Block::SIZE)).min(Block::SIZE)));
+        if e / Block::SIZE != x {
+            continue;
+        }
+        let o = e % Block::SIZE;
+        // 0x0 - Partition Type GUID
+        // 0x20 - First LBA
+        // 0x28 - Last LBA
+        let g = b.read_slice(o, 16);
+        v = Some(if g.iter().all(|v| *v == 0) {
+            Err(DeviceError::NotFound)
+        } else if g != GUID_BASIC && g != GUID_EFI {
+            Err(DeviceError::UnsupportedVolume(0xEE))
+        } else if b.read_u32(o + 36) != 0 || b.read_u32(o + 44) != 0 {
+            Err(DeviceError::Overflow)
+        } else {
+            Ok((b.read_u32(o + 32), b.read_u32(o + 40)))
+        });
+    }
+    if r ^ 0xFFFFFFFF != k {
+        return Err(DeviceError::InvalidChecksum);
+    }
+    match v {
+        Some(Ok((s, e))) if e >= s => Ok((s, e - s + 1)),
+        Some(Ok(_)) => Err(DeviceError::InvalidPartition),
+        Some(Err(e)) => Err(e),
+        None => Err(DeviceError::NotFound),
+    }
+}
+
+#[inline]
+fn matches_size(v: u32) -> bool {
+    v >= 0x80 && v <= Block::SIZE as u32 && v.is_power_of_two()
+}
+
+pub(super) fn crc32(mut r: u32, b: &[u8]) -> u32 {
+    for i in b.iter() {
+        r ^= *i as u32;
+        for _ in 0..8 {
+            r = if r & 1 != 0 { unsafe { r.unchecked_shr(1) ^ 0xEDB88320 } } else { unsafe { r.unchecked_shr(1) } };
+        }
+    }
+    r
+}
@@ -0,0 +1,102 @@
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+
+#![no_implicit_prelude]
+
+extern crate core;
+extern crate std;
+
+use core::convert::{AsRef, From};
+use core::iter::IntoIterator;
+use core::option::Option::Some;
+use core::result::Result::{Err, Ok};
+use std::fs::{File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::fs::{Block, BlockDevice, DevResult, DeviceError};
+
+/// BlockDevice backed by a disk image File on the host, such as one created
+/// with 'mkfs.fat'.
+///
+/// Any trailing bytes that don't fill a full Block are ignored.
+pub struct FileDevice {
+    file:   File,
+    blocks: u32,
+}
+
+impl FileDevice {
+    #[inline]
+    pub fn open(path: impl AsRef<Path>) -> io::Result<FileDevice> {
+        FileDevice::new(OpenOptions::new().read(true).write(true).open(path)?)
+    }
+    pub fn new(file: File) -> io::Result<FileDevice> {
+        let n = file.metadata()?.len() / Block::SIZE as u64;
+        if n > 0xFFFFFFFF {
+            return Err(io::Error::from(ErrorKind::FileTooLarge));
+        }
+        Ok(FileDevice { file, blocks: n as u32 })
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> File {
+        self.file
+    }
+
+    #[inline]
+    fn seek(&mut self, start: u32, n: usize) -> DevResult<()> {
+        match (start as usize).checked_add(n) {
+            Some(v) if v <= self.blocks as usize => (),
+            _ => return Err(DeviceError::InvalidIndex),
+        }
+        match self.file.seek(SeekFrom::Start(start as u64 * Block::SIZE as u64)) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(DeviceError::Hardware(0)),
+        }
+    }
+}
+
+impl BlockDevice for FileDevice {
+    #[inline]
+    fn blocks(&mut self) -> DevResult<u32> {
+        Ok(self.blocks)
+    }
+    fn write(&mut self, b: &[Block], start: u32) -> DevResult<()> {
+        let _ = self.seek(start, b.len())?;
+        for v in b.into_iter() {
+            let _ = self.file.write_all(v).map_err(|_| DeviceError::Write)?;
+        }
+        Ok(())
+    }
+    fn read(&mut self, b: &mut [Block], start: u32) -> DevResult<()> {
+        let _ = self.seek(start, b.len())?;
+        for v in b.iter_mut() {
+            let _ = self.file.read_exact(v).map_err(map_read)?;
+        }
+        Ok(())
+    }
+}
+
+#[inline]
+fn map_read(e: io::Error) -> DeviceError {
+    match e.kind() {
+        ErrorKind::UnexpectedEof => DeviceError::UnexpectedEoF,
+        _ => DeviceError::Read,
+    }
+}
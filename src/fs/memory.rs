@@ -0,0 +1,87 @@
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+
+#![no_implicit_prelude]
+
+extern crate core;
+
+use core::convert::AsMut;
+use core::iter::IntoIterator;
+use core::option::Option::Some;
+use core::result::Result::{Err, Ok};
+
+use crate::fs::{Block, BlockDevice, DevResult, DeviceError};
+
+/// BlockDevice backed by memory, such as a byte array or a mutable slice.
+///
+/// Any trailing bytes that don't fill a full Block are ignored.
+pub struct MemoryDevice<T: AsMut<[u8]>>(T);
+
+impl<T: AsMut<[u8]>> MemoryDevice<T> {
+    #[inline]
+    pub const fn new(v: T) -> MemoryDevice<T> {
+        MemoryDevice(v)
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+    #[inline]
+    pub fn as_slice(&mut self) -> &mut [u8] {
+        self.0.as_mut()
+    }
+
+    #[inline]
+    fn pos(&mut self, start: u32, n: usize) -> DevResult<usize> {
+        // NOTE(sf): usize is 32bit on the Pico, so a large LBA can wrap around
+        //           to a low Block if this isn't checked.
+        let i = (start as usize).checked_mul(Block::SIZE).ok_or(DeviceError::InvalidIndex)?;
+        match n.checked_mul(Block::SIZE).and_then(|v| v.checked_add(i)) {
+            Some(v) if v <= self.0.as_mut().len() => Ok(i),
+            _ => Err(DeviceError::InvalidIndex),
+        }
+    }
+}
+
+impl<T: AsMut<[u8]>> BlockDevice for MemoryDevice<T> {
+    #[inline]
+    fn blocks(&mut self) -> DevResult<u32> {
+        let n = self.0.as_mut().len() / Block::SIZE;
+        if n > 0xFFFFFFFF { Err(DeviceError::Overflow) } else { Ok(n as u32) }
+    }
+    fn write(&mut self, b: &[Block], start: u32) -> DevResult<()> {
+        let mut i = self.pos(start, b.len())?;
+        let d = self.0.as_mut();
+        for v in b.into_iter() {
+            unsafe { d.get_unchecked_mut(i..i + Block::SIZE) }.copy_from_slice(v);
+            i += Block::SIZE;
+        }
+        Ok(())
+    }
+    fn read(&mut self, b: &mut [Block], start: u32) -> DevResult<()> {
+        let mut i = self.pos(start, b.len())?;
+        let d = self.0.as_mut();
+        for v in b.iter_mut() {
+            v.copy_from_slice(unsafe { d.get_unchecked(i..i + Block::SIZE) });
+            i += Block::SIZE;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,169 @@
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+
+#![no_implicit_prelude]
+
+extern crate core;
+extern crate embedded_sdmmc;
+
+use core::convert::From;
+use core::iter::Iterator;
+use core::result::Result::{self, Err, Ok};
+
+use embedded_sdmmc::{Block as SdBlock, BlockCount, BlockDevice as SdBlockDevice, BlockIdx};
+
+use crate::fs::{Block, BlockDevice, CacheProvider, DevResult, DeviceError, Storage};
+
+/// BlockDevice backed by an 'embedded-sdmmc' BlockDevice, such as their
+/// 'SdCard', so it can be used by a Storage.
+///
+/// The errors of the inner device can't be kept, so they are returned as
+/// 'Read' or 'Write' errors. As the Block types differ, transfers are done
+/// one Block at a time.
+pub struct SdmmcDevice<D: SdBlockDevice>(D);
+
+impl<D: SdBlockDevice> SdmmcDevice<D> {
+    #[inline]
+    pub const fn new(dev: D) -> SdmmcDevice<D> {
+        SdmmcDevice(dev)
+    }
+
+    #[inline]
+    pub fn device(&self) -> &D {
+        &self.0
+    }
+    #[inline]
+    pub fn into_inner(self) -> D {
+        self.0
+    }
+}
+
+impl Block {
+    /// Returns the 'contents' of the SdBlock as a Block.
+    #[inline]
+    pub(crate) fn from_sd(v: &SdBlock) -> &Block {
+        // Safe as a Block is a transparent 512 byte array with an alignment of
+        // 1, the same as the 'contents'.
+        unsafe { &*(v.contents.as_ptr() as *const Block) }
+    }
+    #[inline]
+    pub(crate) fn from_sd_mut(v: &mut SdBlock) -> &mut Block {
+        unsafe { &mut *(v.contents.as_mut_ptr() as *mut Block) }
+    }
+}
+
+impl From<&Block> for SdBlock {
+    #[inline]
+    fn from(v: &Block) -> SdBlock {
+        let mut b = SdBlock::new();
+        b.contents.copy_from_slice(v);
+        b
+    }
+}
+impl From<&SdBlock> for Block {
+    #[inline]
+    fn from(v: &SdBlock) -> Block {
+        let mut b = Block::new();
+        b.copy_from_slice(&v.contents);
+        b
+    }
+}
+
+impl<D: SdBlockDevice> BlockDevice for SdmmcDevice<D> {
+    #[inline]
+    fn blocks(&mut self) -> DevResult<u32> {
+        match self.0.num_blocks() {
+            Ok(v) => Ok(v.0),
+            Err(_) => Err(DeviceError::Read),
+        }
+    }
+    fn write(&mut self, b: &[Block], start: u32) -> DevResult<()> {
+        let mut t = [SdBlock::new()];
+        for (i, v) in b.iter().enumerate() {
+            unsafe { t.get_unchecked_mut(0) }.contents.copy_from_slice(v);
+            if self.0.write(&t, BlockIdx(start + i as u32)).is_err() {
+                return Err(DeviceError::Write);
+            }
+        }
+        Ok(())
+    }
+    fn read(&mut self, b: &mut [Block], start: u32) -> DevResult<()> {
+        let mut t = [SdBlock::new()];
+        for (i, v) in b.iter_mut().enumerate() {
+            if self.0.read(&mut t, BlockIdx(start + i as u32)).is_err() {
+                return Err(DeviceError::Read);
+            }
+            v.copy_from_slice(&unsafe { t.get_unchecked(0) }.contents);
+        }
+        Ok(())
+    }
+}
+
+/// Storage as an 'embedded-sdmmc' BlockDevice, so an 'embedded-sdmmc'
+/// VolumeManager can use the same device, like a 'sd::Card'.
+impl<B: BlockDevice, C: CacheProvider> SdBlockDevice for Storage<B, C> {
+    type Error = DeviceError;
+
+    #[inline]
+    fn num_blocks(&self) -> Result<BlockCount, DeviceError> {
+        Ok(BlockCount(self.device().blocks()?))
+    }
+    #[inline]
+    fn write(&self, blocks: &[SdBlock], start: BlockIdx) -> Result<(), DeviceError> {
+        write_blocks(self, blocks, start.0)
+    }
+    #[inline]
+    fn read(&self, blocks: &mut [SdBlock], start: BlockIdx) -> Result<(), DeviceError> {
+        read_blocks(self, blocks, start.0)
+    }
+}
+/// Shared Storage as an 'embedded-sdmmc' BlockDevice, so it can be used by a
+/// VolumeManager while it's also used to mount Volumes.
+impl<B: BlockDevice, C: CacheProvider> SdBlockDevice for &Storage<B, C> {
+    type Error = DeviceError;
+
+    #[inline]
+    fn num_blocks(&self) -> Result<BlockCount, DeviceError> {
+        Ok(BlockCount(self.device().blocks()?))
+    }
+    #[inline]
+    fn write(&self, blocks: &[SdBlock], start: BlockIdx) -> Result<(), DeviceError> {
+        write_blocks(self, blocks, start.0)
+    }
+    #[inline]
+    fn read(&self, blocks: &mut [SdBlock], start: BlockIdx) -> Result<(), DeviceError> {
+        read_blocks(self, blocks, start.0)
+    }
+}
+
+// NOTE(sf): These read and write into the 'contents' of each SdBlock directly
+//           through the device, so no cache Block is claimed and they can be
+//           used while a Reader, index or SharedCache slot is held.
+fn read_blocks<B: BlockDevice, C: CacheProvider>(dev: &Storage<B, C>, b: &mut [SdBlock], start: u32) -> DevResult<()> {
+    for (i, v) in b.iter_mut().enumerate() {
+        let _ = dev.device().read_single(Block::from_sd_mut(v), start + i as u32)?;
+    }
+    Ok(())
+}
+fn write_blocks<B: BlockDevice, C: CacheProvider>(dev: &Storage<B, C>, b: &[SdBlock], start: u32) -> DevResult<()> {
+    for (i, v) in b.iter().enumerate() {
+        let _ = dev.device().write_single(Block::from_sd(v), start + i as u32)?;
+    }
+    Ok(())
+}
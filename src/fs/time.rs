@@ -0,0 +1,292 @@
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+
+#![no_implicit_prelude]
+
+extern crate core;
+
+use core::clone::Clone;
+use core::cmp::{Eq, Ord, PartialEq};
+use core::convert::{From, Into};
+use core::default::Default;
+use core::marker::Copy;
+use core::matches;
+use core::result::Result;
+
+const DAYS_IN_YEAR: [u16; 13] = [
+    0, 0x1F, 0x3B, 0x5A, 0x78, 0x97, 0xB5, 0xD4, 0xF3, 0x111, 0x130, 0x14E, 0x16D,
+];
+
+// NOTE(sf): These mirror the 'rpsp' time types that the 'fs' module uses, so
+//           it can be built on the host without 'rpsp', which only builds
+//           for the Pico.
+
+pub enum RtcError {
+    NotRunning,
+    InvalidTime,
+    ValueTooLarge,
+}
+#[repr(u8)]
+pub enum Month {
+    None      = 0,
+    January   = 1,
+    February  = 2,
+    March     = 3,
+    April     = 4,
+    May       = 5,
+    June      = 6,
+    July      = 7,
+    August    = 8,
+    September = 9,
+    October   = 10,
+    November  = 11,
+    December  = 12,
+}
+#[repr(u8)]
+pub enum Weekday {
+    Sunday    = 0,
+    Monday    = 1,
+    Tuesday   = 2,
+    Wednesday = 3,
+    Thursday  = 4,
+    Friday    = 5,
+    Saturday  = 6,
+    None      = 7,
+}
+
+pub struct Time {
+    pub day:     u8,
+    pub year:    u16,
+    pub mins:    u8,
+    pub secs:    u8,
+    pub hours:   u8,
+    pub month:   Month,
+    pub weekday: Weekday,
+}
+
+pub trait TimeSource {
+    type Error: Into<RtcError>;
+
+    fn now(&mut self) -> Result<Time, Self::Error>;
+}
+
+impl Time {
+    #[inline]
+    pub const fn zero() -> Time {
+        Time {
+            day:     0u8,
+            year:    0u16,
+            mins:    0u8,
+            secs:    0u8,
+            hours:   0u8,
+            month:   Month::None,
+            weekday: Weekday::None,
+        }
+    }
+    #[inline]
+    pub const fn empty() -> Time {
+        Time {
+            day:     1u8,
+            year:    0u16,
+            mins:    0u8,
+            secs:    0u8,
+            hours:   0u8,
+            month:   Month::January,
+            weekday: Weekday::None,
+        }
+    }
+    #[inline]
+    pub const fn new(year: u16, month: Month, day: u8, hours: u8, mins: u8, secs: u8, weekday: Weekday) -> Time {
+        Time {
+            day,
+            mins,
+            secs,
+            year,
+            hours,
+            month,
+            weekday,
+        }
+    }
+
+    #[inline]
+    pub fn is_valid(&self) -> bool {
+        self.day >= 1 && self.day <= 31 && self.hours <= 23 && self.mins <= 59 && self.secs <= 59 && !self.month.is_none()
+    }
+    pub fn into_seconds(&self) -> i64 {
+        let (y, v) = norm(self.year as i32, self.month as i32 - 1, 0xC);
+        let (s, _) = norm(self.secs as i32, 0, 0x3B9ACA00);
+        let (m, s) = norm(self.mins as i32, s, 0x3C);
+        let (h, m) = norm(self.hours as i32, m, 0x3C);
+        let (d, h) = norm(self.day as i32, h, 0x18);
+        let r = v as usize + 1;
+        let mut e = since_epoch(y) + unsafe { *DAYS_IN_YEAR.get_unchecked((r - 1).min(12)) as i64 };
+        if y % 4 == 0 && (y % 100 != 0 || y % 400 == 0) && r >= 3 {
+            e += 1;
+        }
+        ((e + d as i64 - 1) * 0x15180) + (h * 0xE10 + m * 0x3C + s) as i64
+    }
+}
+impl Month {
+    #[inline]
+    pub fn is_none(&self) -> bool {
+        matches!(self, Month::None)
+    }
+}
+impl Weekday {
+    #[inline]
+    pub fn is_none(&self) -> bool {
+        matches!(self, Weekday::None)
+    }
+}
+
+impl Eq for Time {}
+impl Copy for Time {}
+impl Clone for Time {
+    #[inline]
+    fn clone(&self) -> Time {
+        *self
+    }
+}
+impl Default for Time {
+    #[inline]
+    fn default() -> Time {
+        Time::empty()
+    }
+}
+impl PartialEq for Time {
+    #[inline]
+    fn eq(&self, other: &Time) -> bool {
+        self.day == other.day
+            && self.year == other.year
+            && self.mins == other.mins
+            && self.secs == other.secs
+            && self.hours == other.hours
+            && self.month == other.month
+    }
+}
+
+impl Eq for Month {}
+impl Copy for Month {}
+impl Clone for Month {
+    #[inline]
+    fn clone(&self) -> Month {
+        *self
+    }
+}
+impl From<u8> for Month {
+    #[inline]
+    fn from(v: u8) -> Month {
+        match v {
+            1 => Month::January,
+            2 => Month::February,
+            3 => Month::March,
+            4 => Month::April,
+            5 => Month::May,
+            6 => Month::June,
+            7 => Month::July,
+            8 => Month::August,
+            9 => Month::September,
+            10 => Month::October,
+            11 => Month::November,
+            12 => Month::December,
+            _ => Month::None,
+        }
+    }
+}
+impl PartialEq for Month {
+    #[inline]
+    fn eq(&self, other: &Month) -> bool {
+        *self as u8 == *other as u8
+    }
+}
+
+impl Eq for Weekday {}
+impl Copy for Weekday {}
+impl Clone for Weekday {
+    #[inline]
+    fn clone(&self) -> Weekday {
+        *self
+    }
+}
+impl PartialEq for Weekday {
+    #[inline]
+    fn eq(&self, other: &Weekday) -> bool {
+        *self as u8 == *other as u8
+    }
+}
+
+fn since_epoch(year: i32) -> i64 {
+    let mut y = year as i64 + 0x440D116EBF;
+    let mut d = 0x23AB1 * (y / 0x190);
+    y -= 0x190 * (y / 0x190);
+    d += 0x8EAC * (y / 0x64);
+    y -= 0x64 * (y / 0x64);
+    d += 0x5B5 * (y / 0x4);
+    y -= 0x4 * (y / 0x4);
+    d + (0x16D * y)
+}
+fn norm(hi: i32, low: i32, base: i32) -> (i32, i32) {
+    let (mut x, mut y) = (hi, low);
+    if y < 0 {
+        let n = (-y - 1) / base + 1;
+        x -= n;
+        y += n * base;
+    }
+    if y >= base {
+        let n = y / base;
+        x += n;
+        y -= n * base;
+    }
+    (x, y)
+}
+
+#[cfg(feature = "debug")]
+mod display {
+    extern crate core;
+
+    use core::fmt::{Debug, Formatter, Result};
+
+    use crate::fs::{Month, Time, Weekday};
+
+    impl Debug for Time {
+        #[inline]
+        fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+            f.debug_struct("Time")
+                .field("year", &self.year)
+                .field("month", &self.month)
+                .field("day", &self.day)
+                .field("hours", &self.hours)
+                .field("mins", &self.mins)
+                .field("secs", &self.secs)
+                .finish()
+        }
+    }
+    impl Debug for Month {
+        #[inline]
+        fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+            Debug::fmt(&(*self as u8), f)
+        }
+    }
+    impl Debug for Weekday {
+        #[inline]
+        fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+            Debug::fmt(&(*self as u8), f)
+        }
+    }
+}
@@ -0,0 +1,278 @@
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+
+#![no_implicit_prelude]
+
+extern crate core;
+
+use core::cmp::Ord;
+use core::iter::Iterator;
+use core::option::Option::{self, None, Some};
+
+use crate::fs::{Block, BlockDevice, DevResult};
+
+const RECENT: usize = 0x20usize;
+
+/// Counters of the transfers done by a TracingDevice.
+pub struct IoStats {
+    reads:   u32,
+    writes:  u32,
+    repeats: u32,
+    blocks:  (u32, u32),
+}
+/// A single read or write done by a TracingDevice.
+pub struct IoOp {
+    lba:   u32,
+    count: u32,
+    write: bool,
+}
+/// BlockDevice wrapper that counts the reads and writes done on the inner
+/// device, to profile how much a Volume or File path touches the device.
+///
+/// When 'N' is more than zero, the last 'N' operations are also kept and can
+/// be listed with 'ops' or printed with the 'debug' feature.
+///
+/// Reads of any of the last 32 different Blocks read are counted as repeats,
+/// as those are the reads a bigger cache would have saved.
+pub struct TracingDevice<B: BlockDevice, const N: usize = 0> {
+    dev:    B,
+    ops:    [IoOp; N],
+    pos:    usize,
+    stats:  IoStats,
+    recent: [u32; RECENT],
+    next:   usize,
+}
+pub struct IoOpIter<'a, const N: usize> {
+    ops:  &'a [IoOp; N],
+    pos:  usize,
+    left: usize,
+}
+
+impl IoOp {
+    const EMPTY: IoOp = IoOp { lba: 0u32, count: 0u32, write: false };
+
+    #[inline]
+    pub fn lba(&self) -> u32 {
+        self.lba
+    }
+    #[inline]
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+    #[inline]
+    pub fn is_write(&self) -> bool {
+        self.write
+    }
+}
+impl IoStats {
+    #[inline]
+    const fn new() -> IoStats {
+        IoStats {
+            reads:   0u32,
+            writes:  0u32,
+            repeats: 0u32,
+            blocks:  (0u32, 0u32),
+        }
+    }
+
+    /// Count of read calls made to the device.
+    #[inline]
+    pub fn reads(&self) -> u32 {
+        self.reads
+    }
+    /// Count of write calls made to the device.
+    #[inline]
+    pub fn writes(&self) -> u32 {
+        self.writes
+    }
+    /// Count of Blocks read that were read recently before.
+    #[inline]
+    pub fn repeats(&self) -> u32 {
+        self.repeats
+    }
+    #[inline]
+    pub fn blocks_read(&self) -> u32 {
+        self.blocks.0
+    }
+    #[inline]
+    pub fn blocks_written(&self) -> u32 {
+        self.blocks.1
+    }
+    #[inline]
+    pub fn transactions(&self) -> u32 {
+        self.reads + self.writes
+    }
+}
+impl<B: BlockDevice, const N: usize> TracingDevice<B, N> {
+    #[inline]
+    pub const fn new(dev: B) -> TracingDevice<B, N> {
+        TracingDevice {
+            dev,
+            ops: [const { IoOp::EMPTY }; N],
+            pos: 0usize,
+            stats: IoStats::new(),
+            recent: [u32::MAX; RECENT],
+            next: 0usize,
+        }
+    }
+
+    #[inline]
+    pub fn stats(&self) -> &IoStats {
+        &self.stats
+    }
+    #[inline]
+    pub fn device(&mut self) -> &mut B {
+        &mut self.dev
+    }
+    #[inline]
+    pub fn into_inner(self) -> B {
+        self.dev
+    }
+    /// Clear the counters and the recorded operations.
+    #[inline]
+    pub fn reset(&mut self) {
+        (self.stats, self.pos, self.next) = (IoStats::new(), 0usize, 0usize);
+        self.recent = [u32::MAX; RECENT];
+    }
+    /// Returns the recorded operations, oldest first.
+    #[inline]
+    pub fn ops(&self) -> IoOpIter<'_, N> {
+        let p = self.pos;
+        IoOpIter {
+            ops:  &self.ops,
+            pos:  if N > 0 && p >= N { p % N } else { 0 },
+            left: p.min(N),
+        }
+    }
+
+    fn record(&mut self, lba: u32, count: u32, write: bool) {
+        if write {
+            (self.stats.writes, self.stats.blocks.1) = (self.stats.writes.saturating_add(1), self.stats.blocks.1.saturating_add(count));
+        } else {
+            (self.stats.reads, self.stats.blocks.0) = (self.stats.reads.saturating_add(1), self.stats.blocks.0.saturating_add(count));
+            for i in lba..lba.saturating_add(count) {
+                if self.recent.contains(&i) {
+                    self.stats.repeats = self.stats.repeats.saturating_add(1);
+                    continue;
+                }
+                unsafe { *self.recent.get_unchecked_mut(self.next) = i };
+                self.next = (self.next + 1) % RECENT;
+            }
+        }
+        if N == 0 {
+            return;
+        }
+        unsafe { *self.ops.get_unchecked_mut(self.pos % N) = IoOp { lba, count, write } };
+        self.pos = self.pos.wrapping_add(1);
+    }
+}
+
+impl<'a, const N: usize> Iterator for IoOpIter<'a, N> {
+    type Item = &'a IoOp;
+
+    #[inline]
+    fn next(&mut self) -> Option<&'a IoOp> {
+        if self.left == 0 {
+            return None;
+        }
+        let v = unsafe { self.ops.get_unchecked(self.pos) };
+        (self.pos, self.left) = ((self.pos + 1) % N, self.left - 1);
+        Some(v)
+    }
+}
+
+impl<B: BlockDevice, const N: usize> BlockDevice for TracingDevice<B, N> {
+    #[inline]
+    fn blocks(&mut self) -> DevResult<u32> {
+        self.dev.blocks()
+    }
+    #[inline]
+    fn write(&mut self, b: &[Block], start: u32) -> DevResult<()> {
+        self.record(start, b.len() as u32, true);
+        self.dev.write(b, start)
+    }
+    #[inline]
+    fn read(&mut self, b: &mut [Block], start: u32) -> DevResult<()> {
+        self.record(start, b.len() as u32, false);
+        self.dev.read(b, start)
+    }
+    #[inline]
+    fn is_read_only(&mut self) -> bool {
+        self.dev.is_read_only()
+    }
+    #[inline]
+    fn write_single(&mut self, b: &Block, start: u32) -> DevResult<()> {
+        self.record(start, 1, true);
+        self.dev.write_single(b, start)
+    }
+    #[inline]
+    fn read_single(&mut self, b: &mut Block, start: u32) -> DevResult<()> {
+        self.record(start, 1, false);
+        self.dev.read_single(b, start)
+    }
+}
+
+#[cfg(feature = "debug")]
+mod display {
+    extern crate core;
+
+    use core::fmt::{Debug, Formatter, Result};
+
+    use crate::fs::{BlockDevice, IoOp, IoStats, TracingDevice};
+
+    impl Debug for IoOp {
+        #[inline]
+        fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+            f.write_str(if self.write { "W " } else { "R " })?;
+            Debug::fmt(&self.lba, f)?;
+            f.write_str("+")?;
+            Debug::fmt(&self.count, f)
+        }
+    }
+    impl Debug for IoStats {
+        #[inline]
+        fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+            f.debug_struct("IoStats")
+                .field("reads", &self.reads)
+                .field("writes", &self.writes)
+                .field("repeats", &self.repeats)
+                .field("blocks_read", &self.blocks.0)
+                .field("blocks_written", &self.blocks.1)
+                .finish()
+        }
+    }
+    impl<B: BlockDevice, const N: usize> Debug for TracingDevice<B, N> {
+        #[inline]
+        fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+            f.debug_struct("TracingDevice")
+                .field("stats", &self.stats)
+                .field("ops", &Ops(self))
+                .finish()
+        }
+    }
+
+    struct Ops<'a, B: BlockDevice, const N: usize>(&'a TracingDevice<B, N>);
+
+    impl<B: BlockDevice, const N: usize> Debug for Ops<'_, B, N> {
+        #[inline]
+        fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+            f.debug_list().entries(self.0.ops()).finish()
+        }
+    }
+}
@@ -20,23 +20,44 @@
 #![no_implicit_prelude]
 
 extern crate core;
+#[cfg(feature = "pico")]
+extern crate rpsp;
 
 use core::cell::UnsafeCell;
 use core::cmp::{PartialEq, PartialOrd};
-use core::convert::AsRef;
+use core::convert::{AsRef, From};
 use core::iter::{FusedIterator, IntoIterator, Iterator};
 use core::matches;
+use core::ops::{FnMut, FnOnce};
 use core::option::Option::{self, None, Some};
+use core::ptr::NonNull;
 use core::result::Result::{Err, Ok};
 
-use crate::fs::{Block, BlockCache, BlockDevice, BlockEntryIter, Cache, DevResult, DeviceError, Storage};
+#[cfg(feature = "pico")]
+use rpsp::clock::{RtcError, TimeSource};
+#[cfg(feature = "pico")]
+use rpsp::time::Time;
+
+use crate::fs::state::Safe;
+use crate::fs::{Block, BlockBuffer, BlockCache, BlockDevice, BlockEntryIter, CacheProvider, DevResult, DeviceError, SharedCache, Storage};
 use crate::{Slice, SliceMut};
+#[cfg(not(feature = "pico"))]
+use crate::fs::{RtcError, Time, TimeSource};
+
+// Count of numeric tails tried, 4 plain and then "~1" to "~999999" with the
+// name hash, which is shortened to make room for longer tails like Windows.
+const ALIAS_MAX: u32 = 0xF4243u32;
 
+mod check;
+mod delete;
+mod exfat;
 mod file;
 mod iter;
 mod name;
 mod objects;
 
+pub use self::check::*;
+pub use self::delete::*;
 pub use self::file::*;
 pub use self::iter::*;
 pub use self::name::*;
@@ -44,63 +65,212 @@ pub use self::objects::*;
 
 const CLUSTER_EOF: u32 = 0xFFFFFFFFu32;
 
-pub struct Volume<'a, B: BlockDevice> {
-    dev:   &'a Storage<B>,
-    ver:   FatVersion,
-    info:  Clusters,
-    name:  VolumeName,
-    block: Blocks,
-    index: Index,
+pub struct Volume<'a, B: BlockDevice, C: CacheProvider = SharedCache> {
+    dev:    &'a Storage<B, C>,
+    ver:    FatVersion,
+    info:   Clusters,
+    name:   VolumeName,
+    block:  Blocks,
+    index:  Index,
+    table:  Tables,
+    clock:  Clock<'a>,
+    open:   Handles,
+    state:  State,
+    serial: u32,
+    ro:     bool,
 }
 
 struct Index {
-    fat:  u32,
-    lba:  u32,
-    data: u32,
-    root: ClusterIndex,
+    fat:   u32,
+    lba:   u32,
+    data:  u32,
+    size:  u32,
+    root:  ClusterIndex,
+    fats:  u8,
+    flags: u8,
+}
+struct Clock<'a> {
+    src:    Option<NonNull<dyn TimeSource<Error = RtcError> + 'a>>,
+    access: bool,
 }
 struct Blocks {
     count:    u32,
-    clusters: u8,
+    clusters: u32,
+}
+struct Tables {
+    size:   u32,
+    bitmap: Cluster,
+    upcase: Cluster,
 }
 struct Clusters {
     next:  UnsafeCell<Cluster>,
-    free:  UnsafeCell<Cluster>,
+    // Free Cluster count, 0xFFFFFFFF when unknown like in the FSInfo sector.
+    free:  UnsafeCell<u32>,
     count: u32,
 }
+struct Handle {
+    block:  u32,
+    offset: u32,
+    count:  u8,
+}
+// Open File entries, keyed by the Directory Block and offset of their entry.
+struct Handles(UnsafeCell<[Handle; Handles::MAX]>);
+// Mount state of the Volume, a mix of the 'State' flags.
+struct State(UnsafeCell<u8>);
 struct PathIter<'a>(&'a [u8]);
 
 impl Index {
     #[inline]
-    fn new(fat: u32, lba: u32, data: u32, root: u32) -> DevResult<Index> {
+    fn new(b: &[u8], fat: u32, lba: u32, data: u32, root: ClusterIndex, flags: u8) -> DevResult<Index> {
+        // 0x10 - Number of FATs
+        let n = b.read_u8(16);
+        if n == 0 {
+            return Err(DeviceError::InvalidFileSystem);
+        }
         Ok(Index {
             fat,
             lba,
             data,
-            root: ClusterIndex::new(root).ok_or(DeviceError::InvalidFileSystem)?,
+            flags,
+            root,
+            size: _fat_size(b),
+            fats: n,
         })
     }
+
+    #[inline]
+    fn active(&self) -> u32 {
+        // NOTE(sf): When mirroring is disabled, only the FAT selected by the
+        //           lower bits of the flags is used for both read and writes.
+        if self.is_mirrored() { 0 } else { (self.flags & 0xF) as u32 * self.size }
+    }
+    #[inline]
+    fn is_mirrored(&self) -> bool {
+        self.flags & 0x80 == 0
+    }
 }
 impl Blocks {
     #[inline]
-    const fn new(count: u32, clusters: u8) -> Blocks {
+    const fn new(count: u32, clusters: u32) -> Blocks {
         Blocks { count, clusters }
     }
 
     #[inline]
     const fn bytes(&self) -> u32 {
-        self.clusters as u32 * Block::SIZE as u32
+        self.clusters * Block::SIZE as u32
     }
     #[inline]
     const fn blocks(&self) -> u32 {
-        self.clusters as u32
+        self.clusters
+    }
+}
+impl Tables {
+    #[inline]
+    const fn empty() -> Tables {
+        Tables {
+            size:   0u32,
+            bitmap: None,
+            upcase: None,
+        }
+    }
+}
+impl Clock<'_> {
+    #[inline]
+    const fn empty() -> Clock<'static> {
+        Clock { src: None, access: false }
+    }
+}
+impl Handles {
+    const MAX: usize = 0x10usize;
+    const WRITER: u8 = 0xFFu8;
+
+    #[inline]
+    const fn new() -> Handles {
+        Handles(UnsafeCell::new(
+            [const {
+                Handle {
+                    block:  0u32,
+                    offset: 0u32,
+                    count:  0u8,
+                }
+            }; Handles::MAX],
+        ))
+    }
+
+    /// Track a new handle to the entry. Writers need the entry to not be open
+    /// by anything else, readers only need it to not be open by a writer.
+    fn open(&self, block: u32, offset: u32, write: bool) -> DevResult<()> {
+        let (t, mut f) = (unsafe { &mut *self.0.get() }, None);
+        for (i, h) in t.iter_mut().enumerate() {
+            if h.count == 0 {
+                f = f.or(Some(i));
+                continue;
+            }
+            if h.block != block || h.offset != offset {
+                continue;
+            }
+            if write || h.count == Handles::WRITER {
+                return Err(DeviceError::FileInUse);
+            }
+            if h.count == Handles::WRITER - 1 {
+                return Err(DeviceError::Overflow);
+            }
+            h.count += 1;
+            return Ok(());
+        }
+        let h = unsafe { t.get_unchecked_mut(f.ok_or(DeviceError::Overflow)?) };
+        (h.block, h.offset, h.count) = (block, offset, if write { Handles::WRITER } else { 1 });
+        Ok(())
+    }
+    fn close(&self, block: u32, offset: u32) {
+        for h in unsafe { &mut *self.0.get() }.iter_mut() {
+            if h.count == 0 || h.block != block || h.offset != offset {
+                continue;
+            }
+            h.count = if h.count == Handles::WRITER { 0 } else { h.count - 1 };
+            return;
+        }
+    }
+    #[inline]
+    fn is_open(&self, block: u32, offset: u32) -> bool {
+        unsafe { &*self.0.get() }.iter().any(|h| h.count > 0 && h.block == block && h.offset == offset)
+    }
+    #[inline]
+    fn is_writing(&self) -> bool {
+        unsafe { &*self.0.get() }.iter().any(|h| h.count == Handles::WRITER)
+    }
+}
+impl State {
+    /// The dirty bit was set on the device during this mount.
+    const DIRTY: u8 = 0x1u8;
+    /// The Volume was not unmounted cleanly before it was mounted.
+    const UNCLEAN: u8 = 0x2u8;
+    /// The free Cluster count is known to be correct.
+    const COUNTED: u8 = 0x4u8;
+
+    #[inline]
+    const fn new() -> State {
+        State(UnsafeCell::new(0u8))
+    }
+
+    #[inline(always)]
+    fn is(&self, v: u8) -> bool {
+        unsafe { *self.0.get() & v != 0 }
+    }
+    #[inline(always)]
+    fn set(&self, v: u8) {
+        unsafe { *self.0.get() |= v }
+    }
+    #[inline(always)]
+    fn clear(&self, v: u8) {
+        unsafe { *self.0.get() &= !v }
     }
 }
 impl Clusters {
     #[inline]
     const fn new_16(v: u32) -> Clusters {
         Clusters {
-            free:  UnsafeCell::new(None),
+            free:  UnsafeCell::new(0xFFFFFFFFu32),
             next:  UnsafeCell::new(None),
             count: v,
         }
@@ -108,10 +278,7 @@ impl Clusters {
     #[inline]
     const fn new_32(v: u32, next: u32, free: u32) -> Clusters {
         Clusters {
-            free:  UnsafeCell::new(match free {
-                0 | 0xFFFFFFFF => None,
-                v => Some(unsafe { ClusterIndex::new_unchecked(v) }),
-            }),
+            free:  UnsafeCell::new(free),
             next:  UnsafeCell::new(match next {
                 0 | 0x1 | 0xFFFFFFFF => None,
                 v => Some(unsafe { ClusterIndex::new_unchecked(v) }),
@@ -122,92 +289,142 @@ impl Clusters {
 
     #[inline]
     fn free_add(&self) {
-        ClusterIndex::add_one(unsafe { &mut *self.free.get() })
+        let v = unsafe { &mut *self.free.get() };
+        if *v != 0xFFFFFFFF {
+            *v = v.saturating_add(1);
+        }
     }
     #[inline]
     fn free_remove(&self) {
-        ClusterIndex::sub_one(unsafe { &mut *self.free.get() })
+        let v = unsafe { &mut *self.free.get() };
+        if *v != 0xFFFFFFFF {
+            *v = v.saturating_sub(1);
+        }
     }
     #[inline(always)]
     fn next(&self) -> Cluster {
         unsafe { *self.next.get() }
     }
     #[inline(always)]
-    fn free(&self) -> Cluster {
-        unsafe { *self.free.get() }
+    fn free(&self) -> Option<u32> {
+        match unsafe { *self.free.get() } {
+            0xFFFFFFFF => None,
+            v => Some(v),
+        }
     }
     #[inline(always)]
     fn is_empty(&self) -> bool {
-        unsafe { (&*self.next.get()).is_none() && (&*self.free.get()).is_none() }
+        unsafe { (&*self.next.get()).is_none() && *self.free.get() == 0xFFFFFFFF }
+    }
+    #[inline]
+    fn free_reset(&self, v: u32) {
+        unsafe { *self.free.get() = v }
     }
     #[inline]
     fn next_set(&self, v: Cluster) {
         unsafe { *self.next.get() = v }
     }
+    /// Move the next free hint back to 'v' if it's before the current one.
     #[inline]
-    fn free_set(&self, v: ClusterIndex) {
-        let n = unsafe { &mut *self.free.get() };
+    fn next_lower(&self, v: ClusterIndex) {
+        let n = unsafe { &mut *self.next.get() };
         if n.is_some_and(|i| i.le(&v)) {
             return;
         }
         *n = Some(v);
     }
 }
-impl<'a, B: BlockDevice> Volume<'a, B> {
-    pub(super) fn new(dev: &'a Storage<B>, b: &mut Block, lba: u32, blocks: u32) -> DevResult<Volume<'a, B>> {
+impl<'a, B: BlockDevice, C: CacheProvider> Volume<'a, B, C> {
+    pub(super) fn new(dev: &'a Storage<B, C>, b: &mut Block, lba: u32, blocks: u32) -> DevResult<Volume<'a, B, C>> {
         let _ = dev.read_single(b, lba)?;
         // 0x1FE - Boot Sector Signature
         if b.read_u16(510) != 0xAA55 {
             return Err(DeviceError::InvalidFileSystem);
         }
+        // 0x3 - OEM Name
+        if b.read_slice(3, 8) == b"EXFAT   " {
+            return Volume::new_ex(dev, b, lba, blocks);
+        }
         // 0x11 - Max Root Directory Entries
         let e = b.read_u16(17) as u32 * DirEntry::SIZE as u32;
-        let (h, c) = (e / Block::SIZE as u32, _blocks(b));
+        let (h, c) = (e.div_ceil(Block::SIZE as u32), _blocks(b));
         // 0xD - Logical Sectors per Cluster
         // 0xE - Count of Reserved Logical Sectors
         let (r, s, k) = (b.read_u16(14) as u32, _size(b), b.read_u8(13));
-        let n = (c - (r + s + if e != h { h + 1 } else { h })) / k as u32;
-        if n < 0xFF5 {
-            return Err(DeviceError::UnsupportedFileSystem);
+        if k == 0 || c <= r + s + h {
+            return Err(DeviceError::InvalidFileSystem);
         }
-        let z = Blocks::new(blocks, k);
+        let n = (c - (r + s + h)) / k as u32;
+        // NOTE(sf): Zero blocks means the size is unknown, so we use what the
+        //           boot sector says.
+        let z = Blocks::new(if blocks == 0 { c } else { blocks }, k as u32);
         let v = VolumeName::new(n > 0xFFF5, b);
+        // 0x26 - Extended Boot Signature
+        // 0x27 - Volume Serial
+        let o = if n > 0xFFF5 { 0x1C } else { 0 };
+        let d = if b.read_u8(0x26 + o) == 0x29 { b.read_u32(0x27 + o) } else { 0 };
         if n <= 0xFFF5 {
             // 0xB - Bytes per Logical Sector
             if b.read_u16(11) as usize != Block::SIZE {
                 return Err(DeviceError::InvalidFileSystem);
             }
-            return Ok(Volume {
+            // NOTE(sf): FAT12 and FAT16 keep the root Directory in a fixed
+            //           area between the FATs and the data region, so it has
+            //           no Cluster.
+            let v = Volume {
                 dev,
-                ver: FatVersion::new_16(b.read_u16(17)),
+                ver: if n < 0xFF5 { FatVersion::new_12(b.read_u16(17)) } else { FatVersion::new_16(b.read_u16(17)) },
                 info: Clusters::new_16(n),
                 name: v,
                 block: z,
-                index: Index::new(
-                    r,
-                    lba,
-                    r + s + ((e + (Block::SIZE as u32 - 1)) / Block::SIZE as u32),
-                    r + s,
-                )?,
-            });
+                index: Index::new(b, r, lba, r + s + h, ClusterIndex::EMPTY, 0)?,
+                table: Tables::empty(),
+                clock: Clock::empty(),
+                open: Handles::new(),
+                state: State::new(),
+                serial: d,
+                ro: dev.device().is_read_only(),
+            };
+            let _ = v.load_state(b)?;
+            return Ok(v);
         }
+        // 0x28 - Mirroring Flags
         // 0x2C - Cluster Root Start
         // 0x30 - FS Information Sector
         let (i, t) = (b.read_u16(48) as u32 + lba, b.read_u32(44));
+        let x = Index::new(
+            b,
+            r,
+            lba,
+            r + s,
+            ClusterIndex::new(t).ok_or(DeviceError::InvalidFileSystem)?,
+            b.read_u16(40) as u8,
+        )?;
+        if (x.flags & 0xF) >= x.fats && !x.is_mirrored() {
+            return Err(DeviceError::InvalidFileSystem);
+        }
         let _ = dev.read_single(b, i)?;
         if b.read_u32(0) != 0x41615252 || b.read_u32(484) != 0x61417272 || b.read_u32(508) != 0xAA550000 {
             return Err(DeviceError::InvalidFileSystem);
         }
         // 0x1E8 - Last Known Number of Free Data Clusters
         // 0x1EC - Most Recent Allocated Cluster
-        Ok(Volume {
+        let v = Volume {
             dev,
             ver: FatVersion::new_32(i),
             info: Clusters::new_32(n, b.read_u32(492), b.read_u32(488)),
             name: v,
             block: z,
-            index: Index::new(r, lba, r + s, t)?,
-        })
+            index: x,
+            table: Tables::empty(),
+            clock: Clock::empty(),
+            open: Handles::new(),
+            state: State::new(),
+            serial: d,
+            ro: dev.device().is_read_only(),
+        };
+        let _ = v.load_state(b)?;
+        Ok(v)
     }
 
     #[inline]
@@ -215,6 +432,131 @@ impl<'a, B: BlockDevice> Volume<'a, B> {
         self.index.fat
     }
     #[inline]
+    pub fn fat_size(&self) -> u32 {
+        self.index.size
+    }
+    #[inline]
+    pub fn fat_count(&self) -> u8 {
+        self.index.fats
+    }
+    #[inline]
+    pub fn is_fat_mirrored(&self) -> bool {
+        self.index.is_mirrored()
+    }
+    /// Returns true if reading a File updates it's last access date.
+    #[inline]
+    pub fn is_access_time(&self) -> bool {
+        self.clock.access && self.clock.src.is_some() && !self.ro
+    }
+    /// Returns false if the Volume was not unmounted cleanly the last time it
+    /// was used, which means the free Cluster count may be wrong.
+    ///
+    /// Only FAT16 and FAT32 Volumes keep track of this.
+    #[inline]
+    pub fn is_clean(&self) -> bool {
+        !self.state.is(State::UNCLEAN)
+    }
+    /// Returns true if the Volume has been written to since it was mounted
+    /// (or last unmounted).
+    #[inline]
+    pub fn is_dirty(&self) -> bool {
+        self.state.is(State::DIRTY)
+    }
+    /// Returns the size of the data area of the Volume in bytes.
+    #[inline]
+    pub fn total_space(&self) -> u64 {
+        self.info.count as u64 * self.block.bytes() as u64
+    }
+    /// Returns the free space of the Volume in bytes.
+    ///
+    /// The free Cluster count is recounted first if it's not known or can't
+    /// be trusted, which reads the whole FAT.
+    pub fn free_space(&self) -> DevResult<u64> {
+        let n = if self.state.is(State::COUNTED) { self.info.free().unwrap_or(0) } else { self.recount()? };
+        Ok(n as u64 * self.block.bytes() as u64)
+    }
+    /// Count the free Clusters by reading the FAT (or the Bitmap on exFAT) and
+    /// update the free Cluster count with the result.
+    ///
+    /// This should be used when 'is_clean' returns false, as the count kept
+    /// in the FSInfo sector may be wrong.
+    pub fn recount(&self) -> DevResult<u32> {
+        let mut b = self.dev.block_a()?;
+        let (mut c, mut n) = (BlockCache::new(), 0u32);
+        for i in 0x2..self.info.count + 0x2 {
+            let f = if self.ver.is_exfat() { self.ex_is_free(&mut b, &mut c, i)? } else { self.value(&mut b, &mut c, i)? == 0 };
+            if f {
+                n += 1;
+            }
+        }
+        self.info.free_reset(n);
+        self.state.set(State::COUNTED);
+        if !self.ro {
+            let _ = self.sync(&mut b)?;
+        }
+        Ok(n)
+    }
+    /// Write the free Cluster count and mark the Volume as cleanly unmounted.
+    ///
+    /// Any Files open for writing must be closed first. The Volume can still
+    /// be used after, but the next write will mark it as dirty again.
+    pub fn unmount(&self) -> DevResult<()> {
+        if self.ro || !self.state.is(State::DIRTY | State::UNCLEAN) {
+            return Ok(());
+        }
+        if self.open.is_writing() {
+            return Err(DeviceError::FileInUse);
+        }
+        // NOTE(sf): Don't mark the Volume as clean with a free Cluster count
+        //           we can't trust, as it would be used on the next mount.
+        if self.ver.is_fat32() && !self.state.is(State::COUNTED) {
+            let _ = self.recount()?;
+        }
+        let _ = self.sync(&mut *self.dev.block_a()?)?;
+        let _ = self.mark(true)?;
+        self.state.clear(State::UNCLEAN);
+        Ok(())
+    }
+    /// Returns true if the Volume is mounted read only.
+    #[inline]
+    pub fn is_read_only(&self) -> bool {
+        self.ro
+    }
+    /// Set the Volume as read only. When read only, anything that would write
+    /// to the device returns 'NotWritable' instead, this includes opening a
+    /// File with any of the write modes.
+    ///
+    /// A Volume on a read only device can't be made writable.
+    #[inline]
+    pub fn set_read_only(&mut self, ro: bool) {
+        self.ro = ro || self.dev.device().is_read_only();
+    }
+    /// Set the TimeSource used to timestamp new Files and Directories and
+    /// update the modified time of written Files.
+    ///
+    /// If 'access' is true, the last access date of Files will also be
+    /// updated when they are read.
+    #[inline]
+    pub fn set_time_source(&mut self, src: &'a mut dyn TimeSource<Error = RtcError>, access: bool) {
+        self.clock = Clock {
+            src: Some(NonNull::from(src)),
+            access,
+        };
+    }
+    #[inline]
+    pub fn clear_time_source(&mut self) {
+        self.clock = Clock::empty();
+    }
+    /// Returns the current time from the TimeSource, if one is set and it
+    /// returns a valid time.
+    #[inline]
+    pub fn now(&self) -> Option<Time> {
+        // NOTE(sf): The TimeSource can only be set with a mutable reference
+        //           that outlives the Volume, so this is safe.
+        let t = unsafe { (&mut *self.clock.src?.as_ptr()).now() }.ok()?;
+        if t.is_valid() { Some(t) } else { None }
+    }
+    #[inline]
     pub fn pos_lba(&self) -> u32 {
         self.index.lba
     }
@@ -224,18 +566,48 @@ impl<'a, B: BlockDevice> Volume<'a, B> {
     }
     #[inline]
     pub fn pos_root(&self) -> u32 {
-        self.index.root.get()
+        if self.index.root.is_empty() { self.index.fat + self.index.size * self.index.fats as u32 } else { self.index.root.get() }
     }
     #[inline]
     pub fn name(&self) -> &VolumeName {
         &self.name
     }
+    /// Returns the Volume serial ID, which is set when the Volume is
+    /// formatted. This is zero if the Volume does not have one.
+    #[inline]
+    pub fn serial(&self) -> u32 {
+        self.serial
+    }
+    /// Set the Volume label, which is stored in the boot sector and in the
+    /// label entry of the root Directory.
+    ///
+    /// Chars not allowed in a label are replaced with '_' and the label is
+    /// truncated to 11 chars. An empty label removes the label entry.
+    pub fn set_label(&mut self, v: impl AsRef<str>) -> DevResult<()> {
+        let _ = self.mark_dirty()?;
+        let n = VolumeName::from_str(v);
+        {
+            let mut b = self.dev.block_a()?;
+            if self.ver.is_exfat() {
+                let _ = self.ex_label(&mut b, &n)?;
+            } else {
+                let _ = self.label(&mut b, &n)?;
+            }
+        }
+        // The boot sector uses "NO NAME" when there's no label.
+        self.name = if n.is_empty() && !self.ver.is_exfat() { VolumeName::NO_NAME } else { n };
+        Ok(())
+    }
     #[inline]
     pub fn cluster_count(&self) -> u32 {
         self.info.count
     }
     #[inline]
-    pub fn device(&self) -> &Storage<B> {
+    pub fn block_count(&self) -> u32 {
+        self.block.count
+    }
+    #[inline]
+    pub fn device(&self) -> &Storage<B, C> {
         self.dev
     }
     #[inline]
@@ -243,63 +615,235 @@ impl<'a, B: BlockDevice> Volume<'a, B> {
         &self.ver
     }
     #[inline]
-    pub fn dir_root(&'a self) -> Directory<'a, B> {
+    pub fn dir_root(&'a self) -> Directory<'a, B, C> {
         Directory::new(DirEntry::new_root(), self)
     }
     #[inline]
-    pub fn open(&'a self, path: impl AsRef<str>) -> DevResult<File<'a, B>> {
+    pub fn open(&'a self, path: impl AsRef<str>) -> DevResult<File<'a, B, Safe, C>> {
         self.open_inner(path.as_ref().as_bytes(), Mode::READ)
     }
     #[inline]
-    pub fn file_create(&'a self, path: impl AsRef<str>) -> DevResult<File<'a, B>> {
+    pub fn file_create(&'a self, path: impl AsRef<str>) -> DevResult<File<'a, B, Safe, C>> {
         self.open_inner(
             path.as_ref().as_bytes(),
             Mode::WRITE | Mode::CREATE | Mode::TRUNCATE,
         )
     }
     #[inline]
-    pub fn dir_open(&'a self, path: impl AsRef<str>) -> DevResult<Directory<'a, B>> {
+    pub fn dir_open(&'a self, path: impl AsRef<str>) -> DevResult<Directory<'a, B, C>> {
         let p = path.as_ref().as_bytes();
         if p.is_empty() || (p.len() == 1 && unsafe { is_sep(p.get_unchecked(0)) }) {
             return Ok(Directory::new(DirEntry::new_root(), self));
         }
-        let mut x = DirectoryIndex::new(self);
+        let mut x = DirectoryIndex::new(self)?;
         {
-            let mut b = Cache::block_a();
+            let mut b = self.dev.block_a()?;
             self.find_dir(&mut x, &mut b, p, false)
         }
     }
     #[inline]
-    pub fn dir_create(&'a self, path: impl AsRef<str>) -> DevResult<Directory<'a, B>> {
-        let mut x = DirectoryIndex::new(self);
+    pub fn dir_create(&'a self, path: impl AsRef<str>) -> DevResult<Directory<'a, B, C>> {
+        let mut x = DirectoryIndex::new(self)?;
         {
-            let mut b = Cache::block_a();
+            let mut b = self.dev.block_a()?;
             self.find_dir(&mut x, &mut b, path.as_ref().as_bytes(), true)
         }
     }
+    /// Open the File at the path with the supplied 'Mode' flags.
+    ///
+    /// Any number of readers can have the same File open, but a writer needs
+    /// to be the only handle, otherwise a 'FileInUse' error is returned.
     #[inline]
-    pub fn file_open(&'a self, path: impl AsRef<str>, mode: u8) -> DevResult<File<'a, B>> {
+    pub fn file_open(&'a self, path: impl AsRef<str>, mode: u8) -> DevResult<File<'a, B, Safe, C>> {
         self.open_inner(path.as_ref().as_bytes(), mode)
     }
+    /// Returns the File or Directory entry at the path.
+    #[inline]
+    pub fn stat(&'a self, path: impl AsRef<str>) -> DevResult<DirEntry> {
+        let mut x = DirectoryIndex::new(self)?;
+        {
+            let mut b = self.dev.block_a()?;
+            self.stat_inner(&mut x, &mut b, path.as_ref().as_bytes())
+        }
+    }
+    /// Returns true if a File or Directory exists at the path.
+    #[inline]
+    pub fn exists(&'a self, path: impl AsRef<str>) -> DevResult<bool> {
+        match self.stat(path) {
+            Ok(_) => Ok(true),
+            Err(DeviceError::NotFound | DeviceError::NotADirectory) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+    /// Remove the File or empty Directory at the path.
+    ///
+    /// Returns a `NonEmptyDirectory` error if the Directory has any entries.
+    pub fn remove(&'a self, path: impl AsRef<str>) -> DevResult<()> {
+        let mut x = DirectoryIndex::new(self)?;
+        {
+            let mut b = self.dev.block_a()?;
+            let e = self.stat_inner(&mut x, &mut b, path.as_ref().as_bytes())?;
+            if e.filename().is_empty() {
+                return Err(DeviceError::InvalidOptions);
+            }
+            if e.is_directory() {
+                let _ = unsafe { x.reset_entry(Some(&e))? };
+                if x.find(|v| !v.shortname().is_self() && !v.shortname().is_parent())?.is_some() {
+                    return Err(DeviceError::NonEmptyDirectory);
+                }
+            }
+            e.delete(self, &mut b)
+        }
+    }
+    /// Replace the contents of the File at the path, creating it if needed,
+    /// so that either the old or the new contents survive a power loss.
+    ///
+    /// The data is written to a temporary File next to the target first. Its
+    /// Cluster chain is then swapped into the target entry with a single
+    /// Block write, before the old chain is freed. A power loss can at worst
+    /// leave the temporary File or some lost Clusters behind, which 'check'
+    /// can clean up.
+    ///
+    /// On exFAT the swap is only a single Block write when the entry set of
+    /// the target does not span two Blocks, otherwise a power loss during the
+    /// swap can leave the set with a bad Set Checksum.
+    pub fn write_atomic(&'a self, path: impl AsRef<str>, data: &[u8]) -> DevResult<()> {
+        let p = path.as_ref().as_bytes();
+        let (d, n) = match p.iter().rposition(is_sep) {
+            Some(i) => (unsafe { p.get_unchecked(0..i) }, unsafe { p.get_unchecked(i + 1..) }),
+            None => (unsafe { p.get_unchecked(0..0) }, p),
+        };
+        if n.is_empty() {
+            return Err(DeviceError::NotAFile);
+        }
+        if n.len() + 4 > LongName::SIZE {
+            return Err(DeviceError::NameTooLong);
+        }
+        let mut t = [0u8; LongName::SIZE];
+        t.write_from(0, n);
+        t.write_from(n.len(), b".tmp");
+        let mut x = DirectoryIndex::new(self)?;
+        let (d, mut f) = {
+            let mut b = self.dev.block_a()?;
+            let d = if d.is_empty() { None } else { Some(self.find_dir(&mut x, &mut b, d, false)?) };
+            let f = self.create_file(
+                &mut x,
+                &mut b,
+                unsafe { t.get_unchecked(0..n.len() + 4) },
+                d.as_deref(),
+                Mode::WRITE | Mode::CREATE | Mode::TRUNCATE,
+            )?;
+            (d, f)
+        };
+        let mut i = 0usize;
+        while i < data.len() {
+            match f.write(unsafe { data.get_unchecked(i..) })? {
+                0 => return Err(DeviceError::NoSpace),
+                v => i += v,
+            }
+        }
+        // Flushes the data, FAT and the temporary entry.
+        let e = f.into_entry()?;
+        let _ = unsafe { x.reset_entry(d.as_deref())? };
+        let mut b = self.dev.block_a()?;
+        let mut v = match x.find(|v| v.eq(n))? {
+            Some(v) if v.is_directory() => {
+                let _ = e.delete(self, &mut b)?;
+                return Err(DeviceError::NotAFile);
+            },
+            Some(v) if v.is_open(self) => {
+                let _ = e.delete(self, &mut b)?;
+                return Err(DeviceError::FileInUse);
+            },
+            Some(v) => {
+                let _ = e.unlink(self, &mut b)?;
+                v
+            },
+            None => {
+                let _ = e.unlink(self, &mut b)?;
+                self.create(&mut b, x.lfn(), n, 0, d.as_deref(), false)?
+            },
+        };
+        let _ = v.replace_data(self, &e, &mut b)?;
+        self.sync(&mut b)
+    }
+    /// Copy the File at the 'src' path to the 'dst' path on this Volume.
+    ///
+    /// See 'copy_to' for the details.
+    #[inline]
+    pub fn copy(&'a self, src: impl AsRef<str>, dst: impl AsRef<str>, overwrite: bool, buf: &mut BlockBuffer, progress: impl FnMut(u32, u32)) -> DevResult<u32> {
+        self.copy_to(self, src, dst, overwrite, buf, progress)
+    }
+    /// Copy the File at the 'src' path to the 'dst' path on the 'to' Volume,
+    /// which can be this Volume or another Volume on the same Storage.
+    ///
+    /// The destination is created if needed. If it already exists and
+    /// 'overwrite' is false, an 'AlreadyExists' error is returned. If the copy
+    /// fails part way, the destination File is removed.
+    ///
+    /// The data is moved through 'buf' several Blocks at a time, its previous
+    /// contents are lost.
+    ///
+    /// 'progress' is called with the bytes copied and the total size as the
+    /// copy goes on. It must not use either Volume, as the cache Block is held
+    /// while it runs.
+    ///
+    /// Returns the count of bytes copied.
+    pub fn copy_to<'b>(&'a self, to: &'b Volume<'b, B, C>, src: impl AsRef<str>, dst: impl AsRef<str>, overwrite: bool, buf: &mut BlockBuffer, progress: impl FnMut(u32, u32)) -> DevResult<u32> {
+        let mut s = self.open(src)?;
+        let p = dst.as_ref();
+        if !overwrite && to.exists(p)? {
+            return Err(DeviceError::AlreadyExists);
+        }
+        let mut d = to.file_create(p)?;
+        match d.copy_from(&mut s, buf, progress) {
+            Ok(v) => {
+                let _ = d.close()?;
+                Ok(v)
+            },
+            Err(e) => {
+                let _ = d.delete();
+                Err(e)
+            },
+        }
+    }
+    /// Set the created and modified times of the File or Directory at the
+    /// path.
+    #[inline]
+    pub fn set_times(&'a self, path: impl AsRef<str>, created: Time, modified: Time) -> DevResult<()> {
+        self.modify(path.as_ref().as_bytes(), |e| {
+            e.set_created(created);
+            e.set_modified(modified);
+        })
+    }
+    /// Set the attributes of the File or Directory at the path.
+    ///
+    /// Only the read-only (0x1), hidden (0x2), system (0x4) and archive (0x20)
+    /// attributes can be changed, the others are ignored.
+    #[inline]
+    pub fn set_attributes(&'a self, path: impl AsRef<str>, attrs: u8) -> DevResult<()> {
+        self.modify(path.as_ref().as_bytes(), |e| {
+            e.set_attributes((e.attributes() & 0x18) | (attrs & 0x27))
+        })
+    }
 
     #[inline]
-    pub unsafe fn list_entry(&'a self, target: Option<&DirEntry>) -> DevResult<DirectoryIndex<'a, B>> {
-        let mut x = DirectoryIndex::new(self);
-        let _ = unsafe { x.reset_cluster(target.and_then(|v| v.cluster()))? };
+    pub unsafe fn list_entry(&'a self, target: Option<&DirEntry>) -> DevResult<DirectoryIndex<'a, B, C>> {
+        let mut x = DirectoryIndex::new(self)?;
+        let _ = unsafe { x.reset_entry(target)? };
         Ok(x)
     }
     #[inline]
-    pub unsafe fn file_entry(&'a self, name: impl AsRef<str>, parent: Option<&DirEntry>, mode: u8) -> DevResult<File<'a, B>> {
-        let mut x = DirectoryIndex::new(self);
+    pub unsafe fn file_entry(&'a self, name: impl AsRef<str>, parent: Option<&DirEntry>, mode: u8) -> DevResult<File<'a, B, Safe, C>> {
+        let mut x = DirectoryIndex::new(self)?;
         {
-            let mut b = Cache::block_a();
+            let mut b = self.dev.block_a()?;
             self.create_file(&mut x, &mut b, name.as_ref().as_bytes(), parent, mode)
         }
     }
-    pub unsafe fn dir_entry(&'a self, name: impl AsRef<str>, parent: Option<&DirEntry>, create: bool) -> DevResult<Directory<'a, B>> {
-        let p = parent.and_then(|v| v.cluster());
-        let mut x = DirectoryIndex::new(self);
-        let _ = unsafe { x.reset_cluster(p)? };
+    pub unsafe fn dir_entry(&'a self, name: impl AsRef<str>, parent: Option<&DirEntry>, create: bool) -> DevResult<Directory<'a, B, C>> {
+        let mut x = DirectoryIndex::new(self)?;
+        let _ = unsafe { x.reset_entry(parent)? };
         let n = name.as_ref().as_bytes();
         if let Some(e) = x.find(|e| e.eq(n))? {
             return if e.is_directory() { Ok(Directory::new(e, self)) } else { Err(DeviceError::NotADirectory) };
@@ -308,47 +852,121 @@ impl<'a, B: BlockDevice> Volume<'a, B> {
             return Err(DeviceError::NotFound);
         }
         {
-            let mut b = Cache::block_a();
-            Ok(Directory::new(self.create_dir(&mut b, n, p)?, self))
+            let mut b = self.dev.block_a()?;
+            Ok(Directory::new(self.create_dir(&mut b, x.lfn(), n, parent)?, self))
         }
     }
 
+    /// Returns the Cluster, first Block and Block count of the Directory.
+    ///
+    /// The fixed FAT12/FAT16 root Directory returns the 'EMPTY' Cluster, as
+    /// it can't be followed or extended.
     #[inline]
-    fn entries(&self, idx: Cluster) -> u32 {
-        match idx {
-            Some(_) => self.block.blocks(),
-            None if self.ver.is_fat32() => self.block.blocks(),
-            None => self.ver.sector() * DirEntry::SIZE as u32,
-        }
-    }
-    #[inline]
-    fn block_pos(&self, idx: Cluster) -> u32 {
+    fn dir_pos(&self, idx: Cluster) -> (ClusterIndex, u32, u32) {
         match idx {
-            Some(v) => self.block_pos_at(v),
-            None => self.block_pos_at(self.index.root),
+            Some(v) => (v, self.block_pos_at(v), self.block.blocks()),
+            None if self.index.root.is_empty() => {
+                let i = self.pos_root();
+                (ClusterIndex::EMPTY, self.index.lba + i, self.index.data - i)
+            },
+            None => (self.index.root, self.block_pos_at(self.index.root), self.block.blocks()),
         }
     }
     #[inline]
     fn offset(&self, idx: u32) -> (usize, u32) {
-        let v = if self.ver.is_fat32() { 4 } else { 2 };
+        // NOTE(sf): FAT12 entries are 1.5 bytes, so they may start on the
+        //           last byte of a Block.
+        let v = match &self.ver {
+            x if x.is_fat12() => idx as usize + (idx as usize / 2),
+            x if x.is_fat16() => idx as usize * 2,
+            _ => idx as usize * 4,
+        };
         (
-            (idx as usize * v) % Block::SIZE,
-            self.index.lba + (self.index.fat + ((idx as usize * v) as u32 / Block::SIZE as u32)),
+            v % Block::SIZE,
+            self.index.lba + self.index.fat + self.index.active() + (v / Block::SIZE) as u32,
         )
     }
     #[inline]
-    fn root(&self, idx: Cluster) -> ClusterIndex {
-        match idx {
-            Some(v) => v,
-            None => self.index.root,
-        }
-    }
-    #[inline]
     fn block_pos_at(&self, idx: ClusterIndex) -> u32 {
         self.index.lba + self.index.data + ((*idx - 0x2) * self.block.blocks())
     }
+    /// Returns an error if the Volume is read only.
+    #[inline]
+    pub(super) fn writable(&self) -> DevResult<()> {
+        if self.ro { Err(DeviceError::NotWritable) } else { Ok(()) }
+    }
+    /// Same as 'writable', but the Volume is also marked as dirty if it's not
+    /// already. This is called right before data, entries or the FAT are
+    /// written, so calls that end up writing nothing leave the Volume clean.
+    #[inline]
+    pub(super) fn mark_dirty(&self) -> DevResult<()> {
+        let _ = self.writable()?;
+        if self.state.is(State::DIRTY) {
+            return Ok(());
+        }
+        self.mark(false)
+    }
+    /// Set or clear the dirty bit, which is the "clean shutdown" bit of the
+    /// second FAT entry on FAT16 and FAT32.
+    fn mark(&self, clean: bool) -> DevResult<()> {
+        // NOTE(sf): FAT12 has no dirty bit and exFAT keeps it in the boot
+        //           sector, which we don't touch.
+        let m = match &self.ver {
+            v if v.is_fat16() => 0x8000u32,
+            v if v.is_fat32() => 0x8000000u32,
+            _ => 0u32,
+        };
+        if m > 0 {
+            // NOTE(sf): This is done before the first write, so the callers
+            //           may have data in their Blocks. We use our own here.
+            let mut b = Block::new();
+            let (i, n) = self.offset(1);
+            let _ = self.dev.read_single(&mut b, n)?;
+            if self.ver.is_fat16() {
+                let v = b.read_u16(i);
+                b.write_u16(i, if clean { v | m as u16 } else { v & !(m as u16) });
+            } else {
+                let v = b.read_u32(i);
+                b.write_u32(i, if clean { v | m } else { v & !m });
+            }
+            let _ = self.write_fat(&b, n)?;
+        }
+        if clean {
+            self.state.clear(State::DIRTY);
+        } else {
+            self.state.set(State::DIRTY);
+        }
+        Ok(())
+    }
+    /// Read the dirty bit when mounting, the free Cluster count is only
+    /// trusted when the Volume was unmounted cleanly.
+    fn load_state(&self, tmp: &mut Block) -> DevResult<()> {
+        let (i, n) = self.offset(1);
+        let v = match &self.ver {
+            v if v.is_fat16() => {
+                let _ = self.dev.read_single(tmp, n)?;
+                tmp.read_u16(i) & 0x8000 != 0
+            },
+            v if v.is_fat32() => {
+                let _ = self.dev.read_single(tmp, n)?;
+                tmp.read_u32(i) & 0x8000000 != 0
+            },
+            _ => true,
+        };
+        if !v {
+            self.state.set(State::UNCLEAN);
+        } else if self.info.free().is_some() {
+            self.state.set(State::COUNTED);
+        }
+        Ok(())
+    }
     #[inline]
     fn sync(&self, tmp: &mut Block) -> DevResult<()> {
+        // NOTE(sf): The FSInfo sector is only a hint, so writing it doesn't
+        //           need to mark the Volume as dirty.
+        if self.ro {
+            return Err(DeviceError::NotWritable);
+        }
         if !self.ver.is_fat32() {
             return Ok(());
         }
@@ -359,7 +977,7 @@ impl<'a, B: BlockDevice> Volume<'a, B> {
         let _ = self.dev.read_single(tmp, i)?;
         if let Some(v) = self.info.free() {
             // 0x1E8 - Last Known Number of Free Data Clusters
-            tmp.write_u32(488, *v);
+            tmp.write_u32(488, v);
         }
         if let Some(v) = self.info.next() {
             // 0x1EC - Most Recent Allocated Cluster
@@ -376,31 +994,48 @@ impl<'a, B: BlockDevice> Volume<'a, B> {
             None => return Ok(()),
             Some(v) => v,
         };
-        self.info.free_set(n);
+        self.info.next_lower(n);
         let _ = self.update(tmp, idx, CLUSTER_EOF)?;
         let mut x = n;
         loop {
             c.clear();
             let r = self.next(tmp, &mut c, x)?;
             let _ = self.update(tmp, x, 0)?;
+            if self.ver.is_exfat() {
+                let _ = self.ex_mark(tmp, x, false)?;
+            }
+            self.info.free_add();
             x = match r {
                 Some(v) => v,
                 None => break,
             };
-            self.info.free_add();
         }
         Ok(())
     }
-    fn open_inner(&'a self, path: &[u8], mode: u8) -> DevResult<File<'a, B>> {
+    fn release(&self, tmp: &mut Block, idx: ClusterIndex) -> DevResult<()> {
+        if *idx < 2 || idx.is_empty() {
+            return Ok(());
+        }
+        // Free the rest of the chain first, then the first Cluster.
+        let _ = self.truncate(tmp, idx)?;
+        let _ = self.update(tmp, idx, 0)?;
+        if self.ver.is_exfat() {
+            let _ = self.ex_mark(tmp, idx, false)?;
+        }
+        self.info.free_add();
+        self.info.next_lower(idx);
+        Ok(())
+    }
+    fn open_inner(&'a self, path: &[u8], mode: u8) -> DevResult<File<'a, B, Safe, C>> {
         if !Mode::is_mode_valid(mode) {
             return Err(DeviceError::InvalidOptions);
         }
         if path.is_empty() {
             return Err(DeviceError::NotFound);
         }
-        let mut x = DirectoryIndex::new(self);
+        let mut x = DirectoryIndex::new(self)?;
         {
-            let mut k = Cache::block_a();
+            let mut k = self.dev.block_a()?;
             // Split file into dirs and path.
             let i = match path.iter().rposition(is_sep) {
                 Some(v) if v + 1 >= path.len() => return Err(DeviceError::NotAFile),
@@ -431,26 +1066,109 @@ impl<'a, B: BlockDevice> Volume<'a, B> {
         }
     }
     fn update(&self, tmp: &mut Block, idx: ClusterIndex, val: u32) -> DevResult<()> {
+        let _ = self.mark_dirty()?;
         let (i, n) = self.offset(*idx);
         let _ = self.dev.read_single(tmp, n)?;
-        if self.ver.is_fat32() {
-            if i + 4 < Block::SIZE {
-                tmp.write_u32(i, (tmp.read_u32(i) & 0xF0000000) | val);
+        if self.ver.is_fat12() {
+            // Odd entries use the high nibble of the first byte, even entries
+            // use the low nibble of the second byte.
+            let o = *idx & 1 == 1;
+            let v = tmp.read_u8(i);
+            tmp.write_u8(i, if o { (v & 0xF) | unsafe { (val as u8).unchecked_shl(4) } } else { val as u8 });
+            let (j, m) = if i + 1 < Block::SIZE {
+                (i + 1, n)
+            } else {
+                // The entry straddles two Blocks.
+                let _ = self.write_fat(tmp, n)?;
+                let _ = self.dev.read_single(tmp, n + 1)?;
+                (0, n + 1)
+            };
+            let v = tmp.read_u8(j);
+            tmp.write_u8(
+                j,
+                if o { unsafe { val.unchecked_shr(4) as u8 } } else { (v & 0xF0) | (unsafe { val.unchecked_shr(8) as u8 } & 0xF) },
+            );
+            return self.write_fat(tmp, m);
+        }
+        if self.ver.is_fat16() {
+            if i + 2 <= Block::SIZE {
+                tmp.write_u16(i, val as u16);
+            }
+        } else if self.ver.is_fat32() {
+            if i + 4 <= Block::SIZE {
+                tmp.write_u32(i, (tmp.read_u32(i) & 0xF0000000) | (val & 0xFFFFFFF));
             }
         } else {
-            if i + 2 < Block::SIZE {
-                tmp.write_u16(i, val as u16);
+            if i + 4 <= Block::SIZE {
+                tmp.write_u32(i, val);
+            }
+        }
+        self.write_fat(tmp, n)
+    }
+    #[inline]
+    fn write_fat(&self, tmp: &Block, n: u32) -> DevResult<()> {
+        let _ = self.dev.write_single(tmp, n)?;
+        if !self.index.is_mirrored() {
+            return Ok(());
+        }
+        // Mirror the change to every other FAT copy, the first FAT is the
+        // one we read from, so it's already written.
+        for k in 1..self.index.fats as u32 {
+            let _ = self.dev.write_single(tmp, n + (k * self.index.size))?;
+        }
+        Ok(())
+    }
+    /// Returns the first Cluster of a run of at least 'count' free Clusters,
+    /// starting from the next free hint, or None if there isn't one.
+    fn free_run(&self, tmp: &mut Block, count: u32) -> DevResult<Cluster> {
+        let (e, mut c) = (self.info.count + 0x2, BlockCache::new());
+        let s = match self.info.next() {
+            Some(v) if *v < e => *v,
+            _ => 2,
+        };
+        let (mut p, mut n) = (0u32, 0u32);
+        for i in (s..e).chain(2..s) {
+            // Runs can't wrap around the end.
+            if i == 2 {
+                n = 0;
+            }
+            let f = if self.ver.is_exfat() { self.ex_is_free(tmp, &mut c, i)? } else { self.value(tmp, &mut c, i)? == 0 };
+            if !f {
+                n = 0;
+                continue;
+            }
+            if n == 0 {
+                p = i;
+            }
+            n += 1;
+            if n >= count {
+                return Ok(ClusterIndex::new(p));
             }
         }
-        self.dev.write_single(tmp, n)
+        Ok(None)
     }
     fn free(&self, tmp: &mut Block, start: u32, end: u32) -> DevResult<ClusterIndex> {
-        let (mut p, v) = (start, self.ver.is_fat32());
-        let (a, mut c) = (if v { 0x4 } else { 0x2 }, BlockCache::new());
+        if self.ver.is_exfat() {
+            return self.ex_free(tmp, start, end);
+        }
+        let (mut p, v, mut c) = (start, self.ver.is_fat32(), BlockCache::new());
+        if self.ver.is_fat12() {
+            // NOTE(sf): FAT12 entries aren't aligned, but the FAT is never
+            //           larger than 12 Blocks, so just check each entry.
+            while p < end {
+                if self.value(tmp, &mut c, p)? == 0 {
+                    return Ok(unsafe { ClusterIndex::new_unchecked(p) });
+                }
+                p += 1;
+            }
+            return Err(DeviceError::NoSpace);
+        }
+        let a = if v { 0x4 } else { 0x2 };
         while p < end {
             let (mut i, n) = self.offset(p);
             let _ = c.read_single(&self.dev, tmp, n)?;
-            while i <= Block::SIZE - a {
+            // The last FAT Block can have entries past the end of the Volume.
+            while i <= Block::SIZE - a && p < end {
                 if (v && tmp.read_u32(i) & 0xFFFFFFF == 0) || (!v && tmp.read_u16(i) == 0) {
                     return Ok(unsafe { ClusterIndex::new_unchecked(p) });
                 }
@@ -459,36 +1177,62 @@ impl<'a, B: BlockDevice> Volume<'a, B> {
         }
         Err(DeviceError::NoSpace)
     }
+    #[inline]
     fn allocate(&self, tmp: &mut Block, prev: Cluster, zero: bool) -> DevResult<ClusterIndex> {
-        let e = self.block.count + 0x2;
         let s = match prev.as_deref() {
-            Some(&v) if v < e => v,
+            Some(&v) if v < self.info.count + 0x2 => v,
             _ => 2,
         };
-        let n = self.free_try(tmp, s, e)?.ok_or(DeviceError::NoSpace)?;
+        self.allocate_at(tmp, prev, s, zero)
+    }
+    /// Allocate the first free Cluster at or after 'start' and link it after
+    /// 'prev', if not None.
+    fn allocate_at(&self, tmp: &mut Block, prev: Cluster, start: u32, zero: bool) -> DevResult<ClusterIndex> {
+        let _ = self.mark_dirty()?;
+        let e = self.info.count + 0x2;
+        let n = self.free_try(tmp, start, e)?.ok_or(DeviceError::NoSpace)?;
+        if self.ver.is_exfat() {
+            let _ = self.ex_mark(tmp, n, true)?;
+        }
         let _ = self.update(tmp, n, CLUSTER_EOF)?;
         if let Some(v) = prev {
             let _ = self.update(tmp, v, *n)?;
         }
-        let f = self.free_try(tmp, *n, e)?;
-        self.info.next_set(f);
+        // NOTE(sf): exFAT has no FSInfo sector to keep the next free hint in,
+        //           so we skip the extra Bitmap scan.
+        if !self.ver.is_exfat() {
+            // Taking the last free Cluster leaves no next free hint.
+            let f = match self.free_try(tmp, *n, e) {
+                Err(DeviceError::NoSpace) => None,
+                r => r?,
+            };
+            self.info.next_set(f);
+        }
         self.info.free_remove();
         if zero {
             tmp.clear();
             let p = self.block_pos_at(n);
-            for i in p..=p + self.block.blocks() {
+            for i in p..p + self.block.blocks() {
                 self.dev.write_single(tmp, i)?;
             }
         }
         Ok(n)
     }
     #[inline]
-    fn create_dir(&self, tmp: &mut Block, name: &[u8], parent: Cluster) -> DevResult<DirEntry> {
-        let e = self.create(tmp, name, 0x10, parent, true)?;
-        let i = self.block_pos(e.cluster());
+    fn create_dir(&self, tmp: &mut Block, lfn: &mut LongName, name: &[u8], parent: Option<&DirEntry>) -> DevResult<DirEntry> {
+        let e = self.create(tmp, lfn, name, 0x10, parent, true)?;
+        let i = self.block_pos_at(e.index());
+        if self.ver.is_exfat() {
+            // exFAT Directories don't have Self and Parent entries.
+            tmp.clear();
+            for k in i..i + self.block.blocks() {
+                let _ = self.dev.write_single(&tmp, k)?;
+            }
+            return Ok(e);
+        }
         unsafe {
             let s = DirEntry::new_self(&e, i);
-            let p = DirEntry::new_parent(parent, i);
+            let p = DirEntry::new_parent(parent.and_then(|v| v.cluster()), i);
             // Write Parent and Self entries
             s.write_entry(self.ver.is_fat32(), tmp);
             p.write_entry(self.ver.is_fat32(), tmp.get_unchecked_mut(DirEntry::SIZE..));
@@ -496,7 +1240,7 @@ impl<'a, B: BlockDevice> Volume<'a, B> {
         let _ = self.dev.write_single(&tmp, i)?;
         tmp.clear();
         // Write empty blocks to create initial Directory space.
-        for k in (i + 1)..=i + self.block.blocks() {
+        for k in (i + 1)..i + self.block.blocks() {
             let _ = self.dev.write_single(&tmp, k)?;
         }
         Ok(e)
@@ -505,33 +1249,68 @@ impl<'a, B: BlockDevice> Volume<'a, B> {
     fn next(&self, tmp: &mut Block, cache: &mut BlockCache, idx: ClusterIndex) -> DevResult<Cluster> {
         // NOTE(sf): Instead of returning an error for the EOF or absence of
         //           a next cluster, we return None for easier signaling.
-        let (s, i) = self.offset(*idx);
+        match self.value(tmp, cache, *idx)? {
+            0 => Err(DeviceError::InvalidChain),
+            v if self.is_bad(v) => Err(DeviceError::InvalidCluster),
+            v if v == 1 || self.is_eoc(v) => Ok(None),
+            v => Ok(Some(unsafe { ClusterIndex::new_unchecked(v) })),
+        }
+    }
+    #[inline]
+    fn value(&self, tmp: &mut Block, cache: &mut BlockCache, idx: u32) -> DevResult<u32> {
+        let (s, i) = self.offset(idx);
         cache.read_single(&self.dev, tmp, i)?;
-        match self.ver.is_fat32() {
-            // FAT16
-            false if s + 2 > Block::SIZE => Err(DeviceError::InvalidCluster),
-            false => match tmp.read_u16(s) {
-                0 => Err(DeviceError::InvalidChain),
-                0xFFF7 => Err(DeviceError::InvalidCluster),
-                0xFFF8..=0xFFFF => Ok(None),
-                v => Ok(Some(unsafe { ClusterIndex::new_unchecked(v as u32) })),
-            },
-            // FAT32
-            true if s + 4 > Block::SIZE => Err(DeviceError::InvalidCluster),
-            true => match tmp.read_u32(s) & 0xFFFFFFF {
-                0 => Err(DeviceError::InvalidChain),
-                0xFFFFFF7 => Err(DeviceError::InvalidCluster),
-                0x1 | 0xFFFFFF8..=0xFFFFFFF => Ok(None),
-                v => Ok(Some(unsafe { ClusterIndex::new_unchecked(v) })),
+        match &self.ver {
+            // FAT12
+            v if v.is_fat12() => {
+                let l = tmp.read_u8(s) as u16;
+                let h = if s + 1 < Block::SIZE {
+                    tmp.read_u8(s + 1)
+                } else {
+                    // The entry straddles two Blocks.
+                    cache.read_single(&self.dev, tmp, i + 1)?;
+                    tmp.read_u8(0)
+                } as u16;
+                let v = l | unsafe { h.unchecked_shl(8) };
+                Ok(if idx & 1 == 1 { unsafe { v.unchecked_shr(4) as u32 } } else { v as u32 & 0xFFF })
             },
+            // FAT16
+            v if v.is_fat16() && s + 2 > Block::SIZE => Err(DeviceError::InvalidCluster),
+            v if v.is_fat16() => Ok(tmp.read_u16(s) as u32),
+            // FAT32 and exFAT
+            _ if s + 4 > Block::SIZE => Err(DeviceError::InvalidCluster),
+            v if v.is_fat32() => Ok(tmp.read_u32(s) & 0xFFFFFFF),
+            _ => Ok(tmp.read_u32(s)),
+        }
+    }
+    #[inline]
+    fn is_bad(&self, v: u32) -> bool {
+        v == match &self.ver {
+            x if x.is_fat12() => 0xFF7,
+            x if x.is_fat16() => 0xFFF7,
+            x if x.is_fat32() => 0xFFFFFF7,
+            _ => 0xFFFFFFF7,
+        }
+    }
+    #[inline]
+    fn is_eoc(&self, v: u32) -> bool {
+        v >= match &self.ver {
+            x if x.is_fat12() => 0xFF8,
+            x if x.is_fat16() => 0xFFF8,
+            x if x.is_fat32() => 0xFFFFFF8,
+            _ => 0xFFFFFFF8,
         }
     }
-    fn find(&self, tmp: &mut Block, count: u8, parent: Cluster, pred: fn(u32, &[u8]) -> bool) -> DevResult<Range> {
-        let mut t = self.entries(parent);
-        let (mut c, mut r, mut k) = (BlockCache::new(), Range::new(), self.root(parent));
+    fn find(&self, tmp: &mut Block, count: u8, parent: Option<&DirEntry>, pred: fn(u32, &[u8]) -> bool) -> DevResult<Range> {
+        let d = parent.and_then(|v| v.cluster());
+        let mut n = match parent {
+            Some(v) => self.ex_run(tmp, v)?,
+            None => None,
+        };
+        let (mut k, mut p, mut t) = self.dir_pos(d);
+        let (mut c, mut r) = (BlockCache::new(), Range::new());
         'outer: loop {
-            let p = self.block_pos_at(k);
-            for i in p..=p + t {
+            for i in p..p + t {
                 let _ = self.dev.read_single(tmp, i)?;
                 for e in 0..DirEntry::SIZE_PER_BLOCK {
                     let x = e as usize * DirEntry::SIZE;
@@ -545,23 +1324,43 @@ impl<'a, B: BlockDevice> Volume<'a, B> {
                     }
                 }
             }
+            if k.is_empty() {
+                // The fixed root Directory is full.
+                return Err(DeviceError::NoSpace);
+            }
             c.clear();
-            k = match self.next(tmp, &mut c, k)? {
-                None => self.allocate(tmp, Some(k), true)?,
-                Some(v) => v,
+            k = match n {
+                Some(0) => self.ex_grow(tmp, parent, k)?,
+                Some(v) => {
+                    n = Some(v - 1);
+                    unsafe { ClusterIndex::new_unchecked(*k + 1) }
+                },
+                None => match self.next(tmp, &mut c, k)? {
+                    None if self.ver.is_exfat() => self.ex_grow(tmp, parent, k)?,
+                    None => self.allocate(tmp, Some(k), true)?,
+                    Some(v) => v,
+                },
             };
-            t = self.block.blocks();
+            (p, t) = (self.block_pos_at(k), self.block.blocks());
         }
         Ok(r)
     }
-    fn create(&self, tmp: &mut Block, name: &[u8], attrs: u8, parent: Cluster, alloc: bool) -> DevResult<DirEntry> {
-        let mut n = Cache::lfn();
-        let _ = n.fill(name)?;
-        let s = n.lfn_size();
+    /// Create a new entry in the parent Directory, using 'lfn' as the buffer
+    /// for the long name.
+    fn create(&self, tmp: &mut Block, lfn: &mut LongName, name: &[u8], attrs: u8, parent: Option<&DirEntry>, alloc: bool) -> DevResult<DirEntry> {
+        let _ = self.mark_dirty()?;
+        if self.ver.is_exfat() {
+            return self.ex_create(tmp, name, attrs, parent, alloc);
+        }
+        let _ = lfn.fill(name)?;
+        // Names that are already valid 8.3 names don't need any LFN entries.
+        let s = if is_short(name, false) { 1 } else { lfn.lfn_size() + 1 };
+        let v = if s > 1 { self.alias(tmp, parent, name, lfn)? } else { ShortName::from_slice(name) };
         // Look for empty or free'd spaces
         let r = self.find(tmp, s, parent, |_, b| matches!(b.read_u8(0), 0 | 0xE5))?;
         let (mut e, mut t) = (DirEntry::new(attrs, s - 1), 0u8);
-        e.fill_name(name);
+        e.set_name(v);
+        e.stamp(self);
         let mut w = BlockEntryIter::new(r.blocks());
         for (_, i, l, o) in r {
             if l && !w.in_scope(i) {
@@ -569,6 +1368,9 @@ impl<'a, B: BlockDevice> Volume<'a, B> {
             }
             let (b, v) = (w.buffer(i), o * DirEntry::SIZE);
             let d = unsafe { b.get_unchecked_mut(v..) };
+            if t == 0 {
+                e.prepare_start(i, v);
+            }
             if t + 1 == s {
                 e.prepare(i, v);
                 if alloc {
@@ -577,27 +1379,144 @@ impl<'a, B: BlockDevice> Volume<'a, B> {
                 e.write_entry(self.ver.is_fat32(), d);
                 break;
             }
-            e.write_lfn_entry(&n, t, s - 1, d);
+            e.write_lfn_entry(lfn, t, s - 1, d);
             t += 1;
         }
         let _ = w.flush(self.dev)?;
         Ok(e)
     }
+    /// Pick the ShortName for a new entry with a LFN, adding the first numeric
+    /// tail that is not used by another entry in the parent Directory.
+    fn alias(&self, tmp: &mut Block, parent: Option<&DirEntry>, name: &[u8], lfn: &LongName) -> DevResult<ShortName> {
+        let mut b = ShortName::empty();
+        // Names that only differ by case keep the basis name if it's free.
+        if !b.basis(name) && !self.is_alias(tmp, parent, &b)? {
+            return Ok(b);
+        }
+        let h = sfn_hash(lfn.as_str());
+        for i in 1..=ALIAS_MAX {
+            let v = if i <= 4 { b.with_tail(i, None) } else { b.with_tail(i - 4, Some(h)) };
+            if !self.is_alias(tmp, parent, &v)? {
+                return Ok(v);
+            }
+        }
+        Err(DeviceError::Overflow)
+    }
     #[inline]
-    fn open_inner_file(&'a self, x: &mut DirectoryIndex<'a, B>, tmp: &mut Block, name: &[u8], mode: u8) -> DevResult<File<'a, B>> {
-        let _ = unsafe { x.reset_cluster(None)? };
+    fn is_alias(&self, tmp: &mut Block, parent: Option<&DirEntry>, v: &ShortName) -> DevResult<bool> {
+        Ok(self.scan(tmp, parent, |b| b.read_u8(11) & 0x8 == 0 && b.read_slice(0, ShortName::SIZE) == v.as_raw())?.is_some())
+    }
+    /// Find the first used entry in the Directory that matches 'pred' and
+    /// return its Block and offset. LFN entries are skipped.
+    ///
+    /// When found, 'tmp' contains the Block with the entry.
+    fn scan(&self, tmp: &mut Block, parent: Option<&DirEntry>, mut pred: impl FnMut(&[u8]) -> bool) -> DevResult<Option<(u32, usize)>> {
+        let (mut k, mut p, mut t) = self.dir_pos(parent.and_then(|v| v.cluster()));
+        let mut c = BlockCache::new();
+        loop {
+            for i in p..p + t {
+                let _ = self.dev.read_single(tmp, i)?;
+                for e in 0..DirEntry::SIZE_PER_BLOCK as usize {
+                    let x = e * DirEntry::SIZE;
+                    match tmp.read_u8(x) {
+                        0 => return Ok(None),
+                        0xE5 => continue,
+                        _ if !self.ver.is_exfat() && tmp.read_u8(x + 11) & 0x3F == 0xF => continue,
+                        _ if pred(tmp.read_slice(x, DirEntry::SIZE)) => return Ok(Some((i, x))),
+                        _ => (),
+                    }
+                }
+            }
+            if k.is_empty() {
+                return Ok(None);
+            }
+            c.clear();
+            k = match self.next(tmp, &mut c, k)? {
+                Some(v) => v,
+                None => return Ok(None),
+            };
+            (p, t) = (self.block_pos_at(k), self.block.blocks());
+        }
+    }
+    fn label(&self, tmp: &mut Block, v: &VolumeName) -> DevResult<()> {
+        // 0x26 - Extended Boot Signature
+        // 0x2B - Volume Label
+        //
+        // NOTE(sf): The FAT32 fields are moved by 0x1C and the label is only
+        //           there when the extended signature is.
+        let o = if self.ver.is_fat32() { 0x1C } else { 0 };
+        let _ = self.dev.read_single(tmp, self.index.lba)?;
+        if tmp.read_u8(0x26 + o) == 0x29 {
+            tmp.write_from(0x2B + o, if v.is_empty() { VolumeName::NO_NAME.as_raw() } else { v.as_raw() });
+            let _ = self.dev.write_single(tmp, self.index.lba)?;
+            // 0x32 - Backup Boot Sector
+            let b = if self.ver.is_fat32() { tmp.read_u16(50) as u32 } else { 0 };
+            if b > 0 && b < 0xFFFF {
+                let _ = self.dev.write_single(tmp, self.index.lba + b)?;
+            }
+        }
+        match self.scan(tmp, None, |b| b.read_u8(11) & 0x18 == 0x8)? {
+            Some((i, x)) if v.is_empty() => {
+                tmp.write_u8(x, 0xE5);
+                self.dev.write_single(tmp, i)
+            },
+            Some((i, x)) => {
+                tmp.write_from(x, v.as_raw());
+                self.dev.write_single(tmp, i)
+            },
+            None if v.is_empty() => Ok(()),
+            None => {
+                let r = self.find(tmp, 1, None, |_, b| matches!(b.read_u8(0), 0 | 0xE5))?;
+                let (_, i, _, o) = r.into_iter().next().ok_or(DeviceError::NoSpace)?;
+                let mut e = DirEntry::new(0x8, 0);
+                e.set_name(unsafe { ShortName::from_raw(v.as_raw()) });
+                e.stamp(self);
+                let _ = self.dev.read_single(tmp, i)?;
+                e.write_entry(self.ver.is_fat32(), unsafe { tmp.get_unchecked_mut(o * DirEntry::SIZE..) });
+                self.dev.write_single(tmp, i)
+            },
+        }
+    }
+    #[inline]
+    fn open_inner_file(&'a self, x: &mut DirectoryIndex<'a, B, C>, tmp: &mut Block, name: &[u8], mode: u8) -> DevResult<File<'a, B, Safe, C>> {
+        let _ = unsafe { x.reset_entry(None)? };
         match x.find(|e| e.eq(name))? {
             Some(e) if e.is_directory() => return Err(DeviceError::NotAFile),
-            Some(e) => Ok(File::new(e, mode, self)),
-            None if Mode::is_create(mode) => Ok(File::new(
-                self.create(tmp, name, 0, None, false)?,
-                mode,
-                self,
-            )),
+            Some(e) => File::new(e, mode, self),
+            None if Mode::is_create(mode) => File::new(self.create(tmp, x.lfn(), name, 0, None, false)?, mode, self),
             None => Err(DeviceError::NotFound),
         }
     }
-    fn find_dir(&'a self, x: &mut DirectoryIndex<'a, B>, tmp: &mut Block, path: &[u8], makedirs: bool) -> DevResult<Directory<'a, B>> {
+    fn modify(&'a self, path: &[u8], func: impl FnOnce(&mut DirEntry)) -> DevResult<()> {
+        let mut x = DirectoryIndex::new(self)?;
+        {
+            let mut b = self.dev.block_a()?;
+            let mut e = self.stat_inner(&mut x, &mut b, path)?;
+            // The root Directory has no entry to change.
+            if e.filename().is_empty() {
+                return Err(DeviceError::InvalidOptions);
+            }
+            func(&mut e);
+            e.sync(self, &mut b)
+        }
+    }
+    fn stat_inner(&'a self, x: &mut DirectoryIndex<'a, B, C>, tmp: &mut Block, path: &[u8]) -> DevResult<DirEntry> {
+        // Ignore any trailing separators, so "dir/" is the same as "dir".
+        let p = unsafe { path.get_unchecked(0..path.iter().rposition(|v| !is_sep(v)).map_or(0, |v| v + 1)) };
+        let (d, n) = match p.iter().rposition(is_sep) {
+            Some(i) => (
+                Some(self.find_dir(x, tmp, unsafe { p.get_unchecked(0..i) }, false)?),
+                unsafe { p.get_unchecked(i + 1..) },
+            ),
+            None => (None, p),
+        };
+        if n.is_empty() || n == b"." || n == b".." {
+            return Ok(self.find_dir(x, tmp, p, false)?.into_entry());
+        }
+        let _ = unsafe { x.reset_entry(d.as_deref())? };
+        x.find(|v| v.eq(n))?.ok_or(DeviceError::NotFound)
+    }
+    fn find_dir(&'a self, x: &mut DirectoryIndex<'a, B, C>, tmp: &mut Block, path: &[u8], makedirs: bool) -> DevResult<Directory<'a, B, C>> {
         if path.is_empty() || (path.len() == 1 && unsafe { is_sep(path.get_unchecked(0)) }) {
             return Ok(Directory::new(DirEntry::new_root(), self));
         }
@@ -610,15 +1529,14 @@ impl<'a, B: BlockDevice> Volume<'a, B> {
                 (p, o) = (o, None);
                 continue;
             }
-            let i = p.as_ref().and_then(|v| v.cluster());
-            let _ = unsafe { x.reset_cluster(i)? };
+            let _ = unsafe { x.reset_entry(p.as_ref())? };
             o = p; // Set the current Parent as the old one before setting the new one.
             p = match x.find(|v| v.eq(e))? {
                 Some(v) if v.is_file() => return Err(DeviceError::NotADirectory),
                 Some(v) if c => return Ok(Directory::new(v, self)),
                 Some(v) => Some(v),
-                None if makedirs && c => return Ok(Directory::new(self.create_dir(tmp, e, i)?, self)),
-                None if makedirs => Some(self.create_dir(tmp, e, i)?),
+                None if makedirs && c => return Ok(Directory::new(self.create_dir(tmp, x.lfn(), e, o.as_ref())?, self)),
+                None if makedirs => Some(self.create_dir(tmp, x.lfn(), e, o.as_ref())?),
                 None => return Err(DeviceError::NotFound),
             };
         }
@@ -627,26 +1545,25 @@ impl<'a, B: BlockDevice> Volume<'a, B> {
             None => Err(DeviceError::NotFound),
         }
     }
-    fn create_file(&'a self, x: &mut DirectoryIndex<'a, B>, tmp: &mut Block, name: &[u8], parent: Option<&DirEntry>, mode: u8) -> DevResult<File<'a, B>> {
+    fn create_file(&'a self, x: &mut DirectoryIndex<'a, B, C>, tmp: &mut Block, name: &[u8], parent: Option<&DirEntry>, mode: u8) -> DevResult<File<'a, B, Safe, C>> {
         if !Mode::is_mode_valid(mode) {
             return Err(DeviceError::InvalidOptions);
         }
-        let p = parent.and_then(|v| v.cluster());
-        let _ = unsafe { x.reset_cluster(p)? };
+        let _ = unsafe { x.reset_entry(parent)? };
         match x.find(|e| e.eq(name))? {
             Some(e) if e.is_directory() => Err(DeviceError::NotAFile),
             Some(e) => {
-                let mut f = File::new(e, mode, self);
+                let mut f = File::new(e, mode, self)?;
                 if mode & Mode::APPEND != 0 {
                     f.seek_to_end();
                 } else if mode & Mode::TRUNCATE != 0 {
-                    let _ = self.truncate(tmp, f.index())?;
+                    let _ = f.shrink(self, tmp)?;
                     f.zero();
-                    let _ = f.sync(&self.dev, tmp, self.ver.is_fat32())?;
+                    let _ = f.sync(self, tmp)?;
                 }
                 Ok(f)
             },
-            None if Mode::is_create(mode) => Ok(File::new(self.create(tmp, name, 0, p, false)?, mode, self)),
+            None if Mode::is_create(mode) => File::new(self.create(tmp, x.lfn(), name, 0, parent, false)?, mode, self),
             None => Err(DeviceError::NotFound),
         }
     }
@@ -698,10 +1615,14 @@ fn is_sep(v: &u8) -> bool {
 #[inline]
 fn _size(b: &[u8]) -> u32 {
     // 0x10 - Number of FAT
+    b.read_u8(16) as u32 * _fat_size(b)
+}
+#[inline]
+fn _fat_size(b: &[u8]) -> u32 {
     // 0x16 - Logical Sectors per FAT
     // 0x24 - Logical Sectors per FAT (FAT32)
-    let (v, n) = (b.read_u16(22) as u32, b.read_u8(16) as u32);
-    if v > 0 { v * n } else { n * b.read_u32(36) }
+    let v = b.read_u16(22) as u32;
+    if v > 0 { v } else { b.read_u32(36) }
 }
 #[inline]
 fn _blocks(b: &[u8]) -> u32 {
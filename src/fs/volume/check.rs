@@ -0,0 +1,501 @@
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+
+#![no_implicit_prelude]
+
+extern crate core;
+
+use core::cmp::Ord;
+use core::option::Option::{None, Some};
+use core::ptr::write_bytes;
+use core::result::Result::{Err, Ok};
+
+use crate::fs::volume::State;
+use crate::fs::{Block, BlockCache, BlockDevice, CacheProvider, Cluster, ClusterIndex, DevResult, DeviceError, DirEntry, ShortName, Volume};
+use crate::{Slice, SliceMut};
+
+const DEPTH: usize = 0x10usize;
+
+pub struct CheckReport {
+    dirs:    u32,
+    files:   u32,
+    lost:    u32,
+    free:    u32,
+    hint:    u32,
+    fixed:   u32,
+    cross:   u32,
+    sizes:   u32,
+    chains:  u32,
+    orphans: u32,
+    skipped: u32,
+}
+
+struct Level {
+    block:   u32,
+    entry:   u32,
+    blocks:  u32,
+    cluster: ClusterIndex,
+}
+struct Orphans {
+    pos:   [(u32, usize); 0x14],
+    sum:   u8,
+    ord:   u8,
+    count: u8,
+}
+struct Checker<'b, 'a, B: BlockDevice, C: CacheProvider> {
+    r:      CheckReport,
+    vol:    &'b Volume<'a, B, C>,
+    map:    &'b mut [u8],
+    dir:    &'b mut Block,
+    tmp:    &'b mut Block,
+    end:    u32,
+    last:   u32,
+    start:  u32,
+    cache:  BlockCache,
+    repair: bool,
+}
+
+impl Level {
+    #[inline]
+    const fn new() -> Level {
+        Level {
+            block:   0u32,
+            entry:   0u32,
+            blocks:  0u32,
+            cluster: ClusterIndex::EMPTY,
+        }
+    }
+
+    #[inline]
+    fn set<B: BlockDevice, C: CacheProvider>(&mut self, vol: &Volume<'_, B, C>, dir: Cluster) {
+        self.entry = 0;
+        let (c, i, n) = vol.dir_pos(dir);
+        (self.cluster, self.block, self.blocks) = (c, i, i + n);
+    }
+}
+impl Orphans {
+    #[inline]
+    const fn new() -> Orphans {
+        Orphans {
+            pos:   [(0u32, 0usize); 0x14],
+            sum:   0u8,
+            ord:   0u8,
+            count: 0u8,
+        }
+    }
+
+    #[inline]
+    fn is_match(&self, sum: u8) -> bool {
+        // The last LFN entry before the ShortName entry must be the first
+        // (ordinal 1) in the sequence.
+        self.ord == 1 && self.sum == sum
+    }
+}
+impl CheckReport {
+    #[inline]
+    const fn new() -> CheckReport {
+        CheckReport {
+            dirs:    0u32,
+            files:   0u32,
+            lost:    0u32,
+            free:    0u32,
+            hint:    0u32,
+            fixed:   0u32,
+            cross:   0u32,
+            sizes:   0u32,
+            chains:  0u32,
+            orphans: 0u32,
+            skipped: 0u32,
+        }
+    }
+
+    /// Count of Clusters that are marked as used in the FAT, but are not
+    /// referenced by any File or Directory.
+    #[inline]
+    pub fn lost(&self) -> u32 {
+        self.lost
+    }
+    /// Count of Clusters that are referenced by more than one chain.
+    #[inline]
+    pub fn cross(&self) -> u32 {
+        self.cross
+    }
+    /// Count of Files with a size that does not match their chain length.
+    #[inline]
+    pub fn sizes(&self) -> u32 {
+        self.sizes
+    }
+    /// Count of chains that point to free, bad or out of range Clusters.
+    #[inline]
+    pub fn chains(&self) -> u32 {
+        self.chains
+    }
+    /// Count of LFN entries that do not belong to a valid ShortName entry.
+    #[inline]
+    pub fn orphans(&self) -> u32 {
+        self.orphans
+    }
+    /// Count of Directories that were not checked as they were nested too
+    /// deep. When non-zero, lost Clusters will not be repaired.
+    #[inline]
+    pub fn skipped(&self) -> u32 {
+        self.skipped
+    }
+    #[inline]
+    pub fn dirs(&self) -> u32 {
+        self.dirs
+    }
+    #[inline]
+    pub fn files(&self) -> u32 {
+        self.files
+    }
+    #[inline]
+    pub fn fixed(&self) -> u32 {
+        self.fixed
+    }
+    /// Count of free Clusters found in the FAT.
+    #[inline]
+    pub fn free(&self) -> u32 {
+        self.free
+    }
+    /// Count of free Clusters stored in the FSInfo sector before the check.
+    /// This is the same as 'free' on FAT16.
+    #[inline]
+    pub fn free_hint(&self) -> u32 {
+        self.hint
+    }
+    #[inline]
+    pub fn is_clean(&self) -> bool {
+        self.lost == 0 && self.cross == 0 && self.sizes == 0 && self.chains == 0 && self.orphans == 0 && self.hint == self.free
+    }
+}
+impl<'a, B: BlockDevice, C: CacheProvider> Volume<'a, B, C> {
+    /// Walk every Directory and cluster chain in the Volume and report any
+    /// inconsistencies found.
+    ///
+    /// The 'bitmap' buffer is used to mark referenced Clusters, each byte
+    /// covers 8 Clusters. If it's too small to cover the whole Volume, the
+    /// check is done in multiple passes, which is slower.
+    ///
+    /// When 'repair' is true, lost Clusters are freed, orphaned LFN entries
+    /// are removed, File sizes and chains are fixed to match each other and
+    /// the FSInfo free count is recalculated. Cross-linked chains are only
+    /// reported as there is no safe way to tell which File owns the Cluster.
+    pub fn check(&self, bitmap: &mut [u8], repair: bool) -> DevResult<CheckReport> {
+        if bitmap.is_empty() {
+            return Err(DeviceError::InvalidOptions);
+        }
+        if self.ver.is_exfat() {
+            return Err(DeviceError::UnsupportedFileSystem);
+        }
+        if repair {
+            let _ = self.writable()?;
+        }
+        let (mut a, mut b) = (self.dev.block_a()?, self.dev.block_b()?);
+        let (e, w) = (self.info.count + 2, (bitmap.len() as u32).saturating_mul(8));
+        let mut k = Checker {
+            repair,
+            r: CheckReport::new(),
+            vol: self,
+            map: bitmap,
+            dir: &mut a,
+            tmp: &mut b,
+            end: 0u32,
+            last: u32::MAX,
+            start: 2u32,
+            cache: BlockCache::new(),
+        };
+        while k.start < e {
+            k.end = k.start.saturating_add(w).min(e);
+            unsafe { write_bytes(k.map.as_mut_ptr(), 0, k.map.len()) };
+            let _ = k.tree()?;
+            let _ = k.lost()?;
+            k.start = k.end;
+        }
+        if !self.ver.is_fat32() {
+            k.r.hint = k.r.free;
+            return Ok(k.r);
+        }
+        k.r.hint = self.info.free().unwrap_or(0);
+        if repair && k.r.hint != k.r.free {
+            self.info.free_reset(k.r.free);
+            self.state.set(State::COUNTED);
+            let _ = self.sync(k.tmp)?;
+            k.r.fixed += 1;
+        }
+        Ok(k.r)
+    }
+}
+impl<B: BlockDevice, C: CacheProvider> Checker<'_, '_, B, C> {
+    #[inline]
+    fn is_first(&self) -> bool {
+        self.start == 2
+    }
+    #[inline]
+    fn mark(&mut self, v: u32) -> bool {
+        if v < self.start || v >= self.end {
+            return false;
+        }
+        let (i, m) = (((v - self.start) / 8) as usize, unsafe { 1u8.unchecked_shl((v - self.start) % 8) });
+        let r = self.map.read_u8(i) & m != 0;
+        self.map.write_u8(i, self.map.read_u8(i) | m);
+        r
+    }
+    #[inline]
+    fn is_marked(&self, v: u32) -> bool {
+        let i = v - self.start;
+        self.map.read_u8((i / 8) as usize) & unsafe { 1u8.unchecked_shl(i % 8) } != 0
+    }
+    fn lost(&mut self) -> DevResult<()> {
+        let f = self.repair && self.r.skipped == 0;
+        for x in self.start..self.end {
+            let v = self.vol.value(self.tmp, &mut self.cache, x)?;
+            if v == 0 {
+                self.r.free += 1;
+                continue;
+            }
+            if self.is_marked(x) || self.vol.is_bad(v) {
+                continue;
+            }
+            self.r.lost += 1;
+            if !f {
+                continue;
+            }
+            self.cache.clear();
+            let _ = self.vol.update(self.tmp, unsafe { ClusterIndex::new_unchecked(x) }, 0)?;
+            (self.r.free, self.r.fixed) = (self.r.free + 1, self.r.fixed + 1);
+        }
+        Ok(())
+    }
+    fn tree(&mut self) -> DevResult<()> {
+        let mut k = [const { Level::new() }; DEPTH];
+        let (mut o, mut n) = (Orphans::new(), 1usize);
+        // The FAT32 root Directory has a chain, which isn't referenced by any
+        // entry.
+        if !self.vol.index.root.is_empty() {
+            let _ = self.chain(*self.vol.index.root)?;
+        }
+        unsafe { k.get_unchecked_mut(0) }.set(self.vol, None);
+        while n > 0 {
+            let v = unsafe { k.get_unchecked_mut(n - 1) };
+            if v.entry >= DirEntry::SIZE_PER_BLOCK {
+                (v.block, v.entry) = (v.block + 1, 0);
+                if v.block < v.blocks {
+                    continue;
+                }
+                self.cache.clear();
+                // The fixed FAT12/FAT16 root Directory has no chain.
+                let r = if v.cluster.is_empty() { Ok(None) } else { self.vol.next(self.tmp, &mut self.cache, v.cluster) };
+                match r {
+                    Ok(Some(c)) => {
+                        let i = self.vol.block_pos_at(c);
+                        (v.cluster, v.block, v.blocks) = (c, i, i + self.vol.block.blocks());
+                        continue;
+                    },
+                    Ok(None) => (),
+                    Err(_) if self.is_first() => self.r.chains += 1,
+                    Err(_) => (),
+                }
+                let _ = self.orphans(&mut o)?;
+                n -= 1;
+                continue;
+            }
+            if self.last != v.block {
+                let _ = self.vol.dev.read_single(self.dir, v.block)?;
+                self.last = v.block;
+            }
+            let (i, x) = (v.block, v.entry as usize * DirEntry::SIZE);
+            v.entry += 1;
+            let (h, a) = (self.dir.read_u8(x), self.dir.read_u8(x + 11));
+            if h == 0 {
+                // Rest of the Directory is empty.
+                let _ = self.orphans(&mut o)?;
+                n -= 1;
+                continue;
+            }
+            if h != 0xE5 && a & 0x3F == 0xF {
+                let _ = self.lfn(&mut o, i, x, h)?;
+                continue;
+            }
+            if h == 0xE5 || a & 0x8 != 0 {
+                let _ = self.orphans(&mut o)?;
+                continue;
+            }
+            let s = unsafe { ShortName::from_raw(self.dir.read_slice(x, ShortName::SIZE)) };
+            if s.is_self() || s.is_parent() {
+                let _ = self.orphans(&mut o)?;
+                continue;
+            }
+            if o.count > 0 && !o.is_match(s.checksum()) {
+                let _ = self.orphans(&mut o)?;
+            }
+            o.count = 0;
+            let c = if self.vol.ver.is_fat32() {
+                unsafe { (self.dir.read_u16(x + 20) as u32).unchecked_shl(16) | self.dir.read_u16(x + 26) as u32 }
+            } else {
+                self.dir.read_u16(x + 26) as u32
+            };
+            if a & 0x10 == 0 {
+                let _ = self.file(i, x, c)?;
+                continue;
+            }
+            if self.is_first() {
+                self.r.dirs += 1;
+            }
+            if c == 0 {
+                if self.is_first() {
+                    self.r.chains += 1;
+                }
+                continue;
+            }
+            let _ = self.chain(c)?;
+            if n >= DEPTH {
+                if self.is_first() {
+                    self.r.skipped += 1;
+                }
+                continue;
+            }
+            unsafe { k.get_unchecked_mut(n) }.set(self.vol, ClusterIndex::new(c));
+            n += 1;
+        }
+        Ok(())
+    }
+    fn lfn(&mut self, o: &mut Orphans, block: u32, offset: usize, h: u8) -> DevResult<()> {
+        let (q, s) = (h & 0x1F, self.dir.read_u8(offset + 13));
+        if h & 0x40 != 0 {
+            // Start of a new sequence, anything before it is orphaned.
+            let _ = self.orphans(o)?;
+            (o.sum, o.ord) = (s, q);
+        } else if o.count == 0 || q + 1 != o.ord || s != o.sum {
+            let _ = self.orphans(o)?;
+            // Set the ordinal to zero so this sequence can never match.
+            (o.sum, o.ord) = (s, 0);
+        } else {
+            o.ord = q;
+        }
+        if o.count as usize >= o.pos.len() {
+            let _ = self.orphans(o)?;
+            o.ord = 0;
+        }
+        unsafe { *o.pos.get_unchecked_mut(o.count as usize) = (block, offset) };
+        o.count += 1;
+        Ok(())
+    }
+    fn file(&mut self, block: u32, offset: usize, c: u32) -> DevResult<()> {
+        let (l, v) = if c == 0 { (0, true) } else { self.chain(c)? };
+        if !self.is_first() {
+            return Ok(());
+        }
+        self.r.files += 1;
+        let (s, z) = (self.dir.read_u32(offset + 28), self.vol.block.bytes() as u64);
+        // NOTE(sf): A zero sized File can still keep its first Cluster, as
+        //           'Volume::truncate' keeps the first Cluster intact.
+        let n = match ((s as u64 + z - 1) / z) as u32 {
+            0 if c != 0 => 1,
+            x => x,
+        };
+        if !v || l == n {
+            return Ok(());
+        }
+        self.r.sizes += 1;
+        if !self.repair {
+            return Ok(());
+        }
+        if l > n {
+            // Chain is longer than the size, free the extra Clusters.
+            let mut x = unsafe { ClusterIndex::new_unchecked(c) };
+            for _ in 1..n {
+                x = match self.vol.next(self.tmp, &mut self.cache, x)? {
+                    Some(v) => v,
+                    None => break,
+                };
+            }
+            self.cache.clear();
+            let _ = self.vol.truncate(self.tmp, x)?;
+        } else {
+            // Chain is shorter than the size, shrink the size to what we
+            // actually have.
+            self.dir.write_u32(offset + 28, (l as u64 * z).min(s as u64) as u32);
+            let _ = self.vol.mark_dirty()?;
+            let _ = self.vol.dev.write_single(self.dir, block)?;
+        }
+        self.r.fixed += 1;
+        Ok(())
+    }
+    fn chain(&mut self, start: u32) -> DevResult<(u32, bool)> {
+        let (mut x, mut n, mut k) = (start, 0u32, false);
+        loop {
+            if x < 2 || x >= self.vol.info.count + 2 || n > self.vol.info.count {
+                // Out of range or a loop.
+                if self.is_first() {
+                    self.r.chains += 1;
+                }
+                return Ok((n, false));
+            }
+            n += 1;
+            if self.mark(x) && !k {
+                self.r.cross += 1;
+                k = true;
+            }
+            match self.vol.value(self.tmp, &mut self.cache, x)? {
+                0 => (),
+                v if v == 1 || self.vol.is_eoc(v) => return Ok((n, true)),
+                v if self.vol.is_bad(v) => (),
+                v => {
+                    x = v;
+                    continue;
+                },
+            }
+            // Chain points to a free or bad Cluster.
+            if self.is_first() {
+                self.r.chains += 1;
+            }
+            return Ok((n, false));
+        }
+    }
+    fn orphans(&mut self, o: &mut Orphans) -> DevResult<()> {
+        if o.count == 0 {
+            return Ok(());
+        }
+        let n = o.count;
+        o.count = 0;
+        if !self.is_first() {
+            return Ok(());
+        }
+        self.r.orphans += n as u32;
+        if !self.repair {
+            return Ok(());
+        }
+        let _ = self.vol.mark_dirty()?;
+        for i in 0..n as usize {
+            let (b, x) = unsafe { *o.pos.get_unchecked(i) };
+            if b == self.last {
+                self.dir.write_u8(x, 0xE5);
+                let _ = self.vol.dev.write_single(self.dir, b)?;
+                continue;
+            }
+            self.cache.clear();
+            let _ = self.vol.dev.read_single(self.tmp, b)?;
+            self.tmp.write_u8(x, 0xE5);
+            let _ = self.vol.dev.write_single(self.tmp, b)?;
+        }
+        self.r.fixed += n as u32;
+        Ok(())
+    }
+}
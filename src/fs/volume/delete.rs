@@ -0,0 +1,150 @@
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+
+#![no_implicit_prelude]
+
+extern crate core;
+
+use core::convert::From;
+use core::option::Option::{self, None, Some};
+use core::result::Result::{Err, Ok};
+
+use crate::fs::{Block, BlockDevice, CacheProvider, DevResult, DeviceError, DirEntry, DirectoryIndex, Volume};
+
+const DEPTH: usize = 0x10usize;
+
+pub struct DeleteReport {
+    dirs:  u32,
+    files: u32,
+    bytes: u64,
+}
+
+struct Level {
+    dir:  DirEntry,
+    skip: u32,
+}
+
+impl Level {
+    #[inline]
+    const fn new(dir: DirEntry) -> Level {
+        Level { dir, skip: 0u32 }
+    }
+}
+impl DeleteReport {
+    #[inline]
+    const fn new() -> DeleteReport {
+        DeleteReport {
+            dirs:  0u32,
+            files: 0u32,
+            bytes: 0u64,
+        }
+    }
+
+    /// Count of Directories removed, including the Directory being deleted.
+    #[inline]
+    pub fn dirs(&self) -> u32 {
+        self.dirs
+    }
+    #[inline]
+    pub fn files(&self) -> u32 {
+        self.files
+    }
+    /// Total size of the Files removed in bytes.
+    #[inline]
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+}
+
+/// Delete the Directory and everything in it, depth first.
+///
+/// Each pass over a Directory removes its Files until a Directory is found,
+/// which is then walked before coming back to its parent. Removed entries are
+/// not seen again, so a pass always starts at the beginning of the Directory.
+///
+/// When 'dry' is true nothing is removed, the entries that would be removed
+/// are only counted. As nothing is removed, the count of entries already seen
+/// is kept for each Directory to skip them on the next pass.
+///
+/// The root Directory has no entry of it's own, so only it's contents are
+/// removed and it is not counted.
+///
+/// Directories nested deeper than 16 levels return an 'Overflow' error when
+/// they are reached, a dry run can be used to check for this first.
+pub(super) fn delete_tree<'a, B: BlockDevice, C: CacheProvider>(vol: &'a Volume<'a, B, C>, dir: DirEntry, t: &mut Block, force: bool, dry: bool) -> DevResult<DeleteReport> {
+    if !dry {
+        let _ = vol.writable()?;
+    }
+    let mut k: [Option<Level>; DEPTH] = [const { None }; DEPTH];
+    let (mut r, mut n) = (DeleteReport::new(), 1usize);
+    k[0] = Some(Level::new(dir));
+    let mut x = DirectoryIndex::new(vol)?;
+    while n > 0 {
+        let v = unsafe { k.get_unchecked_mut(n - 1) }.as_mut().ok_or(DeviceError::InvalidIndex)?;
+        let _ = unsafe { x.reset_entry(Some(&v.dir))? };
+        let (mut c, mut i) = (None, 0u32);
+        for e in x.into_iter_mut() {
+            let mut e = e?;
+            // NOTE(sf): Only skip the Self and Parent entries by name, as the
+            //           other checks also match empty Files.
+            if e.shortname().is_self() || e.shortname().is_parent() {
+                continue;
+            }
+            i += 1;
+            if i <= v.skip {
+                continue;
+            }
+            if !force {
+                return Err(DeviceError::NonEmptyDirectory);
+            }
+            if e.is_directory() {
+                c = Some(DirEntry::from(&mut e));
+                break;
+            }
+            (r.files, r.bytes) = (r.files + 1, r.bytes + e.size() as u64);
+            if !dry {
+                let _ = e.delete(vol, t)?;
+            }
+        }
+        match c {
+            Some(_) if n >= DEPTH => return Err(DeviceError::Overflow),
+            Some(d) => {
+                if dry {
+                    v.skip = i;
+                }
+                k[n] = Some(Level::new(d));
+                n += 1;
+            },
+            None => {
+                // The Directory is empty now, remove it and go back to the
+                // parent.
+                n -= 1;
+                let d = unsafe { k.get_unchecked_mut(n) }.take().ok_or(DeviceError::InvalidIndex)?;
+                if d.dir.is_root() {
+                    continue;
+                }
+                r.dirs += 1;
+                if !dry {
+                    let _ = d.dir.delete(vol, t)?;
+                }
+            },
+        }
+    }
+    Ok(r)
+}
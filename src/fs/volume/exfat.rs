@@ -0,0 +1,501 @@
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+
+#![no_implicit_prelude]
+
+extern crate core;
+
+use core::iter::{IntoIterator, Iterator};
+use core::ops::FnMut;
+use core::option::Option::{self, None, Some};
+use core::result::Result::{Err, Ok};
+use core::str::from_utf8_unchecked;
+
+use crate::fs::volume::{Blocks, CLUSTER_EOF, Clock, Clusters, Handles, Index, State, Tables};
+use crate::fs::{Block, BlockCache, BlockDevice, BlockEntryIter, CacheProvider, ClusterIndex, DevResult, DeviceError, DirEntry, FatVersion, Storage, Volume, VolumeName};
+use crate::{Slice, SliceMut};
+
+pub(super) const TYPE_FILE: u8 = 0x85u8;
+pub(super) const TYPE_NAME: u8 = 0xC1u8;
+pub(super) const TYPE_LABEL: u8 = 0x83u8;
+pub(super) const TYPE_BITMAP: u8 = 0x81u8;
+pub(super) const TYPE_STREAM: u8 = 0xC0u8;
+pub(super) const TYPE_UPCASE: u8 = 0x82u8;
+
+impl<'a, B: BlockDevice, C: CacheProvider> Volume<'a, B, C> {
+    pub(super) fn new_ex(dev: &'a Storage<B, C>, b: &mut Block, lba: u32, blocks: u32) -> DevResult<Volume<'a, B, C>> {
+        // 0x6C - Bytes per Sector Shift
+        if b.read_u8(108) != 9 {
+            return Err(DeviceError::UnsupportedFileSystem);
+        }
+        // 0x6D - Sectors per Cluster Shift
+        let k = b.read_u8(109);
+        if k > 0x10 {
+            return Err(DeviceError::InvalidFileSystem);
+        }
+        // 0x48 - Volume Length
+        if b.read_u32(76) != 0 {
+            return Err(DeviceError::Overflow);
+        }
+        // 0x6A - Volume Flags
+        // 0x6E - Number of FATs
+        let (f, n) = ((b.read_u16(106) & 0x1) as u8, b.read_u8(110));
+        if n == 0 || f >= n {
+            return Err(DeviceError::InvalidFileSystem);
+        }
+        // 0x50 - FAT Offset
+        // 0x54 - FAT Length
+        // 0x58 - Cluster Heap Offset
+        // 0x5C - Cluster Count
+        // 0x60 - First Cluster of Root Directory
+        //
+        // NOTE(sf): exFAT never mirrors the FATs, only the active one is used,
+        //           so we set the "mirroring disabled" bit.
+        let mut v = Volume {
+            dev,
+            ver: FatVersion::new_ex(),
+            info: Clusters::new_16(b.read_u32(92)),
+            name: VolumeName::empty(),
+            block: Blocks::new(if blocks == 0 { b.read_u32(72) } else { blocks }, unsafe { 1u32.unchecked_shl(k as u32) }),
+            index: Index {
+                lba,
+                fat: b.read_u32(80),
+                data: b.read_u32(88),
+                size: b.read_u32(84),
+                root: ClusterIndex::new(b.read_u32(96)).ok_or(DeviceError::InvalidFileSystem)?,
+                fats: n,
+                flags: 0x80 | f,
+            },
+            table: Tables::empty(),
+            clock: Clock::empty(),
+            open: Handles::new(),
+            state: State::new(),
+            // 0x64 - Volume Serial Number
+            serial: b.read_u32(100),
+            ro: dev.device().is_read_only(),
+        };
+        let _ = v.load_tables(b)?;
+        Ok(v)
+    }
+
+    /// Returns the count of Clusters following the first one for Directories
+    /// that are stored without a FAT chain, or None if the FAT should be used.
+    pub(super) fn ex_run(&self, tmp: &mut Block, e: &DirEntry) -> DevResult<Option<u32>> {
+        if !e.is_contiguous() || e.cluster().is_none() {
+            return Ok(None);
+        }
+        // NOTE(sf): The Directory may have been moved to a FAT chain since the
+        //           entry was read, so we re-read the Stream entry.
+        let (b, o, _) = e.ex_set();
+        let (mut f, mut n) = (0u8, 0u32);
+        let _ = self.walk_set(tmp, b, o, 2, false, |i, v| {
+            if i == 1 {
+                // 0x1 - General Secondary Flags
+                // 0x18 - Data Length
+                (f, n) = (v.read_u8(1), v.read_u32(24));
+            }
+        })?;
+        if f & 0x2 == 0 {
+            return Ok(None);
+        }
+        Ok(Some(n.div_ceil(self.block.bytes()).saturating_sub(1)))
+    }
+    pub(super) fn ex_free(&self, tmp: &mut Block, start: u32, end: u32) -> DevResult<ClusterIndex> {
+        let (mut p, mut c) = (start, BlockCache::new());
+        while p < end {
+            let (i, o, m) = self.ex_bit(p)?;
+            let _ = c.read_single(&self.dev, tmp, i)?;
+            let v = tmp.read_u8(o);
+            if v == 0xFF && m == 1 {
+                // Skip whole used bytes.
+                p += 8;
+                continue;
+            }
+            if v & m == 0 {
+                return Ok(unsafe { ClusterIndex::new_unchecked(p) });
+            }
+            p += 1;
+        }
+        Err(DeviceError::NoSpace)
+    }
+    #[inline]
+    pub(super) fn ex_is_free(&self, tmp: &mut Block, cache: &mut BlockCache, idx: u32) -> DevResult<bool> {
+        let (i, o, m) = self.ex_bit(idx)?;
+        let _ = cache.read_single(&self.dev, tmp, i)?;
+        Ok(tmp.read_u8(o) & m == 0)
+    }
+    #[inline]
+    pub(super) fn ex_mark(&self, tmp: &mut Block, idx: ClusterIndex, used: bool) -> DevResult<()> {
+        self.ex_fill(tmp, *idx, 1, used)
+    }
+    /// Free a Cluster run that has no FAT chain, if 'keep' is true the first
+    /// Cluster is kept allocated.
+    pub(super) fn ex_release(&self, tmp: &mut Block, idx: ClusterIndex, count: u32, keep: bool) -> DevResult<()> {
+        let k = if keep { 1 } else { 0 };
+        if count <= k {
+            return Ok(());
+        }
+        self.ex_fill(tmp, *idx + k, count - k, false)
+    }
+    /// Write a FAT chain for a Cluster run that has no FAT chain, so that it
+    /// can be extended.
+    pub(super) fn ex_unchain(&self, tmp: &mut Block, idx: ClusterIndex, count: u32) -> DevResult<()> {
+        for i in 0..count {
+            let v = *idx + i;
+            let _ = self.update(
+                tmp,
+                unsafe { ClusterIndex::new_unchecked(v) },
+                if i + 1 < count { v + 1 } else { CLUSTER_EOF },
+            )?;
+        }
+        Ok(())
+    }
+    /// Extend the Directory with a new zeroed Cluster after 'last' and update
+    /// the Directory size.
+    pub(super) fn ex_grow(&self, tmp: &mut Block, parent: Option<&DirEntry>, last: ClusterIndex) -> DevResult<ClusterIndex> {
+        let e = match parent {
+            Some(v) if v.cluster().is_some() => v,
+            // The root Directory has no size and always has a FAT chain.
+            _ => return self.allocate(tmp, Some(last), true),
+        };
+        if let Some(n) = self.ex_run(tmp, e)? {
+            let _ = self.ex_unchain(tmp, e.index(), n + 1)?;
+        }
+        let n = self.allocate(tmp, Some(last), true)?;
+        let (b, o, c) = e.ex_set();
+        let z = self.block.bytes();
+        let _ = self.ex_update(tmp, b, o, c, |i, mut v| {
+            if i != 1 {
+                return;
+            }
+            // 0x1 - General Secondary Flags
+            // 0x8 - Valid Data Length
+            // 0x18 - Data Length
+            let s = v.read_u32(24).saturating_add(z);
+            v.write_u8(1, v.read_u8(1) & !0x2);
+            v.write_u32(8, s);
+            v.write_u32(24, s);
+        })?;
+        Ok(n)
+    }
+    pub(super) fn ex_create(&self, tmp: &mut Block, name: &[u8], attrs: u8, parent: Option<&DirEntry>, alloc: bool) -> DevResult<DirEntry> {
+        // Names are always passed from a 'str', so they're valid UTF8.
+        let v = unsafe { from_utf8_unchecked(name) };
+        let n = v.encode_utf16().count();
+        if n == 0 {
+            return Err(DeviceError::InvalidOptions);
+        }
+        if n > 0xFF {
+            return Err(DeviceError::NameTooLong);
+        }
+        let (h, s) = (self.ex_hash(tmp, v)?, 2 + n.div_ceil(15) as u8);
+        // Look for unused entries, which have the "InUse" bit cleared.
+        let r = self.find(tmp, s, parent, |_, b| b.read_u8(0) & 0x80 == 0)?;
+        let mut e = DirEntry::new_ex(attrs, s - 1);
+        e.stamp(self);
+        e.fill_name(name);
+        if alloc {
+            let _ = e.allocate(self, tmp)?;
+            if attrs & 0x10 != 0 {
+                e.set_size(self.block.bytes());
+            }
+        }
+        // The Set Checksum covers every entry, so it has to be calculated
+        // before the primary entry is written.
+        let (mut x, mut k) = ([0u8; DirEntry::SIZE], 0u16);
+        for i in 0..s {
+            e.write_ex_entry(i, v, h, &mut x);
+            k = checksum(k, &x, i == 0);
+        }
+        let (mut w, mut t) = (BlockEntryIter::new(r.blocks()), 0u8);
+        for (_, i, l, o) in r {
+            if l && !w.in_scope(i) {
+                let _ = w.load_and_flush(&self.dev, i)?;
+            }
+            let (b, p) = (w.buffer(i), o * DirEntry::SIZE);
+            let mut d = unsafe { b.get_unchecked_mut(p..p + DirEntry::SIZE) };
+            e.write_ex_entry(t, v, h, d);
+            if t == 0 {
+                // 0x2 - Set Checksum
+                d.write_u16(2, k);
+                e.prepare(i, p);
+            }
+            t += 1;
+            if t >= s {
+                break;
+            }
+        }
+        let _ = w.flush(self.dev)?;
+        Ok(e)
+    }
+    /// Write the Volume Label entry of the root Directory, replacing the
+    /// current one if there is one.
+    pub(super) fn ex_label(&self, tmp: &mut Block, v: &VolumeName) -> DevResult<()> {
+        let (i, x) = match self.scan(tmp, None, |b| b.read_u8(0) == TYPE_LABEL)? {
+            Some(v) => v,
+            None => {
+                let r = self.find(tmp, 1, None, |_, b| b.read_u8(0) & 0x80 == 0)?;
+                let (_, i, _, o) = r.into_iter().next().ok_or(DeviceError::NoSpace)?;
+                let _ = self.dev.read_single(tmp, i)?;
+                (i, o * DirEntry::SIZE)
+            },
+        };
+        let mut d = unsafe { tmp.get_unchecked_mut(x..x + DirEntry::SIZE) };
+        v.write_ex(d);
+        d.write_u8(0, TYPE_LABEL);
+        self.dev.write_single(tmp, i)
+    }
+    /// Walk 'count' entries starting at the supplied position, following the
+    /// Directory chain if the entries span Clusters. This is also used for FAT
+    /// entry sets with LFN entries.
+    ///
+    /// When 'write' is true, any changes made to the entries are saved.
+    pub(super) fn walk_set(&self, tmp: &mut Block, block: u32, offset: u32, count: u8, write: bool, mut f: impl FnMut(u8, &mut [u8])) -> DevResult<()> {
+        if write {
+            let _ = self.mark_dirty()?;
+        }
+        let (mut b, mut o, mut i) = (block, offset as usize, 0u8);
+        loop {
+            let _ = self.dev.read_single(tmp, b)?;
+            while o + DirEntry::SIZE <= Block::SIZE && i < count {
+                f(i, unsafe { tmp.get_unchecked_mut(o..o + DirEntry::SIZE) });
+                (o, i) = (o + DirEntry::SIZE, i + 1);
+            }
+            if write {
+                let _ = self.dev.write_single(tmp, b)?;
+            }
+            if i >= count {
+                return Ok(());
+            }
+            (b, o) = (self.ex_next_block(tmp, b)?, 0);
+        }
+    }
+    /// Same as 'walk_set', but the Set Checksum is updated to match the
+    /// changes, which are always written.
+    ///
+    /// A set inside one Block is changed and written with it's new checksum in
+    /// a single write. A set that spans Blocks is walked twice, once to find
+    /// the checksum of the changed entries and once to write them, so 'f' must
+    /// give the same result when called again on the same entries.
+    pub(super) fn ex_update(&self, tmp: &mut Block, block: u32, offset: u32, count: u8, mut f: impl FnMut(u8, &mut [u8])) -> DevResult<()> {
+        let _ = self.mark_dirty()?;
+        let (o, mut s) = (offset as usize, 0u16);
+        if o + count as usize * DirEntry::SIZE <= Block::SIZE {
+            let _ = self.dev.read_single(tmp, block)?;
+            for i in 0..count {
+                let p = o + i as usize * DirEntry::SIZE;
+                let b = unsafe { tmp.get_unchecked_mut(p..p + DirEntry::SIZE) };
+                f(i, b);
+                s = checksum(s, b, i == 0);
+            }
+            // 0x2 - Set Checksum
+            tmp.write_u16(o + 2, s);
+            return self.dev.write_single(tmp, block);
+        }
+        let _ = self.walk_set(tmp, block, offset, count, false, |i, b| {
+            f(i, b);
+            s = checksum(s, b, i == 0);
+        })?;
+        self.walk_set(tmp, block, offset, count, true, |i, mut b| {
+            f(i, b);
+            if i == 0 {
+                // 0x2 - Set Checksum
+                b.write_u16(2, s);
+            }
+        })
+    }
+
+    fn ex_bit(&self, v: u32) -> DevResult<(u32, usize, u8)> {
+        // NOTE(sf): The Bitmap is None when it's not stored in a contiguous run,
+        //           which we don't support writing to.
+        let m = self.table.bitmap.ok_or(DeviceError::NotWritable)?;
+        if v < 2 || v >= self.info.count + 2 {
+            return Err(DeviceError::InvalidCluster);
+        }
+        let i = v - 2;
+        Ok((
+            self.block_pos_at(m) + (i / (Block::SIZE as u32 * 8)),
+            ((i / 8) % Block::SIZE as u32) as usize,
+            unsafe { 1u8.unchecked_shl(i % 8) },
+        ))
+    }
+    fn ex_fill(&self, tmp: &mut Block, start: u32, count: u32, used: bool) -> DevResult<()> {
+        let _ = self.mark_dirty()?;
+        let (mut p, e) = (start, start.saturating_add(count));
+        while p < e {
+            let (i, ..) = self.ex_bit(p)?;
+            let _ = self.dev.read_single(tmp, i)?;
+            while p < e {
+                let (n, o, m) = self.ex_bit(p)?;
+                if n != i {
+                    break;
+                }
+                let v = tmp.read_u8(o);
+                tmp.write_u8(o, if used { v | m } else { v & !m });
+                p += 1;
+            }
+            let _ = self.dev.write_single(tmp, i)?;
+        }
+        Ok(())
+    }
+    fn ex_hash(&self, tmp: &mut Block, name: &str) -> DevResult<u16> {
+        let mut h = 0u16;
+        for c in name.encode_utf16() {
+            let v = self.ex_upcase(tmp, c)?;
+            h = unsafe { hash(hash(h, v as u8), v.unchecked_shr(8) as u8) };
+        }
+        Ok(h)
+    }
+    fn ex_upcase(&self, tmp: &mut Block, c: u16) -> DevResult<u16> {
+        if c < 0x80 {
+            return Ok(if c >= 0x61 && c <= 0x7A { c - 0x20 } else { c });
+        }
+        let t = match self.table.upcase {
+            Some(v) => v,
+            None => return Ok(c),
+        };
+        // NOTE(sf): The Up-case Table is compressed with runs of identity
+        //           mappings, so it has to be read in order. Non-ASCII names
+        //           are rare enough that we don't cache it.
+        let (p, n, v) = (self.block_pos_at(t), self.table.size as usize / 2, c as u32);
+        let (mut i, mut x, mut r, mut l) = (0u32, 0usize, false, u32::MAX);
+        while x < n {
+            let b = p + (x * 2 / Block::SIZE) as u32;
+            if b != l {
+                let _ = self.dev.read_single(tmp, b)?;
+                l = b;
+            }
+            let u = tmp.read_u16((x * 2) % Block::SIZE);
+            x += 1;
+            if r {
+                if v < i + u as u32 {
+                    return Ok(c);
+                }
+                (i, r) = (i + u as u32, false);
+                continue;
+            }
+            if u == 0xFFFF {
+                r = true;
+                continue;
+            }
+            if i == v {
+                return Ok(u);
+            }
+            i += 1;
+        }
+        Ok(c)
+    }
+    fn ex_next_block(&self, tmp: &mut Block, b: u32) -> DevResult<u32> {
+        // The fixed FAT12/FAT16 root Directory is before the data area and
+        // has no chain to follow.
+        if b < self.index.lba + self.index.data {
+            return Ok(b + 1);
+        }
+        let x = b - (self.index.lba + self.index.data);
+        if (x + 1) % self.block.blocks() != 0 {
+            return Ok(b + 1);
+        }
+        // NOTE(sf): Directories without a FAT chain have a zero (or unused)
+        //           FAT entry, an entry set never goes past the end of the
+        //           Directory, so the next Cluster is used.
+        let c = x / self.block.blocks() + 2;
+        match self.value(tmp, &mut BlockCache::new(), c)? {
+            0 => Ok(self.block_pos_at(unsafe { ClusterIndex::new_unchecked(c + 1) })),
+            v if v < 2 || self.is_bad(v) || self.is_eoc(v) => Err(DeviceError::InvalidChain),
+            v => Ok(self.block_pos_at(unsafe { ClusterIndex::new_unchecked(v) })),
+        }
+    }
+    fn load_tables(&mut self, b: &mut Block) -> DevResult<()> {
+        let (mut k, mut c) = (self.index.root, BlockCache::new());
+        let (mut m, mut u) = (None, None);
+        let a = self.index.flags & 0x1;
+        'outer: loop {
+            let p = self.block_pos_at(k);
+            for i in p..p + self.block.blocks() {
+                let _ = self.dev.read_single(b, i)?;
+                for e in (0..Block::SIZE).step_by(DirEntry::SIZE) {
+                    // 0x1 - Bitmap Flags
+                    // 0x4 - Table Checksum
+                    // 0x14 - First Cluster
+                    // 0x18 - Data Length
+                    match b.read_u8(e) {
+                        0 => break 'outer,
+                        // NOTE(sf): Volumes with two FATs also have two Bitmaps,
+                        //           we use the one that matches the active FAT.
+                        TYPE_BITMAP if b.read_u8(e + 1) & 0x1 == a => m = Some((b.read_u32(e + 20), b.read_u32(e + 24))),
+                        TYPE_UPCASE => u = Some((b.read_u32(e + 20), b.read_u32(e + 24))),
+                        TYPE_LABEL => self.name = VolumeName::new_ex(b.read_slice(e, DirEntry::SIZE)),
+                        _ => (),
+                    }
+                }
+            }
+            c.clear();
+            k = match self.next(b, &mut c, k)? {
+                Some(v) => v,
+                None => break,
+            };
+        }
+        // The Allocation Bitmap is required, the Up-case Table is only used for
+        // name hashes.
+        let (x, n) = m.ok_or(DeviceError::InvalidFileSystem)?;
+        if n < self.info.count.div_ceil(8) {
+            return Err(DeviceError::InvalidFileSystem);
+        }
+        if self.is_contiguous(b, x, n)? {
+            self.table.bitmap = ClusterIndex::new(x);
+        }
+        if let Some((x, n)) = u {
+            if self.is_contiguous(b, x, n)? {
+                (self.table.upcase, self.table.size) = (ClusterIndex::new(x), n);
+            }
+        }
+        Ok(())
+    }
+    fn is_contiguous(&self, tmp: &mut Block, start: u32, size: u32) -> DevResult<bool> {
+        let n = size.div_ceil(self.block.bytes());
+        if start < 2 || n == 0 || start.saturating_add(n) > self.info.count + 2 {
+            return Ok(false);
+        }
+        let mut c = BlockCache::new();
+        for i in start..start + n - 1 {
+            if self.value(tmp, &mut c, i)? != i + 1 {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// Add the entry to the running exFAT Set Checksum, the checksum field
+/// of the primary entry is skipped.
+#[inline]
+pub(super) fn checksum(mut s: u16, b: &[u8], primary: bool) -> u16 {
+    for (i, v) in b.iter().enumerate().take(DirEntry::SIZE) {
+        if primary && (i == 2 || i == 3) {
+            continue;
+        }
+        s = hash(s, *v);
+    }
+    s
+}
+
+#[inline]
+fn hash(s: u16, v: u8) -> u16 {
+    unsafe { (s & 1).unchecked_shl(15).wrapping_add(s.unchecked_shr(1)).wrapping_add(v as u16) }
+}
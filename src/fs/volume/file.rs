@@ -20,24 +20,37 @@
 #![no_implicit_prelude]
 
 extern crate core;
+#[cfg(feature = "pico")]
 extern crate rpsp;
 
+use core::clone::Clone;
 use core::cmp::{Ord, PartialEq, PartialOrd};
 use core::convert::{AsRef, From, TryInto};
+use core::iter::Iterator;
 use core::marker::PhantomData;
-use core::mem::{drop, replace, transmute};
-use core::ops::{Deref, DerefMut, Drop};
-use core::option::Option::{None, Some};
+use core::matches;
+use core::mem::{drop, forget, replace, transmute};
+use core::ops::{Deref, DerefMut, Drop, FnMut};
+use core::option::Option::{self, None, Some};
 use core::ptr::{NonNull, copy_nonoverlapping, write_bytes};
-use core::result::Result::{self, Err, Ok};
+#[cfg(feature = "pico")]
+use core::result::Result;
+use core::result::Result::{Err, Ok};
 
+#[cfg(feature = "pico")]
 use rpsp::io::{Read, Seek, SeekFrom, Write};
+#[cfg(feature = "pico")]
 use rpsp::time::{Month, Time, Weekday};
 
 use crate::fs::state::{Safe, Unsafe};
-use crate::fs::volume::to_lfn;
-use crate::fs::{Block, BlockCache, BlockDevice, BlockPtr, Cache, Cluster, ClusterIndex, DevResult, DeviceError, DirectoryIndex, Error, LongName, LongNamePtr, ShortName, Storage, Volume};
+use crate::fs::volume::exfat::{TYPE_FILE, TYPE_NAME, TYPE_STREAM, checksum};
+use crate::fs::volume::{delete_tree, is_short, to_lfn};
+use crate::fs::{Block, BlockBuffer, BlockCache, BlockDevice, BlockPtr, CacheProvider, Cluster, ClusterIndex, DeleteReport, DevResult, DeviceError, DirectoryIndex, LongName, LongNamePtr, SharedCache, ShortName, Storage, Volume};
 use crate::{Slice, SliceMut};
+#[cfg(feature = "pico")]
+use crate::fs::Error;
+#[cfg(not(feature = "pico"))]
+use crate::fs::{Month, Time, Weekday};
 
 const FILE_MAX_SIZE: u32 = 0xFFFFFFFFu32;
 
@@ -49,32 +62,46 @@ pub struct DirEntry {
     size:     u32,
     attrs:    u8,
     block:    u32,
+    flags:    u8,
     offset:   u32,
     cluster:  Cluster,
     created:  Time,
+    accessed: Time,
     modified: Time,
+    // Position of the first entry of the set, which is the first LFN entry
+    // when there is one.
+    start:    (u32, u32),
 }
 pub struct DirEntryFull {
     lfn:   LongNamePtr,
+    start: (u32, u32),
+    pos:   u16,
+    low:   u16,
     sum:   u8,
+    count: u8,
+    set:   ExSet,
     entry: DirEntry,
 }
 pub struct DirEntryPtr<'a> {
     ptr: NonNull<DirEntryFull>,
     _p:  PhantomData<&'a DirEntryFull>,
 }
-pub struct Reader<'a, B: BlockDevice> {
-    f:   File<'a, B, Unsafe>,
+/// Buffered reader made by 'File::into_reader'.
+///
+/// It holds 'block_a' until it's dropped, so with a LocalCache any Volume call
+/// or mount made meanwhile returns 'FileInUse'.
+pub struct Reader<'a, B: BlockDevice, C: CacheProvider = SharedCache> {
+    f:   File<'a, B, Unsafe, C>,
     bp:  u32,
     buf: BlockPtr,
 }
-pub struct Directory<'a, B: BlockDevice> {
+pub struct Directory<'a, B: BlockDevice, C: CacheProvider = SharedCache> {
     dir: DirEntry,
-    vol: &'a Volume<'a, B>,
+    vol: &'a Volume<'a, B, C>,
 }
-pub struct File<'a, B: BlockDevice, S: FileSync = Safe> {
+pub struct File<'a, B: BlockDevice, S: FileSync = Safe, C: CacheProvider = SharedCache> {
     pos:   u32,
-    vol:   &'a Volume<'a, B>,
+    vol:   &'a Volume<'a, B, C>,
     file:  DirEntry,
     last:  ClusterIndex,
     mode:  u8,
@@ -82,8 +109,17 @@ pub struct File<'a, B: BlockDevice, S: FileSync = Safe> {
     _p:    PhantomData<*const S>,
 }
 
+// Running state of an exFAT entry set while it's being read.
+struct ExSet {
+    sum:   u16,
+    want:  u16,
+    high:  u16,
+    left:  u8,
+    units: u8,
+}
+
 pub trait FileSync {
-    fn cache() -> BlockPtr;
+    fn cache<B: BlockDevice, C: CacheProvider>(dev: &Storage<B, C>) -> DevResult<BlockPtr>;
 }
 
 impl Mode {
@@ -110,6 +146,9 @@ impl DirEntry {
     pub const SIZE: usize = 32usize;
     pub const SIZE_PER_BLOCK: u32 = (Block::SIZE / DirEntry::SIZE) as u32;
 
+    const EXFAT: u8 = 0x80u8;
+    const NO_CHAIN: u8 = 0x2u8;
+
     #[inline]
     pub(super) const fn new_root() -> DirEntry {
         DirEntry {
@@ -118,10 +157,13 @@ impl DirEntry {
             size:     0u32,
             block:    0u32,
             attrs:    0x10u8,
+            flags:    0u8,
             offset:   0u32,
             cluster:  None,
             created:  Time::empty(),
+            accessed: Time::empty(),
             modified: Time::empty(),
+            start:    (0u32, 0u32),
         }
     }
     #[inline]
@@ -132,10 +174,30 @@ impl DirEntry {
             name: ShortName::empty(),
             size: 0u32,
             block: 0u32,
+            flags: 0u8,
             offset: 0u32,
             cluster: None,
             created: Time::empty(),
+            accessed: Time::empty(),
             modified: Time::empty(),
+            start: (0u32, 0u32),
+        }
+    }
+    #[inline]
+    pub(super) const fn new_ex(attrs: u8, lfn: u8) -> DirEntry {
+        DirEntry {
+            lfn,
+            attrs,
+            name: ShortName::empty(),
+            size: 0u32,
+            block: 0u32,
+            flags: DirEntry::EXFAT,
+            offset: 0u32,
+            cluster: None,
+            created: Time::empty(),
+            accessed: Time::empty(),
+            modified: Time::empty(),
+            start: (0u32, 0u32),
         }
     }
     #[inline]
@@ -146,10 +208,13 @@ impl DirEntry {
             name: ShortName::SELF,
             size: 0u32,
             attrs: 0x10u8,
+            flags: 0u8,
             offset: 0u32,
             cluster: parent.cluster,
-            created: Time::empty(),
-            modified: Time::empty(),
+            created: parent.created,
+            accessed: parent.accessed,
+            modified: parent.modified,
+            start: (block, 0u32),
         }
     }
     #[inline]
@@ -161,9 +226,12 @@ impl DirEntry {
             name: ShortName::PARENT,
             size: 0u32,
             attrs: 0x10u8,
+            flags: 0u8,
             offset: 0x20u32,
             created: Time::empty(),
+            accessed: Time::empty(),
             modified: Time::empty(),
+            start: (block, 0x20u32),
         }
     }
 
@@ -188,6 +256,10 @@ impl DirEntry {
         &self.created
     }
     #[inline]
+    pub fn accessed(&self) -> &Time {
+        &self.accessed
+    }
+    #[inline]
     pub fn attributes(&self) -> u8 {
         self.attrs
     }
@@ -220,6 +292,10 @@ impl DirEntry {
         self.created = t
     }
     #[inline]
+    pub fn set_accessed(&mut self, t: Time) {
+        self.accessed = t
+    }
+    #[inline]
     pub fn set_attributes(&mut self, a: u8) {
         self.attrs = a
     }
@@ -228,21 +304,21 @@ impl DirEntry {
         self.modified = t
     }
     #[inline]
-    pub fn into_dir<'a, B: BlockDevice>(self, vol: &'a Volume<B>) -> DevResult<Directory<'a, B>> {
+    pub fn into_dir<'a, B: BlockDevice, C: CacheProvider>(self, vol: &'a Volume<B, C>) -> DevResult<Directory<'a, B, C>> {
         if !self.is_directory() {
             return Err(DeviceError::NotADirectory);
         }
         Ok(Directory::new(self, vol))
     }
     #[inline]
-    pub fn into_file<'a, B: BlockDevice>(self, vol: &'a Volume<B>, mode: u8) -> DevResult<File<'a, B>> {
+    pub fn into_file<'a, B: BlockDevice, C: CacheProvider>(self, vol: &'a Volume<B, C>, mode: u8) -> DevResult<File<'a, B, Safe, C>> {
         if !Mode::is_mode_valid(mode) {
             return Err(DeviceError::InvalidOptions);
         }
         if !self.is_file() {
             return Err(DeviceError::NotAFile);
         }
-        Ok(File::new(self, mode, vol))
+        File::new(self, mode, vol)
     }
 
     #[inline]
@@ -257,16 +333,53 @@ impl DirEntry {
         self.name.fill(v);
     }
     #[inline]
-    pub(super) fn is_root_or_parent(&self) -> bool {
-        match &self.cluster {
-            Some(v) if v.is_empty() => true,
-            None => true,
-            _ => self.name.is_empty() || self.name.is_self() || self.name.is_parent(),
+    pub(super) fn set_name(&mut self, v: ShortName) {
+        self.name = v;
+    }
+    #[inline]
+    pub(super) fn set_size(&mut self, v: u32) {
+        self.size = v
+    }
+    /// Set the created, modified and accessed times to the current time of
+    /// the Volume TimeSource, if there is one.
+    #[inline]
+    pub(super) fn stamp<B: BlockDevice, C: CacheProvider>(&mut self, vol: &Volume<B, C>) {
+        if let Some(t) = vol.now() {
+            (self.created, self.modified, self.accessed) = (t, t, t);
         }
     }
+    /// Returns the position of the exFAT entry set and the count of entries
+    /// in it.
+    #[inline]
+    pub(super) fn ex_set(&self) -> (u32, u32, u8) {
+        (self.block, self.offset, self.lfn + 1)
+    }
+    /// Returns true if any File handle has this entry open.
+    #[inline]
+    pub(super) fn is_open<B: BlockDevice, C: CacheProvider>(&self, vol: &Volume<B, C>) -> bool {
+        vol.open.is_open(self.block, self.offset)
+    }
+    /// Returns true if this is the root Directory, which has no entry on disk.
+    #[inline]
+    pub(super) fn is_root(&self) -> bool {
+        self.block == 0
+    }
+    /// Returns true if this is an exFAT entry that has no FAT chain, so it's
+    /// Clusters are one contiguous run.
+    #[inline]
+    pub(super) fn is_contiguous(&self) -> bool {
+        self.flags & (DirEntry::EXFAT | DirEntry::NO_CHAIN) == DirEntry::EXFAT | DirEntry::NO_CHAIN
+    }
     #[inline]
     pub(super) fn prepare(&mut self, b: u32, o: usize) {
-        (self.block, self.offset) = (b, o as u32)
+        (self.block, self.offset) = (b, o as u32);
+        if self.lfn == 0 {
+            self.start = (b, o as u32);
+        }
+    }
+    #[inline]
+    pub(super) fn prepare_start(&mut self, b: u32, o: usize) {
+        self.start = (b, o as u32)
     }
     pub(super) fn write_entry(&self, f: bool, mut b: &mut [u8]) {
         b.write_from(0, self.name.as_raw());
@@ -274,20 +387,22 @@ impl DirEntry {
         // We set these to zero as we don't need them
         b.write_u8(12, 0); // WinNT bit ??
         b.write_u8(13, 0); // Created Time milliseconds
-        b.write_u16(18, 0); // Last Access Time
+        // NOTE(sf): The Last Access Time is only a date, so it's written first
+        //           and the Created date overwrites the time half of it.
+        time_write(&self.accessed, 16, b);
         time_write(&self.created, 14, b);
-        let c = self.index();
+        let c = self.cluster.map_or(0, |v| *v);
         if f {
             b.write_u16(20, unsafe { (c.unchecked_shr(16) & 0xFFFF) as u16 });
         } else {
             b.write_u16(20, 0);
         }
         time_write(&self.modified, 22, b);
-        b.write_u16(26, (*c & 0xFFFF) as u16);
+        b.write_u16(26, (c & 0xFFFF) as u16);
         b.write_u32(28, self.size);
     }
     pub(super) fn write_lfn_entry(&self, lfn: &LongName, pos: u8, s: u8, mut b: &mut [u8]) {
-        let (n, c) = (lfn.len(), self.name.checksum());
+        let c = self.name.checksum();
         // Clear out data.
         unsafe { write_bytes(b.as_mut_ptr(), 0, DirEntry::SIZE) };
         b.write_u8(0, if pos == 0 { 0x40 } else { 0 } | (s - pos) as u8);
@@ -295,73 +410,184 @@ impl DirEntry {
         b.write_u8(12, 0);
         b.write_u8(13, c);
         b.write_u16(26, 0);
-        let (s, mut p) = ((s - 1 - pos) as usize * 13, 0);
-        for v in s..(s + 0xD).min(n) {
-            b.write_u8(to_lfn(p), lfn.as_raw().read_u8(v));
+        let mut p = 0usize;
+        for v in lfn.as_str().encode_utf16().skip((s - 1 - pos) as usize * 0xD).take(0xD) {
+            b.write_u16(to_lfn(p), v);
             p += 1;
         }
-        // Add NULL padding char.
-        if p < 12 {
-            b.write_u8(to_lfn(p + 1), 0);
+        // Add NULL padding char, then fill the remaining with 0xFFFF.
+        if p < 0xD {
+            b.write_u16(to_lfn(p), 0);
             p += 1;
         }
-        // Fill remaining with 0xFF
-        if p < 12 {
-            for x in to_lfn(p)..DirEntry::SIZE {
-                match x {
-                    0 | 0xB | 0xC | 0xD | 0x1A | 0x1B => (), // Skip these spots
-                    _ => b.write_u8(x, 0xFF),
+        for x in p..0xD {
+            b.write_u16(to_lfn(x), 0xFFFF);
+        }
+    }
+    pub(super) fn write_ex_entry(&self, pos: u8, name: &str, hash: u16, mut b: &mut [u8]) {
+        unsafe { write_bytes(b.as_mut_ptr(), 0, DirEntry::SIZE) };
+        match pos {
+            0 => {
+                // 0x1 - Secondary Count
+                // 0x4 - File Attributes
+                // 0x8 - Create Timestamp
+                // 0xC - Last Modified Timestamp
+                // 0x10 - Last Accessed Timestamp
+                b.write_u8(0, TYPE_FILE);
+                b.write_u8(1, self.lfn);
+                b.write_u16(4, self.attrs as u16);
+                time_write(&self.created, 8, b);
+                time_write(&self.modified, 12, b);
+                time_write(&self.accessed, 16, b);
+            },
+            1 => {
+                // 0x3 - Name Length
+                // 0x4 - Name Hash
+                b.write_u8(0, TYPE_STREAM);
+                b.write_u8(3, name.encode_utf16().count() as u8);
+                b.write_u16(4, hash);
+                self.write_ex_stream(b);
+            },
+            _ => {
+                b.write_u8(0, TYPE_NAME);
+                for (i, c) in name.encode_utf16().skip((pos - 2) as usize * 15).take(15).enumerate() {
+                    b.write_u16(2 + (i * 2), c);
                 }
-            }
+            },
         }
     }
-    pub(super) fn delete<B: BlockDevice>(&self, vol: &Volume<B>, t: &mut Block) -> DevResult<()> {
-        let _ = vol.dev.read_single(t, self.block)?;
-        if self.offset as usize > Block::SIZE {
-            return Err(DeviceError::BadData);
+    #[inline]
+    pub(super) fn delete<B: BlockDevice, C: CacheProvider>(&self, vol: &Volume<B, C>, t: &mut Block) -> DevResult<()> {
+        let _ = self.unlink(vol, t)?;
+        self.release(vol, t)
+    }
+    /// Remove the Directory entry without freeing the Clusters it points to.
+    pub(super) fn unlink<B: BlockDevice, C: CacheProvider>(&self, vol: &Volume<B, C>, t: &mut Block) -> DevResult<()> {
+        let _ = vol.mark_dirty()?;
+        if self.is_root() {
+            return Err(DeviceError::InvalidOptions);
         }
-        t.write_u8(self.offset as usize, 0xE5);
-        if self.lfn == 0 {
-            return vol.dev.write_single(t, self.block);
+        if self.is_open(vol) {
+            return Err(DeviceError::FileInUse);
         }
-        // NOTE(sf): We try to remove the long filename entries, but we're not
-        //           gonna go back further than a whole block for simplicity.
-        let n = self.lfn as u32 * DirEntry::SIZE as u32;
-        if n > self.offset {
-            return vol.dev.write_single(t, self.block);
+        if vol.ver.is_exfat() {
+            // Clear the "InUse" bit of every entry in the set.
+            return vol.walk_set(t, self.block, self.offset, self.lfn + 1, true, |_, mut b| {
+                b.write_u8(0, b.read_u8(0) & 0x7F)
+            });
         }
-        let mut i = self.offset.saturating_sub(n) as usize;
-        while i < self.offset as usize {
-            unsafe { write_bytes(t.as_mut_ptr().add(i), 0, DirEntry::SIZE) };
-            i += DirEntry::SIZE;
+        if self.offset as usize + DirEntry::SIZE > Block::SIZE {
+            return Err(DeviceError::BadData);
         }
-        let _ = vol.dev.write_single(t, self.block)?;
-        if let Some(v) = self.cluster {
-            let _ = vol.truncate(t, v)?;
+        // The LFN entries may be in the Blocks (or Cluster) before this
+        // entry, so we walk the whole set from the first LFN entry.
+        if self.lfn > 0 {
+            // Without the set start, only the ShortName entry would be
+            // removed and the LFN entries would be left as orphans.
+            if self.start.0 == 0 {
+                return Err(DeviceError::BadData);
+            }
+            return vol.walk_set(t, self.start.0, self.start.1, self.lfn + 1, true, |_, mut b| b.write_u8(0, 0xE5));
+        }
+        let _ = vol.dev.read_single(t, self.block)?;
+        t.write_u8(self.offset as usize, 0xE5);
+        vol.dev.write_single(t, self.block)
+    }
+    /// Point this entry at the data of the supplied entry and free the
+    /// Clusters this entry used before.
+    ///
+    /// The supplied entry should be unlinked first, so a power loss can only
+    /// lose Clusters and never leave two entries sharing them.
+    pub(super) fn replace_data<B: BlockDevice, C: CacheProvider>(&mut self, vol: &Volume<B, C>, src: &DirEntry, t: &mut Block) -> DevResult<()> {
+        let mut o = DirEntry::new(0, 0);
+        (o.cluster, o.size, o.flags) = (self.cluster, self.size, self.flags);
+        (self.cluster, self.size, self.flags) = (src.cluster, src.size, src.flags);
+        (self.modified, self.attrs) = (src.modified, self.attrs | 0x20);
+        let _ = self.sync(vol, t)?;
+        o.release(vol, t)
+    }
+    /// Free every Cluster except for the first one.
+    #[inline]
+    pub(super) fn shrink<B: BlockDevice, C: CacheProvider>(&self, vol: &Volume<B, C>, t: &mut Block) -> DevResult<()> {
+        match self.cluster {
+            Some(v) if self.is_contiguous() => vol.ex_release(t, v, self.size.div_ceil(vol.block.bytes()), true),
+            Some(v) => vol.truncate(t, v),
+            None => Ok(()),
         }
-        Ok(())
     }
     #[inline]
-    pub(super) fn allocate<B: BlockDevice>(&mut self, vol: &Volume<B>, t: &mut Block) -> DevResult<()> {
+    pub(super) fn allocate<B: BlockDevice, C: CacheProvider>(&mut self, vol: &Volume<B, C>, t: &mut Block) -> DevResult<()> {
         self.cluster = Some(vol.allocate(t, None, false)?);
         Ok(())
     }
     #[inline]
-    pub(super) fn sync(&self, dev: &Storage<impl BlockDevice>, t: &mut Block, f: bool) -> DevResult<()> {
-        let _ = dev.read_single(t, self.block)?;
+    pub(super) fn sync<B: BlockDevice, C: CacheProvider>(&self, vol: &Volume<B, C>, t: &mut Block) -> DevResult<()> {
+        if vol.ver.is_exfat() {
+            return vol.ex_update(t, self.block, self.offset, self.lfn + 1, |i, mut b| match i {
+                0 => {
+                    b.write_u16(4, self.attrs as u16);
+                    time_write(&self.created, 8, b);
+                    time_write(&self.modified, 12, b);
+                    time_write(&self.accessed, 16, b);
+                },
+                1 => self.write_ex_stream(b),
+                _ => (),
+            });
+        }
+        let _ = vol.mark_dirty()?;
+        let _ = vol.dev.read_single(t, self.block)?;
         if self.offset as usize > Block::SIZE {
             return Err(DeviceError::BadData);
         }
-        self.write_entry(f, unsafe { t.get_unchecked_mut(self.offset as usize..) });
-        dev.write_single(t, self.block)
+        self.write_entry(vol.ver.is_fat32(), unsafe { t.get_unchecked_mut(self.offset as usize..) });
+        vol.dev.write_single(t, self.block)
+    }
+
+    #[inline]
+    fn release<B: BlockDevice, C: CacheProvider>(&self, vol: &Volume<B, C>, t: &mut Block) -> DevResult<()> {
+        match self.cluster {
+            Some(v) if self.is_contiguous() => vol.ex_release(t, v, self.size.div_ceil(vol.block.bytes()).max(1), false),
+            Some(v) => vol.release(t, v),
+            None => Ok(()),
+        }
+    }
+    fn write_ex_stream(&self, mut b: &mut [u8]) {
+        // 0x1 - General Secondary Flags
+        // 0x8 - Valid Data Length
+        // 0x14 - First Cluster
+        // 0x18 - Data Length
+        let c = self.cluster.map_or(0, |v| *v);
+        b.write_u8(1, if c > 0 { 0x1 } else { 0 } | (self.flags & DirEntry::NO_CHAIN));
+        b.write_u32(8, self.size);
+        b.write_u32(12, 0);
+        b.write_u32(20, c);
+        b.write_u32(24, self.size);
+        b.write_u32(28, 0);
+    }
+}
+impl ExSet {
+    #[inline]
+    const fn new() -> ExSet {
+        ExSet {
+            sum:   0u16,
+            want:  0u16,
+            high:  0u16,
+            left:  0u8,
+            units: 0u8,
+        }
     }
 }
 impl DirEntryFull {
     #[inline]
-    pub(super) fn new() -> DirEntryFull {
+    pub(super) fn new(lfn: LongNamePtr) -> DirEntryFull {
         DirEntryFull {
-            lfn:   Cache::lfn(),
+            lfn,
+            start: (0u32, 0u32),
+            pos:   LongName::SIZE as u16,
+            low:   0u16,
             sum:   0u8,
+            count: 0u8,
+            set:   ExSet::new(),
             entry: DirEntry::new(0, 0),
         }
     }
@@ -391,31 +617,39 @@ impl DirEntryFull {
         self.entry()
     }
     #[inline]
-    pub fn into_dir<'a, B: BlockDevice>(&mut self, vol: &'a Volume<B>) -> DevResult<Directory<'a, B>> {
+    pub fn into_dir<'a, B: BlockDevice, C: CacheProvider>(&mut self, vol: &'a Volume<B, C>) -> DevResult<Directory<'a, B, C>> {
         if !self.is_directory() {
             return Err(DeviceError::NotADirectory);
         }
         Ok(Directory::new(self.entry(), vol))
     }
     #[inline]
-    pub fn into_file<'a, B: BlockDevice>(&mut self, vol: &'a Volume<B>, mode: u8) -> DevResult<File<'a, B>> {
+    pub fn into_file<'a, B: BlockDevice, C: CacheProvider>(&mut self, vol: &'a Volume<B, C>, mode: u8) -> DevResult<File<'a, B, Safe, C>> {
         if !Mode::is_mode_valid(mode) {
             return Err(DeviceError::InvalidOptions);
         }
         if !self.is_file() {
             return Err(DeviceError::NotAFile);
         }
-        Ok(File::new(self.entry(), mode, vol))
+        File::new(self.entry(), mode, vol)
     }
 
+    #[inline]
+    pub(super) fn lfn_mut(&mut self) -> &mut LongName {
+        &mut self.lfn
+    }
     #[inline]
     pub(super) fn reset(&mut self) {
         self.lfn.reset();
-        (self.sum, self.entry.lfn) = (0, 0);
+        (self.sum, self.count, self.entry.lfn, self.set.left) = (0, 0, 0, 0);
+        (self.pos, self.low) = (LongName::SIZE as u16, 0);
     }
     #[inline]
-    pub(super) fn fill(&mut self, b: &[u8]) {
-        self.sum = self.lfn.lfn(b)
+    pub(super) fn fill(&mut self, b: &[u8], block: u32, offset: u32) {
+        if b.read_u8(0) & 0x40 != 0 {
+            (self.count, self.start) = (b.read_u8(0) & 0x1F, (block, offset));
+        }
+        self.sum = self.lfn.lfn(b, &mut self.pos, &mut self.low)
     }
     #[inline]
     pub(super) fn entry(&mut self) -> DirEntry {
@@ -434,25 +668,118 @@ impl DirEntryFull {
         } else {
             ClusterIndex::new(b.read_u16(26) as u32)
         };
-        self.entry.lfn = self.lfn.lfn_size();
+        self.lfn.lfn_finish(self.pos);
+        self.entry.lfn = if self.pos < LongName::SIZE as u16 { self.count } else { 0 };
+        (self.pos, self.low, self.count) = (LongName::SIZE as u16, 0, 0);
         self.entry.name.fill_inner(b);
-        if self.sum != self.entry.name.checksum() {
+        if self.entry.lfn == 0 || self.sum != self.entry.name.checksum() {
             self.lfn.reset();
             self.entry.lfn = 0;
         }
         self.entry.block = block;
         self.entry.offset = offset;
+        self.entry.start = if self.entry.lfn > 0 { self.start } else { (block, offset) };
         self.entry.size = b.read_u32(28);
         self.entry.attrs = b.read_u8(11);
         self.entry.created = time_read(b.read_u16(16), b.read_u16(14));
+        self.entry.accessed = time_read(b.read_u16(18), 0);
         self.entry.modified = time_read(b.read_u16(24), b.read_u16(22));
         self.entry.cluster = if v.is_none() && self.entry.attrs & 0x10 == 0x10 { None } else { v };
     }
+    /// Load an exFAT entry, returns None at the end of the Directory and true
+    /// when a complete entry set with a valid checksum was read.
+    pub(super) fn load_ex(&mut self, b: &[u8], block: u32, offset: u32) -> Option<bool> {
+        let t = b.read_u8(0);
+        if t == 0 {
+            return None;
+        }
+        // Unused entries and any other primary entry ends the current set.
+        if t & 0x80 == 0 || (t & 0x40 == 0 && t != TYPE_FILE) {
+            self.set.left = 0;
+            return Some(false);
+        }
+        if t == TYPE_FILE {
+            self.reset();
+            // 0x1 - Secondary Count
+            // 0x2 - Set Checksum
+            // 0x4 - File Attributes
+            // 0x8 - Create Timestamp
+            // 0xC - Last Modified Timestamp
+            // 0x10 - Last Accessed Timestamp
+            let n = b.read_u8(1);
+            if n < 2 {
+                return Some(false);
+            }
+            (self.set.left, self.set.want, self.set.high) = (n, b.read_u16(2), 0);
+            self.set.sum = checksum(0, b, true);
+            self.entry.lfn = n;
+            self.entry.block = block;
+            self.entry.offset = offset;
+            self.entry.start = (block, offset);
+            self.entry.attrs = b.read_u16(4) as u8;
+            self.entry.created = time_read(b.read_u16(10), b.read_u16(8));
+            self.entry.accessed = time_read(b.read_u16(18), b.read_u16(16));
+            self.entry.modified = time_read(b.read_u16(14), b.read_u16(12));
+            return Some(false);
+        }
+        if self.set.left == 0 {
+            return Some(false);
+        }
+        (self.set.sum, self.set.left) = (checksum(self.set.sum, b, false), self.set.left - 1);
+        match t {
+            TYPE_STREAM => {
+                // 0x1 - General Secondary Flags
+                // 0x3 - Name Length
+                // 0x14 - First Cluster
+                // 0x18 - Data Length
+                self.set.units = b.read_u8(3);
+                self.entry.flags = DirEntry::EXFAT | (b.read_u8(1) & DirEntry::NO_CHAIN);
+                self.entry.cluster = ClusterIndex::new(b.read_u32(20));
+                // NOTE(sf): Files over 4GB are capped, as we only use 32bit sizes.
+                self.entry.size = if b.read_u32(28) > 0 { FILE_MAX_SIZE } else { b.read_u32(24) };
+            },
+            TYPE_NAME => {
+                let (mut v, mut n) = ([0u16; 16], 0usize);
+                if self.set.high > 0 {
+                    (v[0], n) = (self.set.high, 1);
+                }
+                let c = self.set.units.min(15);
+                for i in 0..c as usize {
+                    v[n] = b.read_u16(2 + (i * 2));
+                    n += 1;
+                }
+                self.set.units -= c;
+                // Keep a split surrogate pair for the next name entry.
+                self.set.high = if n > 0 && self.set.units > 0 && matches!(v[n - 1], 0xD800..=0xDBFF) {
+                    n -= 1;
+                    v[n]
+                } else {
+                    0
+                };
+                self.lfn.push_utf16(&v[0..n]);
+            },
+            _ => (),
+        }
+        if self.set.left > 0 {
+            return Some(false);
+        }
+        if self.set.sum != self.set.want {
+            self.lfn.reset();
+            return Some(false);
+        }
+        self.entry.name.fill(self.lfn.as_bytes());
+        Some(true)
+    }
 }
-impl<'a, B: BlockDevice> File<'a, B> {
+impl<'a, B: BlockDevice, C: CacheProvider> File<'a, B, Safe, C> {
     #[inline]
-    pub(super) fn new(file: DirEntry, mode: u8, vol: &'a Volume<'a, B>) -> File<'a, B, Safe> {
-        File {
+    pub(super) fn new(file: DirEntry, mode: u8, vol: &'a Volume<'a, B, C>) -> DevResult<File<'a, B, Safe, C>> {
+        let w = mode & (Mode::WRITE | Mode::APPEND | Mode::TRUNCATE) != 0;
+        if w {
+            let _ = vol.writable()?;
+        }
+        let _ = vol.open.open(file.block, file.offset, w)?;
+        Ok(File {
             last: file.index(),
             vol,
             file,
@@ -460,15 +787,24 @@ impl<'a, B: BlockDevice> File<'a, B> {
             pos: 0u32,
             short: 0u32,
             _p: PhantomData,
-        }
+        })
     }
 }
-impl<'a, B: BlockDevice> Reader<'a, B> {
+impl<'a, B: BlockDevice, C: CacheProvider> Reader<'a, B, C> {
     #[inline]
-    pub fn into_file(self) -> File<'a, B, Unsafe> {
+    pub fn into_file(self) -> File<'a, B, Unsafe, C> {
         drop(self.buf);
         self.f
     }
+    #[inline]
+    pub fn cursor(&self) -> usize {
+        self.f.cursor()
+    }
+    /// See 'File.seek_at'.
+    #[inline]
+    pub fn seek_at(&mut self, base: u32, off: i64) -> DevResult<u64> {
+        self.f.seek_at(base, off)
+    }
     /// Similar to 'File.read' but does NOT re-read nearby chunks inside the
     /// same Block when calling 'read' multiple times.
     ///
@@ -479,6 +815,9 @@ impl<'a, B: BlockDevice> Reader<'a, B> {
         }
         let (mut p, t) = (0, b.len());
         let (d, mut c) = (&mut *self.buf, BlockCache::new());
+        if self.f.touch(d)? {
+            self.bp = u32::MAX;
+        }
         while p < t && self.f.pos < self.f.file.size {
             let (i, o, a) = match self.f.data(d, &mut c) {
                 Err(DeviceError::EndOfFile) => return Ok(p),
@@ -501,75 +840,88 @@ impl<'a, B: BlockDevice> Reader<'a, B> {
         Ok(p)
     }
 }
-impl<'a, B: BlockDevice> Directory<'a, B> {
+impl<'a, B: BlockDevice, C: CacheProvider> Directory<'a, B, C> {
     #[inline]
-    pub(super) fn new(dir: DirEntry, vol: &'a Volume<'a, B>) -> Directory<'a, B> {
+    pub(super) fn new(dir: DirEntry, vol: &'a Volume<'a, B, C>) -> Directory<'a, B, C> {
         Directory { vol, dir }
     }
 
     #[inline]
-    pub fn volume(&self) -> &Volume<'a, B> {
+    pub fn volume(&self) -> &Volume<'a, B, C> {
         self.vol
     }
+    #[inline]
+    pub fn into_entry(self) -> DirEntry {
+        self.dir
+    }
     /// Set `force` to true to recursively delete this Directory and it's
     /// contents. Otherwise a `NonEmptyDirectory` error will be returned for
     /// Directories that contain Files or other Directories.
+    ///
+    /// Deleting the root Directory only removes it's contents.
     #[inline]
     pub fn delete(self, force: bool) -> DevResult<()> {
-        let mut b = Cache::block_a();
-        delete(self.vol, &self.dir, &mut b, force)
+        let mut b = self.vol.dev.block_a()?;
+        let _ = delete_tree(self.vol, self.dir, &mut b, force, false)?;
+        Ok(())
+    }
+    /// Returns what would be removed by a forced delete of this Directory,
+    /// without removing anything.
+    #[inline]
+    pub fn delete_dry_run(&self) -> DevResult<DeleteReport> {
+        let mut b = self.vol.dev.block_a()?;
+        delete_tree(self.vol, self.dir.clone(), &mut b, true, true)
     }
     #[inline]
-    pub fn list(&self) -> DevResult<DirectoryIndex<'a, B>> {
+    pub fn list(&self) -> DevResult<DirectoryIndex<'a, B, C>> {
         // Safe as we're the entry and valid.
         unsafe { self.vol.list_entry(Some(&self.dir)) }
     }
     #[inline]
-    pub fn file(&'a self, name: impl AsRef<str>, mode: u8) -> DevResult<File<'a, B>> {
+    pub fn file(&'a self, name: impl AsRef<str>, mode: u8) -> DevResult<File<'a, B, Safe, C>> {
         // Safe as we're the entry and valid.
         unsafe { self.vol.file_entry(name, Some(&self.dir), mode) }
     }
     #[inline]
-    pub fn dir(&'a self, name: impl AsRef<str>, create: bool) -> DevResult<Directory<'a, B>> {
+    pub fn dir(&'a self, name: impl AsRef<str>, create: bool) -> DevResult<Directory<'a, B, C>> {
         // Safe as we're the entry and valid.
         unsafe { self.vol.dir_entry(name, Some(&self.dir), create) }
     }
-
-    #[inline]
-    pub(super) fn cluster(&self) -> Cluster {
-        self.dir.cluster
-    }
 }
-impl<'a, B: BlockDevice> File<'a, B, Safe> {
+impl<'a, B: BlockDevice, C: CacheProvider> File<'a, B, Safe, C> {
     /// Remove the locking requirement for file Read/Writes.
     ///
     /// Use only if sure that reads/writes will not happen on the same or
     /// multiple files by different cores at the same!
     #[inline]
-    pub unsafe fn into_unsafe(self) -> File<'a, B, Unsafe> {
+    pub unsafe fn into_unsafe(self) -> File<'a, B, Unsafe, C> {
         unsafe { transmute(self) }
     }
     /// Transform the File into a high-speed Reader with better caching
     /// and locking mechanisms.
+    ///
+    /// The Reader keeps 'block_a' claimed until it's dropped, with a
+    /// LocalCache any other Volume call made meanwhile returns 'FileInUse'.
     #[inline]
-    pub unsafe fn into_reader(self) -> DevResult<Reader<'a, B>> {
+    pub unsafe fn into_reader(self) -> DevResult<Reader<'a, B, C>> {
         if !self.is_readable() {
             return Err(DeviceError::NotReadable);
         }
+        let b = self.vol.dev.block_a()?;
         Ok(Reader {
             f:   unsafe { self.into_unsafe() },
             bp:  u32::MAX,
-            buf: Cache::block_a(),
+            buf: b,
         })
     }
 }
-impl<'a, B: BlockDevice> File<'a, B, Unsafe> {
+impl<'a, B: BlockDevice, C: CacheProvider> File<'a, B, Unsafe, C> {
     #[inline]
-    pub fn into_safe(self) -> File<'a, B, Safe> {
+    pub fn into_safe(self) -> File<'a, B, Safe, C> {
         unsafe { transmute(self) }
     }
 }
-impl<'a, B: BlockDevice, S: FileSync> File<'a, B, S> {
+impl<'a, B: BlockDevice, S: FileSync, C: CacheProvider> File<'a, B, S, C> {
     #[inline]
     pub fn mode(&self) -> u8 {
         self.mode
@@ -599,42 +951,62 @@ impl<'a, B: BlockDevice, S: FileSync> File<'a, B, S> {
         self.file.cluster.is_some_and(|v| v.is_valid())
     }
     #[inline]
-    pub fn delete(self) -> DevResult<()> {
-        let mut b = S::cache();
-        self.file.delete(self.vol, &mut b)
+    pub fn delete(mut self) -> DevResult<()> {
+        // Drop our own handle first, any others will still block the delete.
+        let _ = self.flush()?;
+        self.vol.open.close(self.file.block, self.file.offset);
+        let (e, v) = (replace(&mut self.file, DirEntry::new(0, 0)), self.vol);
+        forget(self);
+        let mut b = S::cache(v.dev)?;
+        e.delete(v, &mut b)
     }
     #[inline]
-    pub fn volume(&self) -> &Volume<'a, B> {
+    pub fn volume(&self) -> &Volume<'a, B, C> {
         &self.vol
     }
     #[inline]
     pub fn close(mut self) -> DevResult<()> {
         self.flush()
     }
+    /// Flush the File and return it's entry.
+    #[inline]
+    pub fn into_entry(mut self) -> DevResult<DirEntry> {
+        let _ = self.flush()?;
+        self.vol.open.close(self.file.block, self.file.offset);
+        let e = replace(&mut self.file, DirEntry::new(0, 0));
+        forget(self);
+        Ok(e)
+    }
     #[inline]
     pub fn flush(&mut self) -> DevResult<()> {
         if !self.is_dirty() {
             return Ok(());
         }
-        let mut b = S::cache();
+        if let Some(t) = self.vol.now() {
+            self.file.modified = t;
+        }
+        let mut b = S::cache(self.vol.dev)?;
         let _ = self.vol.sync(&mut b)?;
-        self.file.sync(self.vol.dev, &mut b, self.vol.ver.is_fat32())
+        let _ = self.file.sync(self.vol, &mut b)?;
+        self.mode &= 0x7F;
+        Ok(())
     }
     pub fn write(&mut self, b: &[u8]) -> DevResult<usize> {
-        if !self.is_writeable() {
+        if !self.is_writeable() || self.vol.ro {
             return Err(DeviceError::NotWritable);
         }
         if b.is_empty() {
             return Ok(0);
         }
         self.mode |= 0x80;
-        let mut d = S::cache();
+        let _ = self.vol.mark_dirty()?;
+        let mut d = S::cache(self.vol.dev)?;
         if !self.is_allocated() {
             self.file.cluster = Some(self.vol.allocate(&mut d, None, false)?);
             d.clear();
         }
         let c = self.file.cluster.ok_or(DeviceError::Write)?;
-        if self.last.lt(&c) {
+        if self.last.is_empty() || self.last.lt(&c) {
             (self.last, self.short) = (c, 0);
         }
         let t = b.len().min((FILE_MAX_SIZE - self.pos) as usize);
@@ -643,7 +1015,17 @@ impl<'a, B: BlockDevice, S: FileSync> File<'a, B, S> {
             let (i, o, a) = match self.data(&mut d, &mut c) {
                 Ok(v) => v,
                 Err(DeviceError::EndOfFile) => {
-                    let _ = self.vol.allocate(&mut d, self.file.cluster, false)?;
+                    if self.file.is_contiguous() {
+                        // The run has to be moved to a FAT chain before it can
+                        // be extended.
+                        let _ = self.vol.ex_unchain(
+                            &mut d,
+                            self.file.index(),
+                            self.file.size.div_ceil(self.vol.block.bytes()).max(1),
+                        )?;
+                        self.file.flags &= !DirEntry::NO_CHAIN;
+                    }
+                    let _ = self.vol.allocate(&mut d, Some(self.last), false)?;
                     self.data(&mut d, &mut c).or(Err(DeviceError::Write))?
                 },
                 Err(e) => return Err(e),
@@ -659,12 +1041,52 @@ impl<'a, B: BlockDevice, S: FileSync> File<'a, B, S> {
             unsafe { copy_nonoverlapping(b.as_ptr().add(p), d.as_mut_ptr().add(o), n) };
             let _ = self.vol.dev.write_single(&d, i)?;
             self.pos = self.pos.saturating_add(n as u32);
-            self.file.size = self.file.size.saturating_add(n as u32);
+            self.file.size = self.file.size.max(self.pos);
             p = p.saturating_add(n);
         }
         self.file.attrs |= 0x20;
         Ok(p)
     }
+    /// Set the length of the File, freeing or allocating Clusters as needed.
+    ///
+    /// When growing, the File is started on a contiguous run of free Clusters
+    /// if it had none, otherwise new Clusters are taken from right after the
+    /// current end when possible. If 'zero' is true the new space is filled
+    /// with zeros, otherwise it contains whatever was on the disk before.
+    ///
+    /// The cursor is moved to the new end if it was past it.
+    pub fn set_len(&mut self, len: usize, zero: bool) -> DevResult<()> {
+        if !self.is_writeable() {
+            return Err(DeviceError::NotWritable);
+        }
+        let n: u32 = len.try_into().or(Err(DeviceError::Overflow))?;
+        if n == self.file.size {
+            return Ok(());
+        }
+        self.mode |= 0x80;
+        if n < self.file.size {
+            let _ = self.shrink_to(n)?;
+        } else {
+            let (p, s) = (self.pos, self.file.size);
+            let _ = self.grow_to(n, false)?;
+            // 'grow_to' may have just allocated the first Cluster.
+            (self.short, self.last, self.file.size) = (0, self.index(), n);
+            if zero {
+                // Only the space past the old end needs zeroing, the new
+                // Clusters are not zeroed when allocated.
+                self.pos = s;
+                let r = self.zero_to(n);
+                self.pos = p;
+                if r.is_err() {
+                    self.file.size = s;
+                }
+                let _ = r?;
+            }
+        }
+        (self.short, self.last) = (0, self.index());
+        self.pos = self.pos.min(self.file.size);
+        Ok(())
+    }
     /// Does not save the file and keeps the current Cluster intact.
     /// To fully truncate a File entry, it must be opened with 'Mode::TRUNCATE'.
     #[inline]
@@ -687,7 +1109,8 @@ impl<'a, B: BlockDevice, S: FileSync> File<'a, B, S> {
             return Ok(0);
         }
         let (mut p, t, mut l) = (0usize, b.len(), u32::MAX);
-        let (mut d, mut c) = (S::cache(), BlockCache::new());
+        let (mut d, mut c) = (S::cache(self.vol.dev)?, BlockCache::new());
+        let _ = self.touch(&mut d)?;
         while p < t && self.pos < self.file.size {
             let (i, o, a) = match self.data(&mut d, &mut c) {
                 Err(DeviceError::EndOfFile) => return Ok(p),
@@ -712,35 +1135,215 @@ impl<'a, B: BlockDevice, S: FileSync> File<'a, B, S> {
     pub(super) fn zero(&mut self) {
         self.file.size = 0
     }
+    /// Update the last access date if the Volume has access times enabled
+    /// and it's a different day. Returns true if the entry was written.
+    #[inline]
+    fn touch(&mut self, scratch: &mut Block) -> DevResult<bool> {
+        if !self.vol.is_access_time() {
+            return Ok(false);
+        }
+        let t = match self.vol.now() {
+            Some(v) => v,
+            None => return Ok(false),
+        };
+        let a = &self.file.accessed;
+        if a.year == t.year && a.month == t.month && a.day == t.day {
+            return Ok(false);
+        }
+        self.file.accessed = t;
+        let _ = self.file.sync(self.vol, scratch)?;
+        Ok(true)
+    }
     #[inline]
     pub(super) fn seek_to_end(&mut self) {
         self.pos = self.file.size
     }
+    /// Move the cursor 'off' bytes from 'base', it can't be moved past the
+    /// end of the File.
+    ///
+    /// This is what the 'Seek' traits use, with 'base' being zero, the cursor
+    /// or the File size.
+    pub fn seek_at(&mut self, base: u32, off: i64) -> DevResult<u64> {
+        let r = if off >= 0 {
+            base.checked_add(off.try_into().or(Err(DeviceError::Overflow))?)
+                .ok_or(DeviceError::Overflow)?
+        } else {
+            base.saturating_sub(off.unsigned_abs().try_into().or(Err(DeviceError::Overflow))?)
+        };
+        if r > self.file.size {
+            return Err(DeviceError::InvalidIndex);
+        }
+        self.pos = r;
+        Ok(self.pos as u64)
+    }
+    /// Replace the contents of the File with the contents of 'src'.
+    ///
+    /// The File is grown to the size of 'src' before anything is copied, so
+    /// its Clusters are taken as a single run when there is one. The data is
+    /// then moved through 'buf' up to 'BlockBuffer::COUNT' Blocks at a time,
+    /// as long as both Files have them in the same Cluster.
+    ///
+    /// 'progress' is called with the bytes copied and the total before the
+    /// first transfer and after each one.
+    pub(super) fn copy_from<T: FileSync>(&mut self, src: &mut File<'_, B, T, C>, buf: &mut BlockBuffer, mut progress: impl FnMut(u32, u32)) -> DevResult<u32> {
+        if !self.is_writeable() {
+            return Err(DeviceError::NotWritable);
+        }
+        if !src.is_readable() {
+            return Err(DeviceError::NotReadable);
+        }
+        let t = src.file.size;
+        let _ = self.set_len(0, false)?;
+        let _ = self.set_len(t as usize, false)?;
+        (self.pos, src.pos) = (0, 0);
+        progress(0, t);
+        let w = buf.blocks_mut();
+        let (mut d, mut x, mut y, mut p) = (S::cache(self.vol.dev)?, BlockCache::new(), BlockCache::new(), 0u32);
+        while p < t {
+            let (i, a) = src.blocks_at(&mut d, &mut x)?;
+            let (j, b) = self.blocks_at(&mut d, &mut y)?;
+            let n = a.min(b).min(w.len() as u32).min((t - p).div_ceil(Block::SIZE as u32));
+            let v = unsafe { w.get_unchecked_mut(0..n as usize) };
+            let _ = src.vol.dev.read(v, i)?;
+            let _ = self.vol.dev.write(v, j)?;
+            let k = (n * Block::SIZE as u32).min(t - p);
+            (self.pos, src.pos, p) = (self.pos + k, src.pos + k, p + k);
+            progress(p, t);
+        }
+        self.file.attrs |= 0x20;
+        self.mode |= 0x80;
+        Ok(p)
+    }
 
+    /// Free the Clusters past the new size.
+    fn shrink_to(&mut self, n: u32) -> DevResult<()> {
+        let (c, mut d) = (self.vol.block.bytes(), S::cache(self.vol.dev)?);
+        let k = n.div_ceil(c);
+        match self.file.cluster {
+            Some(_) if k == 0 => {
+                let _ = self.file.release(self.vol, &mut d)?;
+                self.file.cluster = None;
+                self.file.flags &= !DirEntry::NO_CHAIN;
+            },
+            Some(v) if self.file.is_contiguous() => {
+                let _ = self.vol.ex_release(
+                    &mut d,
+                    unsafe { ClusterIndex::new_unchecked(*v + k - 1) },
+                    self.file.size.div_ceil(c).max(1) - (k - 1),
+                    true,
+                )?;
+            },
+            Some(v) => {
+                let (mut x, mut b) = (v, BlockCache::new());
+                for _ in 1..k {
+                    x = self.vol.next(&mut d, &mut b, x)?.ok_or(DeviceError::InvalidChain)?;
+                }
+                let _ = self.vol.truncate(&mut d, x)?;
+            },
+            None => (),
+        }
+        self.file.size = n;
+        Ok(())
+    }
+    /// Allocate Clusters until the chain can hold 'n' bytes, this does not
+    /// change the size.
+    fn grow_to(&mut self, n: u32, zero: bool) -> DevResult<()> {
+        let (c, mut d) = (self.vol.block.bytes(), S::cache(self.vol.dev)?);
+        let k = n.div_ceil(c);
+        // The chain can be longer than the size needs.
+        let (mut l, mut h) = match self.file.cluster {
+            Some(v) if self.file.is_contiguous() => {
+                let h = self.file.size.div_ceil(c).max(1);
+                (Some(unsafe { ClusterIndex::new_unchecked(*v + h - 1) }), h)
+            },
+            Some(v) => {
+                let (mut x, mut h, mut b) = (v, 1u32, BlockCache::new());
+                while let Some(y) = self.vol.next(&mut d, &mut b, x)? {
+                    (x, h) = (y, h + 1);
+                }
+                (Some(x), h)
+            },
+            None => (None, 0u32),
+        };
+        if h >= k {
+            return Ok(());
+        }
+        if self.file.is_contiguous() {
+            // The run has to be moved to a FAT chain before it can be extended.
+            let _ = self.vol.ex_unchain(&mut d, self.file.index(), h)?;
+            self.file.flags &= !DirEntry::NO_CHAIN;
+        }
+        if l.is_none() {
+            let s = self.vol.free_run(&mut d, k)?.map_or(2, |v| *v);
+            let v = self.vol.allocate_at(&mut d, None, s, zero)?;
+            (self.file.cluster, l, h) = (Some(v), Some(v), 1);
+        }
+        while h < k {
+            l = Some(self.vol.allocate(&mut d, l, zero)?);
+            h += 1;
+        }
+        Ok(())
+    }
+    /// Fill the File with zeros from the cursor up to 'n' in place, reading
+    /// only the Blocks that are partly kept.
+    fn zero_to(&mut self, n: u32) -> DevResult<()> {
+        let _ = self.vol.mark_dirty()?;
+        let (mut d, mut c) = (S::cache(self.vol.dev)?, BlockCache::new());
+        while self.pos < n {
+            let (i, o, a) = self.data(&mut d, &mut c)?;
+            let k = a.min((n - self.pos) as usize);
+            if k == Block::SIZE {
+                d.clear();
+            } else {
+                let _ = self.vol.dev.read_single(&mut d, i)?;
+                unsafe { write_bytes(d.as_mut_ptr().add(o), 0, k) };
+            }
+            let _ = self.vol.dev.write_single(&d, i)?;
+            self.pos += k as u32;
+        }
+        Ok(())
+    }
     fn data(&mut self, scratch: &mut Block, cache: &mut BlockCache) -> DevResult<(u32, usize, usize)> {
         if self.pos < self.short {
             (self.short, self.last) = (0, self.index());
         }
         let c = self.vol.block.bytes();
-        let n = self.pos.saturating_sub(self.short);
-        cache.clear();
-        for _ in 0..(n / c) {
-            self.last = self.vol.next(scratch, cache, self.last)?.ok_or(DeviceError::EndOfFile)?;
-            self.short += c;
+        if self.file.is_contiguous() {
+            // No FAT chain, so the Cluster can be calculated directly.
+            let n = self.pos / c;
+            if n >= self.file.size.div_ceil(c).max(1) {
+                return Err(DeviceError::EndOfFile);
+            }
+            (self.last, self.short) = (unsafe { ClusterIndex::new_unchecked(*self.index() + n) }, n * c);
+        } else {
+            let n = self.pos.saturating_sub(self.short);
+            cache.clear();
+            for _ in 0..(n / c) {
+                self.last = self.vol.next(scratch, cache, self.last)?.ok_or(DeviceError::EndOfFile)?;
+                self.short += c;
+            }
         }
         let i = self.vol.block_pos_at(self.last) + (self.pos.saturating_sub(self.short) / Block::SIZE as u32);
         let o = self.pos as usize % Block::SIZE;
         Ok((i, o, Block::SIZE - o))
     }
+    /// Returns the Block at the cursor and the count of Blocks left in its
+    /// Cluster. The cursor has to be on a Block boundary.
+    #[inline]
+    fn blocks_at(&mut self, scratch: &mut Block, cache: &mut BlockCache) -> DevResult<(u32, u32)> {
+        let (i, ..) = self.data(scratch, cache)?;
+        Ok((i, self.vol.block.blocks() - (self.pos.saturating_sub(self.short) / Block::SIZE as u32)))
+    }
 }
 
-impl<B: BlockDevice, S: FileSync> Drop for File<'_, B, S> {
+impl<B: BlockDevice, S: FileSync, C: CacheProvider> Drop for File<'_, B, S, C> {
     #[inline]
     fn drop(&mut self) {
         let _ = self.flush();
+        self.vol.open.close(self.file.block, self.file.offset);
     }
 }
-impl<B: BlockDevice, S: FileSync> Deref for File<'_, B, S> {
+impl<B: BlockDevice, S: FileSync, C: CacheProvider> Deref for File<'_, B, S, C> {
     type Target = DirEntry;
 
     #[inline]
@@ -748,33 +1351,26 @@ impl<B: BlockDevice, S: FileSync> Deref for File<'_, B, S> {
         &self.file
     }
 }
-impl<B: BlockDevice, S: FileSync> Seek<DeviceError> for File<'_, B, S> {
+#[cfg(feature = "pico")]
+impl<B: BlockDevice, S: FileSync, C: CacheProvider> Seek<DeviceError> for File<'_, B, S, C> {
+    #[inline]
     fn seek(&mut self, s: SeekFrom) -> Result<u64, Error> {
-        let r = match s {
-            SeekFrom::End(v) if v > 0 => return Err(Error::InvalidIndex),
-            SeekFrom::End(v) => self
-                .pos
-                .saturating_sub(v.unsigned_abs().try_into().or(Err(DeviceError::Overflow))?),
-            SeekFrom::Start(v) => v.try_into().or(Err(DeviceError::Overflow))?,
-            SeekFrom::Current(v) if v > 0 => self.pos.saturating_add(v.try_into().or(Err(DeviceError::Overflow))?),
-            SeekFrom::Current(v) => self
-                .pos
-                .saturating_sub(v.unsigned_abs().try_into().or(Err(DeviceError::Overflow))?),
-        };
-        if r > self.file.size {
-            return Err(Error::InvalidIndex);
-        }
-        self.pos = r;
-        Ok(self.pos as u64)
+        Ok(match s {
+            SeekFrom::End(v) => self.seek_at(self.file.size, v)?,
+            SeekFrom::Start(v) => self.seek_at(0, v.try_into().or(Err(DeviceError::Overflow))?)?,
+            SeekFrom::Current(v) => self.seek_at(self.pos, v)?,
+        })
     }
 }
-impl<B: BlockDevice, S: FileSync> Read<DeviceError> for File<'_, B, S> {
+#[cfg(feature = "pico")]
+impl<B: BlockDevice, S: FileSync, C: CacheProvider> Read<DeviceError> for File<'_, B, S, C> {
     #[inline]
     fn read(&mut self, b: &mut [u8]) -> Result<usize, Error> {
         Ok(self.read(b)?)
     }
 }
-impl<B: BlockDevice, S: FileSync> Write<DeviceError> for File<'_, B, S> {
+#[cfg(feature = "pico")]
+impl<B: BlockDevice, S: FileSync, C: CacheProvider> Write<DeviceError> for File<'_, B, S, C> {
     #[inline]
     fn flush(&mut self) -> Result<(), Error> {
         Ok(self.flush()?)
@@ -785,7 +1381,7 @@ impl<B: BlockDevice, S: FileSync> Write<DeviceError> for File<'_, B, S> {
     }
 }
 
-impl<B: BlockDevice> Deref for Reader<'_, B> {
+impl<B: BlockDevice, C: CacheProvider> Deref for Reader<'_, B, C> {
     type Target = DirEntry;
 
     #[inline]
@@ -793,19 +1389,40 @@ impl<B: BlockDevice> Deref for Reader<'_, B> {
         &self.f.file
     }
 }
-impl<B: BlockDevice> Seek<DeviceError> for Reader<'_, B> {
+#[cfg(feature = "pico")]
+impl<B: BlockDevice, C: CacheProvider> Seek<DeviceError> for Reader<'_, B, C> {
     #[inline]
     fn seek(&mut self, s: SeekFrom) -> Result<u64, Error> {
         self.f.seek(s)
     }
 }
-impl<B: BlockDevice> Read<DeviceError> for Reader<'_, B> {
+#[cfg(feature = "pico")]
+impl<B: BlockDevice, C: CacheProvider> Read<DeviceError> for Reader<'_, B, C> {
     #[inline]
     fn read(&mut self, b: &mut [u8]) -> Result<usize, Error> {
         Ok(self.read(b)?)
     }
 }
 
+impl Clone for DirEntry {
+    #[inline]
+    fn clone(&self) -> DirEntry {
+        DirEntry {
+            lfn:      self.lfn,
+            name:     self.name.clone(),
+            size:     self.size,
+            attrs:    self.attrs,
+            block:    self.block,
+            flags:    self.flags,
+            offset:   self.offset,
+            cluster:  self.cluster,
+            created:  self.created,
+            accessed: self.accessed,
+            modified: self.modified,
+            start:    self.start,
+        }
+    }
+}
 impl PartialEq<str> for DirEntry {
     #[inline]
     fn eq(&self, other: &str) -> bool {
@@ -863,11 +1480,22 @@ impl PartialEq<str> for DirEntryFull {
 impl PartialEq<[u8]> for DirEntryFull {
     #[inline]
     fn eq(&self, other: &[u8]) -> bool {
-        if self.entry.lfn == 0 { self.entry.name.eq(other) } else { self.lfn.eq(other) }
+        if self.entry.flags & DirEntry::EXFAT != 0 {
+            // exFAT names are case insensitive.
+            self.lfn.eq_ignore_case(other)
+        } else if self.entry.lfn == 0 {
+            // Only match names that are valid 8.3 names, so a long name
+            // doesn't match the "~1" short name of another entry.
+            is_short(other, true) && self.entry.name.eq(other)
+        } else {
+            // FAT names are also case insensitive, but the ShortName of an
+            // entry with a LFN can still be used.
+            self.lfn.eq_ignore_case(other) || (is_short(other, true) && self.entry.name.eq(other))
+        }
     }
 }
 
-impl<B: BlockDevice> Deref for Directory<'_, B> {
+impl<B: BlockDevice, C: CacheProvider> Deref for Directory<'_, B, C> {
     type Target = DirEntry;
 
     #[inline]
@@ -878,14 +1506,14 @@ impl<B: BlockDevice> Deref for Directory<'_, B> {
 
 impl FileSync for Safe {
     #[inline]
-    fn cache() -> BlockPtr {
-        Cache::block_a()
+    fn cache<B: BlockDevice, C: CacheProvider>(dev: &Storage<B, C>) -> DevResult<BlockPtr> {
+        dev.block_a()
     }
 }
 impl FileSync for Unsafe {
     #[inline]
-    fn cache() -> BlockPtr {
-        unsafe { Cache::block_a_nolock() }
+    fn cache<B: BlockDevice, C: CacheProvider>(dev: &Storage<B, C>) -> DevResult<BlockPtr> {
+        Ok(unsafe { dev.block_a_nolock() })
     }
 }
 
@@ -894,11 +1522,11 @@ fn time_read(a: u16, b: u16) -> Time {
     unsafe {
         Time {
             year:    (a.unchecked_shr(9) + 0xA) + 0x7B2u16,
-            month:   Month::from((a.unchecked_shr(5) & 0xF) as u8 + 1),
+            month:   Month::from((a.unchecked_shr(5) & 0xF) as u8),
             day:     (a & 0x1F) as u8,
             hours:   (b.unchecked_shr(11) & 0x1F) as u8,
             mins:    (b.unchecked_shr(5) & 0x3F) as u8,
-            secs:    (b.unchecked_shl(1) & 0x3F) as u8,
+            secs:    (b & 0x1F).unchecked_shl(1) as u8,
             weekday: Weekday::None,
         }
     }
@@ -909,34 +1537,9 @@ fn time_write(t: &Time, pos: usize, mut b: &mut [u8]) {
         ((t.hours as u16).unchecked_shl(11) & 0xF800) | ((t.mins as u16).unchecked_shl(5) & 0x7E0) | (((t.secs as u16) / 2) & 0x1F)
     });
     b.write_u16(pos + 2, unsafe {
-        ((t.year.saturating_sub(0x7B2)).saturating_sub(10).unchecked_shl(9) & 0xFE00) | ((t.month as u16 + 1).unchecked_shl(5) & 0x1E0) | ((t.day as u16 + 1) & 0x1F)
+        ((t.year.saturating_sub(0x7B2)).saturating_sub(10).unchecked_shl(9) & 0xFE00) | ((t.month as u16).unchecked_shl(5) & 0x1E0) | (t.day as u16 & 0x1F)
     });
 }
-fn delete<'a, B: BlockDevice>(vol: &'a Volume<'a, B>, dir: &DirEntry, t: &mut Block, force: bool) -> DevResult<()> {
-    // NOTE(sf): This works at deleting recursively for directories. However,
-    //           it sometimes can be buggy just due to how the FAT FS is
-    //           structured.
-    for e in unsafe { vol.list_entry(Some(dir))? } {
-        if !force {
-            return Err(DeviceError::NonEmptyDirectory);
-        }
-        let v = e?;
-        if v.is_root_or_parent() {
-            // NOTE(sf): Usually this is the start of the Block, so we
-            //           should be good to stop here to prevent deleting
-            //           other things.
-            break;
-        }
-        if v.is_directory() {
-            let _ = delete(vol, &v, t, force)?;
-        } else {
-            let _ = v.delete(vol, t)?;
-        }
-    }
-    let _ = dir.delete(vol, t)?;
-    vol.truncate(t, dir.index())
-}
-
 pub mod state {
     pub struct Safe;
     pub struct Unsafe;
@@ -22,14 +22,24 @@
 extern crate core;
 
 use core::clone::Clone;
+use core::cmp::{Ord, Ordering};
+use core::convert::AsRef;
 use core::iter::{IntoIterator, Iterator};
 use core::marker::Copy;
-use core::ops::FnMut;
+use core::ops::{Deref, FnMut};
 use core::option::Option::{self, None, Some};
 use core::result::Result::{Err, Ok};
 
 use crate::Slice;
-use crate::fs::{Block, BlockCache, BlockDevice, BlockPtr, Cache, Cluster, ClusterIndex, DevResult, DirEntry, DirEntryFull, DirEntryPtr, Directory, Volume};
+use crate::fs::{Block, BlockCache, BlockDevice, BlockPtr, CacheProvider, Cluster, ClusterIndex, DevResult, DeviceError, DirEntry, DirEntryFull, DirEntryPtr, Directory, LongName, SharedCache, Volume};
+
+const DEPTH: usize = 0x10usize;
+
+pub enum SortBy {
+    Name,
+    Size,
+    Modified,
+}
 
 pub struct Range {
     sel:   [RangeIndex; 10], // Contagious free Entries found
@@ -42,21 +52,36 @@ pub struct RangeIter {
     range: Range,
 }
 pub struct RangeIndex(u32, u32, u32); // Stores Block, Cluster, Entry
-pub struct DirectoryIndex<'a, B: BlockDevice> {
+pub struct DirectoryIndex<'a, B: BlockDevice, C: CacheProvider = SharedCache> {
     buf:     BlockPtr,
     val:     DirEntryFull,
-    vol:     &'a Volume<'a, B>,
+    vol:     &'a Volume<'a, B, C>,
+    run:     Option<u32>,
     block:   u32,
     cache:   BlockCache,
     entry:   u32,
     blocks:  u32,
     cluster: ClusterIndex,
 }
-pub struct DirectoryIter<'a, B: BlockDevice>(DirectoryIndex<'a, B>);
-pub struct DirectoryIterMut<'b, 'a: 'b, B: BlockDevice>(&'b mut DirectoryIndex<'a, B>);
+pub struct NamedEntry {
+    name:  LongName,
+    entry: DirEntry,
+}
+pub struct DirectoryIter<'a, B: BlockDevice, C: CacheProvider = SharedCache>(DirectoryIndex<'a, B, C>);
+pub struct DirectoryIterMut<'b, 'a: 'b, B: BlockDevice, C: CacheProvider = SharedCache>(&'b mut DirectoryIndex<'a, B, C>);
 
 pub type RangeEntry = (u32, u32, bool, usize);
 
+// Saved position of a parent Directory while walking.
+struct Position {
+    run:     Option<u32>,
+    block:   u32,
+    entry:   u32,
+    state:   u32,
+    blocks:  u32,
+    cluster: ClusterIndex,
+}
+
 impl Range {
     #[inline]
     pub fn blocks(&self) -> u8 {
@@ -123,19 +148,68 @@ impl RangeIndex {
         (self.0, self.1, self.2) = (c, b, e);
     }
 }
-impl<'b, 'a: 'b, B: BlockDevice> DirectoryIndex<'a, B> {
+impl Position {
+    #[inline]
+    const fn new() -> Position {
+        Position {
+            run:     None,
+            block:   0u32,
+            entry:   0u32,
+            state:   0u32,
+            blocks:  0u32,
+            cluster: ClusterIndex::EMPTY,
+        }
+    }
+}
+impl NamedEntry {
+    #[inline]
+    pub const fn empty() -> NamedEntry {
+        NamedEntry {
+            name:  LongName::empty(),
+            entry: DirEntry::new(0, 0),
+        }
+    }
+
+    #[inline]
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+    #[inline]
+    pub fn entry(&self) -> &DirEntry {
+        &self.entry
+    }
+    #[inline]
+    pub fn into_entry(self) -> DirEntry {
+        self.entry
+    }
+
+    #[inline]
+    fn cmp(&self, v: &DirEntryFull, by: &SortBy) -> Ordering {
+        match by {
+            SortBy::Name => self
+                .name
+                .as_bytes()
+                .iter()
+                .map(u8::to_ascii_lowercase)
+                .cmp(v.name().as_bytes().iter().map(u8::to_ascii_lowercase)),
+            SortBy::Size => self.entry.size().cmp(&v.size()),
+            SortBy::Modified => self.entry.modified().into_seconds().cmp(&v.modified().into_seconds()),
+        }
+    }
+}
+impl<'b, 'a: 'b, B: BlockDevice, C: CacheProvider> DirectoryIndex<'a, B, C> {
     /// This is the preferable function to use as it can be reset to save
     /// space in memory.
     ///
     /// This also allows the usage of [`DirEntryPtr`] which contains the full
     /// file name as a LFN.
     #[inline]
-    pub fn into_iter_mut(&'b mut self) -> DirectoryIterMut<'b, 'a, B> {
+    pub fn into_iter_mut(&'b mut self) -> DirectoryIterMut<'b, 'a, B, C> {
         DirectoryIterMut(self)
     }
     #[inline]
-    pub fn reset(&mut self, dir: &Directory<'a, B>) -> DevResult<()> {
-        unsafe { self.reset_cluster(dir.cluster()) }
+    pub fn reset(&mut self, dir: &Directory<'a, B, C>) -> DevResult<()> {
+        unsafe { self.reset_entry(Some(&**dir)) }
     }
     /// This function call cannot be "shortcut" stopped. Use "find" if stoppage
     /// is needed.
@@ -162,29 +236,125 @@ impl<'b, 'a: 'b, B: BlockDevice> DirectoryIndex<'a, B> {
         }
         Ok(None)
     }
+    /// Walk this Directory and every sub-Directory depth first, up to 16
+    /// levels deep. The closure is called with every entry and it's depth,
+    /// starting at zero, and can return `true` to stop the walk.
+    ///
+    /// The index is left at an undefined position and must be reset before
+    /// being used again.
+    #[inline]
+    pub fn walk(&mut self, mut func: impl FnMut(&DirEntryFull, usize) -> bool) -> DevResult<()> {
+        let _ = self.walk_inner(1, |e, n, _| (func(e, n), 1))?;
+        Ok(())
+    }
+    /// Walk this Directory and call the closure with every entry that has a
+    /// path (relative to this Directory) that matches the glob pattern. The
+    /// closure can return `true` to stop the walk.
+    ///
+    /// Patterns are split into path segments by '/', which can contain the
+    /// '*' and '?' wildcards. A '**' segment matches zero or more Directories,
+    /// so "*.tga" only matches entries in this Directory while "**/*.tga"
+    /// matches entries in every sub-Directory. Matching ignores ASCII case.
+    ///
+    /// Sub-Directories are only read if they can match the pattern. The index
+    /// is left at an undefined position and must be reset before being used
+    /// again.
+    pub fn glob(&mut self, pattern: impl AsRef<str>, mut func: impl FnMut(&DirEntryFull) -> bool) -> DevResult<()> {
+        let p = pattern.as_ref();
+        let n = p.split('/').filter(|v| !v.is_empty()).count();
+        if n == 0 || n > 30 {
+            return Err(DeviceError::InvalidOptions);
+        }
+        let _ = self.walk_inner(glob_close(p, 1), |e, _, s| {
+            let v = glob_step(p, s, e.name().as_bytes());
+            if v & unsafe { 1u32.unchecked_shl(n as u32) } != 0 && func(e) {
+                return (true, 0);
+            }
+            (false, v & unsafe { 1u32.unchecked_shl(n as u32) - 1 })
+        })?;
+        Ok(())
+    }
+    /// Fill the supplied slice with the entries of this Directory sorted by
+    /// the supplied order, returning the amount of entries filled.
+    ///
+    /// If there are more entries than space in the slice, only the first
+    /// entries in sorted order are kept.
+    pub fn sorted(&mut self, out: &mut [NamedEntry], by: SortBy, reverse: bool) -> DevResult<usize> {
+        let mut n = 0usize;
+        while let Some(v) = self.next(true)? {
+            if v.shortname().is_self() || v.shortname().is_parent() {
+                continue;
+            }
+            let i = out
+                .iter()
+                .take(n)
+                .position(|e| match e.cmp(v, &by) {
+                    Ordering::Greater => !reverse,
+                    Ordering::Less => reverse,
+                    Ordering::Equal => false,
+                })
+                .unwrap_or(n);
+            if i >= out.len() {
+                continue;
+            }
+            n = (n + 1).min(out.len());
+            // Shift the rest down and drop the last entry if full.
+            for x in (i + 1..n).rev() {
+                out.swap(x, x - 1);
+            }
+            let e = unsafe { out.get_unchecked_mut(i) };
+            e.name = LongName::from_str_truncate(v.name());
+            e.entry = v.entry();
+        }
+        Ok(n)
+    }
 
+    /// Reset to the start of the Directory entry, or the root Directory if
+    /// None.
+    ///
+    /// This is preferred over 'reset_cluster' as exFAT Directories may not
+    /// have a FAT chain.
+    #[inline]
+    pub unsafe fn reset_entry(&mut self, dir: Option<&DirEntry>) -> DevResult<()> {
+        let r = match dir {
+            Some(v) => self.vol.ex_run(&mut self.buf, v)?,
+            None => None,
+        };
+        let _ = unsafe { self.reset_cluster(dir.and_then(|v| v.cluster()))? };
+        self.run = r;
+        Ok(())
+    }
     #[inline]
     pub unsafe fn reset_cluster(&mut self, dir: Cluster) -> DevResult<()> {
         self.entry = 0;
+        self.run = None;
         self.cache.clear();
-        self.cluster = self.vol.root(dir);
-        self.block = self.vol.block_pos_at(self.cluster);
-        self.blocks = self.block + self.vol.entries(dir);
+        let (c, i, n) = self.vol.dir_pos(dir);
+        (self.cluster, self.block, self.blocks) = (c, i, i + n);
         self.cache.read_single(self.vol.dev, &mut self.buf, self.block)
     }
 
     #[inline]
-    pub(super) fn new(vol: &'a Volume<'a, B>) -> DirectoryIndex<'a, B> {
-        DirectoryIndex {
+    pub(super) fn new(vol: &'a Volume<'a, B, C>) -> DevResult<DirectoryIndex<'a, B, C>> {
+        Ok(DirectoryIndex {
             vol,
-            buf: Cache::block_b(),
-            val: DirEntryFull::new(),
+            buf: vol.dev.block_b()?,
+            val: DirEntryFull::new(vol.dev.lfn()?),
+            run: None,
             cache: BlockCache::new(),
             entry: 0u32,
             block: 0u32,
             blocks: 0u32,
             cluster: ClusterIndex::EMPTY,
-        }
+        })
+    }
+    /// Returns the LongName claimed by the index as a buffer, clearing the
+    /// name of the current entry. Used when creating entries, so the index and
+    /// the new entry don't need a LongName each.
+    #[inline]
+    pub(super) fn lfn(&mut self) -> &mut LongName {
+        self.val.reset();
+        self.val.lfn_mut()
     }
 
     #[inline]
@@ -200,9 +370,17 @@ impl<'b, 'a: 'b, B: BlockDevice> DirectoryIndex<'a, B> {
             let _ = self.cache.read_single(self.vol.dev, &mut self.buf, self.block)?;
             return Ok(false);
         }
-        self.cluster = match self.vol.next(&mut self.buf, &mut self.cache, self.cluster)? {
-            Some(v) => v,
-            None => return Ok(true),
+        self.cluster = match self.run {
+            _ if self.cluster.is_empty() => return Ok(true),
+            Some(0) => return Ok(true),
+            Some(v) => {
+                self.run = Some(v - 1);
+                unsafe { ClusterIndex::new_unchecked(*self.cluster + 1) }
+            },
+            None => match self.vol.next(&mut self.buf, &mut self.cache, self.cluster)? {
+                Some(v) => v,
+                None => return Ok(true),
+            },
         };
         let i = self.vol.block_pos_at(self.cluster);
         (self.block, self.blocks) = (i, i + self.vol.block.blocks());
@@ -210,6 +388,46 @@ impl<'b, 'a: 'b, B: BlockDevice> DirectoryIndex<'a, B> {
         let _ = self.cache.read_single(self.vol.dev, &mut self.buf, self.block)?;
         Ok(false)
     }
+    fn restore(&mut self, p: &Position) -> DevResult<()> {
+        (self.run, self.block, self.entry) = (p.run, p.block, p.entry);
+        (self.blocks, self.cluster) = (p.blocks, p.cluster);
+        self.cache.clear();
+        self.cache.read_single(self.vol.dev, &mut self.buf, self.block)
+    }
+    /// Depth first walk, the closure returns if the walk should stop and the
+    /// state passed to the entries of the sub-Directory, zero to skip it.
+    fn walk_inner(&mut self, state: u32, mut func: impl FnMut(&DirEntryFull, usize, u32) -> (bool, u32)) -> DevResult<bool> {
+        let mut k = [const { Position::new() }; DEPTH];
+        let (mut n, mut s) = (0usize, state);
+        loop {
+            let e = match self.next(true)? {
+                Some(v) if v.shortname().is_self() || v.shortname().is_parent() => continue,
+                Some(v) => {
+                    let (x, c) = func(v, n, s);
+                    if x {
+                        return Ok(true);
+                    }
+                    if c == 0 || !v.is_directory() || n + 1 >= DEPTH {
+                        continue;
+                    }
+                    (v.entry(), c)
+                },
+                None if n == 0 => return Ok(false),
+                None => {
+                    n -= 1;
+                    let p = unsafe { k.get_unchecked(n) };
+                    s = p.state;
+                    let _ = self.restore(p)?;
+                    continue;
+                },
+            };
+            let p = unsafe { k.get_unchecked_mut(n) };
+            (p.run, p.block, p.entry, p.state) = (self.run, self.block, self.entry, s);
+            (p.blocks, p.cluster) = (self.blocks, self.cluster);
+            (n, s) = (n + 1, e.1);
+            let _ = unsafe { self.reset_entry(Some(&e.0))? };
+        }
+    }
     fn next(&'b mut self, r: bool) -> DevResult<Option<&'b mut DirEntryFull>> {
         if self.is_complete()? {
             return Ok(None);
@@ -221,13 +439,21 @@ impl<'b, 'a: 'b, B: BlockDevice> DirectoryIndex<'a, B> {
         }
         while !self.is_loop_done() {
             let s = self.entry as usize * DirEntry::SIZE;
+            if self.vol.ver.is_exfat() {
+                self.entry += 1;
+                match self.val.load_ex(unsafe { self.buf.get_unchecked(s..) }, self.block, s as u32) {
+                    Some(true) => return Ok(Some(&mut self.val)),
+                    Some(false) => continue,
+                    None => return Ok(None),
+                }
+            }
             if s + DirEntry::SIZE > Block::SIZE || self.buf.read_u8(s) == 0 {
                 return Ok(None);
             }
             self.entry += 1;
             let v = self.buf.read_u8(s + 11);
             if v & 0xF == 0xF {
-                self.val.fill(unsafe { self.buf.get_unchecked(s..) });
+                self.val.fill(unsafe { self.buf.get_unchecked(s..) }, self.block, s as u32);
                 continue;
             }
             if (v & 0x8 != 0 && self.buf.read_u8(s + 28) == 0) || self.buf.read_u8(s) == 0xE5 {
@@ -302,7 +528,16 @@ impl Iterator for RangeIter {
     }
 }
 
-impl<'a, B: BlockDevice> Iterator for DirectoryIter<'a, B> {
+impl Deref for NamedEntry {
+    type Target = DirEntry;
+
+    #[inline]
+    fn deref(&self) -> &DirEntry {
+        &self.entry
+    }
+}
+
+impl<'a, B: BlockDevice, C: CacheProvider> Iterator for DirectoryIter<'a, B, C> {
     type Item = DevResult<DirEntry>;
 
     #[inline]
@@ -314,7 +549,7 @@ impl<'a, B: BlockDevice> Iterator for DirectoryIter<'a, B> {
         }
     }
 }
-impl<'b, 'a: 'b, B: BlockDevice> Iterator for DirectoryIterMut<'b, 'a, B> {
+impl<'b, 'a: 'b, B: BlockDevice, C: CacheProvider> Iterator for DirectoryIterMut<'b, 'a, B, C> {
     type Item = DevResult<DirEntryPtr<'b>>;
 
     #[inline]
@@ -332,21 +567,88 @@ impl<'b, 'a: 'b, B: BlockDevice> Iterator for DirectoryIterMut<'b, 'a, B> {
     }
 }
 
-impl<'a, B: BlockDevice> IntoIterator for DirectoryIndex<'a, B> {
+impl<'a, B: BlockDevice, C: CacheProvider> IntoIterator for DirectoryIndex<'a, B, C> {
     type Item = DevResult<DirEntry>;
-    type IntoIter = DirectoryIter<'a, B>;
+    type IntoIter = DirectoryIter<'a, B, C>;
 
     #[inline]
-    fn into_iter(self) -> DirectoryIter<'a, B> {
+    fn into_iter(self) -> DirectoryIter<'a, B, C> {
         DirectoryIter(self)
     }
 }
-impl<'b, 'a: 'b, B: BlockDevice> IntoIterator for &'b mut DirectoryIndex<'a, B> {
+impl<'b, 'a: 'b, B: BlockDevice, C: CacheProvider> IntoIterator for &'b mut DirectoryIndex<'a, B, C> {
     type Item = DevResult<DirEntryPtr<'b>>;
-    type IntoIter = DirectoryIterMut<'b, 'a, B>;
+    type IntoIter = DirectoryIterMut<'b, 'a, B, C>;
 
     #[inline]
-    fn into_iter(self) -> DirectoryIterMut<'b, 'a, B> {
+    fn into_iter(self) -> DirectoryIterMut<'b, 'a, B, C> {
         DirectoryIterMut(self)
     }
 }
+
+/// Add the positions after any '**' segments, as they can match nothing.
+#[inline]
+fn glob_close(p: &str, mut s: u32) -> u32 {
+    for (i, v) in p.split('/').filter(|v| !v.is_empty()).enumerate() {
+        if v == "**" && s & unsafe { 1u32.unchecked_shl(i as u32) } != 0 {
+            s |= unsafe { 1u32.unchecked_shl(i as u32 + 1) };
+        }
+    }
+    s
+}
+/// Advance the glob state with the name of an entry. Each set bit is the
+/// index of the pattern segment the entry name is matched against, the bit
+/// after the last segment means the pattern was fully matched.
+fn glob_step(p: &str, s: u32, name: &[u8]) -> u32 {
+    let mut r = 0u32;
+    for (i, v) in p.split('/').filter(|v| !v.is_empty()).enumerate() {
+        if s & unsafe { 1u32.unchecked_shl(i as u32) } == 0 {
+            continue;
+        }
+        if v == "**" {
+            r |= unsafe { 1u32.unchecked_shl(i as u32) };
+        } else if is_glob_match(v.as_bytes(), name) {
+            r |= unsafe { 1u32.unchecked_shl(i as u32 + 1) };
+        }
+    }
+    glob_close(p, r)
+}
+fn is_glob_match(p: &[u8], v: &[u8]) -> bool {
+    // NOTE(sf): Backtracking only to the last '*' is enough, as any earlier
+    //           '*' can't match more than the last one can.
+    let (mut i, mut j, mut b) = (0usize, 0usize, None);
+    while j < v.len() {
+        match p.get(i) {
+            Some(b'*') => {
+                (b, i) = (Some((i + 1, j)), i + 1);
+                continue;
+            },
+            Some(b'?') => {
+                (i, j) = (i + 1, next_char(v, j));
+                continue;
+            },
+            Some(c) if c.eq_ignore_ascii_case(unsafe { v.get_unchecked(j) }) => {
+                (i, j) = (i + 1, j + 1);
+                continue;
+            },
+            _ => (),
+        }
+        match b {
+            Some((x, y)) => {
+                let n = next_char(v, y);
+                (b, i, j) = (Some((x, n)), x, n);
+            },
+            None => return false,
+        }
+    }
+    p.iter().skip(i).all(|c| *c == b'*')
+}
+#[inline]
+fn next_char(v: &[u8], mut i: usize) -> usize {
+    // Skip UTF8 continuation bytes, so '?' matches a whole character.
+    i += 1;
+    while i < v.len() && v.read_u8(i) & 0xC0 == 0x80 {
+        i += 1;
+    }
+    i
+}
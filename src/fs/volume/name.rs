@@ -21,6 +21,7 @@
 
 extern crate core;
 
+use core::char::{REPLACEMENT_CHARACTER, decode_utf16};
 use core::clone::Clone;
 use core::cmp::{Ord, PartialEq};
 use core::convert::AsRef;
@@ -29,20 +30,26 @@ use core::hint::unreachable_unchecked;
 use core::iter::Iterator;
 use core::matches;
 use core::ops::Deref;
-use core::option::Option::{None, Some};
-use core::ptr::{copy_nonoverlapping, write_bytes};
+use core::option::Option::{self, None, Some};
+use core::ptr::{copy, copy_nonoverlapping, write_bytes};
 use core::result::Result::{Err, Ok};
-use core::str::from_utf8_unchecked;
+use core::str::{from_utf8, from_utf8_unchecked};
 
 use crate::fs::{DevResult, DeviceError};
 use crate::{Slice, SliceMut};
 
+const HEX: [u8; 16] = *b"0123456789ABCDEF";
+
 pub struct LongName([u8; LongName::SIZE]);
 pub struct ShortName([u8; ShortName::SIZE]);
 pub struct VolumeName([u8; VolumeName::SIZE]);
 
 impl LongName {
-    pub const SIZE: usize = 255usize;
+    /// Size of the buffer, which fits the 255 UTF16 chars allowed in a LFN
+    /// when stored as UTF8.
+    pub const SIZE: usize = 0x2FDusize;
+    /// Max count of UTF16 chars in a LFN.
+    pub const UNITS: usize = 0xFFusize;
 
     #[inline]
     pub const fn empty() -> LongName {
@@ -57,7 +64,7 @@ impl LongName {
     }
     #[inline]
     pub fn from_slice(v: &[u8]) -> DevResult<LongName> {
-        if v.len() > LongName::SIZE {
+        if units(v) > LongName::UNITS {
             Err(DeviceError::NameTooLong)
         } else {
             Ok(LongName::from_slice_truncate(v))
@@ -69,7 +76,7 @@ impl LongName {
     }
     #[inline]
     pub fn from_str(v: impl AsRef<str>) -> DevResult<LongName> {
-        if v.as_ref().len() > LongName::SIZE {
+        if v.as_ref().encode_utf16().count() > LongName::UNITS {
             Err(DeviceError::NameTooLong)
         } else {
             Ok(LongName::from_str_truncate(v))
@@ -84,21 +91,11 @@ impl LongName {
     pub fn as_str(&self) -> &str {
         unsafe { from_utf8_unchecked(self.as_bytes()) }
     }
+    /// Returns the count of LFN entries needed to store this name, which
+    /// hold 13 UTF16 chars each.
+    #[inline]
     pub fn lfn_size(&self) -> u8 {
-        if self.is_empty() {
-            return 0;
-        }
-        let mut r = self.len();
-        if r < 13 {
-            return 1; // Need NULL so anything UNDER 13
-        }
-        r += 1; // NULL CHAR
-        let c = (r / 0xC) as u8;
-        if (c * 0xC) as usize >= r {
-            c
-        } else {
-            c + 1 // PAD
-        }
+        self.as_str().encode_utf16().count().div_ceil(0xD) as u8
     }
     #[inline]
     pub fn as_raw(&self) -> &[u8] {
@@ -123,7 +120,7 @@ impl LongName {
     #[inline]
     pub fn fill(&mut self, v: impl AsRef<[u8]>) -> DevResult<()> {
         let b = v.as_ref();
-        if b.len() > LongName::SIZE {
+        if units(b) > LongName::UNITS {
             return Err(DeviceError::NameTooLong);
         }
         self.fill_inner(b);
@@ -133,32 +130,114 @@ impl LongName {
     pub fn fill_str(&mut self, v: impl AsRef<str>) -> DevResult<()> {
         self.fill(v.as_ref().as_bytes())
     }
+    /// Compare using the FAT case-insensitive rules, where every char is
+    /// compared by its upper case form.
+    pub fn eq_ignore_case(&self, v: &[u8]) -> bool {
+        let b = self.as_bytes();
+        if b.eq_ignore_ascii_case(v) {
+            return true;
+        }
+        if b.is_ascii() && v.is_ascii() {
+            return false;
+        }
+        let (mut x, mut y) = match from_utf8(v) {
+            Ok(s) => (self.as_str().chars(), s.chars()),
+            Err(_) => return false,
+        };
+        loop {
+            match (x.next(), y.next()) {
+                (None, None) => return true,
+                (Some(a), Some(b)) if a == b || fold(a) == fold(b) => (),
+                _ => return false,
+            }
+        }
+    }
 
     #[inline]
     pub(super) fn reset(&mut self) {
         unsafe { write_bytes(self.0.as_mut_ptr(), 0, LongName::SIZE) };
     }
-    pub(super) fn lfn(&mut self, b: &[u8]) -> u8 {
+    /// Add the chars of the LFN entry to the front of the name and return
+    /// the entry checksum.
+    ///
+    /// LFN entries are stored last to first, so the name is built backwards
+    /// from 'pos' at the end of the buffer and moved into place by
+    /// 'lfn_finish'. A low surrogate starting an entry is kept in 'low' until
+    /// the entry with its pair is read.
+    pub(super) fn lfn(&mut self, b: &[u8], pos: &mut u16, low: &mut u16) -> u8 {
         if b.len() < 32 {
             return 0;
         }
-        let v = ((b.read_u8(0) & 0x1F) as usize - 1) * 0xD;
+        let s = b.read_u8(0);
+        if s & 0x40 != 0 {
+            self.reset();
+            (*pos, *low) = (LongName::SIZE as u16, 0);
+        }
+        let (mut v, mut n) = ([0u16; 0xE], 0usize);
         for i in 0..0xD {
-            if i + v >= LongName::SIZE {
-                break;
-            }
-            let c = b.read_u8(to_lfn(i));
+            let c = b.read_u16(to_lfn(i));
             if c == 0 {
                 break;
             }
-            self.0.write_u8(v + i, c);
+            v[n] = c;
+            n += 1;
+        }
+        if *low != 0 {
+            (v[n], n, *low) = (*low, n + 1, 0);
+        }
+        let k = if n > 0 && s & 0x1F > 1 && matches!(v[0], 0xDC00..=0xDFFF) {
+            *low = v[0];
+            1
+        } else {
+            0
+        };
+        let r = unsafe { v.get_unchecked(k..n) };
+        let t = decode_utf16(r.iter().copied())
+            .map(|c| c.unwrap_or(REPLACEMENT_CHARACTER).len_utf8())
+            .sum::<usize>();
+        // NOTE(sf): This can only overflow with a broken entry that claims to
+        //           be past the 20th, so we drop what doesn't fit.
+        if t <= *pos as usize {
+            let mut i = *pos as usize - t;
+            *pos = i as u16;
+            for c in decode_utf16(r.iter().copied()) {
+                i += c.unwrap_or(REPLACEMENT_CHARACTER).encode_utf8(unsafe { self.0.get_unchecked_mut(i..) }).len();
+            }
         }
         b.read_u8(13)
     }
+    /// Move the name built by 'lfn' to the start of the buffer.
+    pub(super) fn lfn_finish(&mut self, pos: u16) {
+        let p = pos as usize;
+        if p == 0 || p >= LongName::SIZE {
+            return;
+        }
+        unsafe {
+            copy(self.0.as_ptr().add(p), self.0.as_mut_ptr(), LongName::SIZE - p);
+            write_bytes(self.0.as_mut_ptr().add(LongName::SIZE - p), 0, p);
+        }
+    }
+
+    /// Append the UTF16 chars to the end of the name as UTF8. Chars that
+    /// don't fit are dropped.
+    pub(super) fn push_utf16(&mut self, v: &[u16]) {
+        let mut n = self.len();
+        for r in decode_utf16(v.iter().copied()) {
+            let c = r.unwrap_or(REPLACEMENT_CHARACTER);
+            if n + c.len_utf8() > LongName::SIZE {
+                break;
+            }
+            n += c.encode_utf8(unsafe { self.0.get_unchecked_mut(n..) }).len();
+        }
+    }
 
     #[inline]
     fn fill_inner(&mut self, v: &[u8]) {
-        unsafe { copy_nonoverlapping(v.as_ptr(), self.0.as_mut_ptr(), v.len().min(LongName::SIZE)) };
+        let n = limit(v);
+        unsafe {
+            copy_nonoverlapping(v.as_ptr(), self.0.as_mut_ptr(), n);
+            write_bytes(self.0.as_mut_ptr().add(n), 0, LongName::SIZE - n);
+        }
     }
 }
 impl ShortName {
@@ -269,6 +348,75 @@ impl ShortName {
         }
     }
 
+    /// Fill the name with the basis name of 'b' and return true if the
+    /// conversion was lossy, which means the name needs a numeric tail.
+    ///
+    /// Spaces and leading periods are removed, chars that can't be stored
+    /// are replaced with '_' and the name and extension are truncated to 8
+    /// and 3 chars.
+    pub(super) fn basis(&mut self, b: &[u8]) -> bool {
+        unsafe { write_bytes(self.0.as_mut_ptr(), 0x20, ShortName::SIZE) };
+        let s = b.iter().position(|v| *v != b'.').unwrap_or(b.len());
+        let v = unsafe { b.get_unchecked(s..) };
+        let mut r = s > 0 || v.contains(&0x20);
+        // Extract the extension first, if there is one.
+        // 'n' is the end of the 'name'.
+        let n = match v.iter().rposition(|c| *c == b'.') {
+            Some(i) => {
+                let e = unsafe { v.get_unchecked(i + 1..) };
+                r |= sfn_chars(e).count() > ShortName::SIZE_EXT;
+                for (x, c) in sfn_chars(e).take(ShortName::SIZE_EXT).enumerate() {
+                    self.0.write_u8(ShortName::SIZE_NAME + x, c);
+                }
+                i
+            },
+            None => v.len(),
+        };
+        // NOTE(sf): Every char is mapped to a single byte, so names with chars
+        //           outside of ASCII get a '_' for each one instead of one for
+        //           every UTF8 byte.
+        let v = unsafe { v.get_unchecked(0..n) };
+        // Periods in the name are dropped as only the last one is kept.
+        r |= v.contains(&b'.') || sfn_chars(v).count() > ShortName::SIZE_NAME;
+        for (x, c) in sfn_chars(v).take(ShortName::SIZE_NAME).enumerate() {
+            self.0.write_u8(x, c);
+        }
+        r || b.utf8_chunks().any(|c| !c.invalid().is_empty() || !c.valid().is_ascii())
+    }
+    /// Returns a copy of this basis name with the numeric tail "~n" added.
+    ///
+    /// When 'hash' is set the name is replaced by its first two chars and the
+    /// hash as hex, which is what Windows does after the first 4 tails.
+    pub(super) fn with_tail(&self, n: u32, hash: Option<u16>) -> ShortName {
+        let mut v = self.clone();
+        let mut k = self.len();
+        if let Some(h) = hash {
+            k = k.min(2);
+            for i in 0..4 {
+                v.0.write_u8(k + i, HEX[unsafe { h.unchecked_shr(12 - (i as u32 * 4)) } as usize & 0xF]);
+            }
+            k += 4;
+        }
+        let (mut d, mut x) = ([0u8; 10], 0usize);
+        let mut t = n;
+        loop {
+            d[x] = b'0' + (t % 10) as u8;
+            (t, x) = (t / 10, x + 1);
+            if t == 0 {
+                break;
+            }
+        }
+        let i = k.min(ShortName::SIZE_NAME - 1 - x);
+        v.0.write_u8(i, b'~');
+        for y in 0..x {
+            v.0.write_u8(i + 1 + y, d[x - 1 - y]);
+        }
+        for y in i + 1 + x..ShortName::SIZE_NAME {
+            v.0.write_u8(y, 0x20);
+        }
+        v
+    }
+
     fn sfn(&mut self, b: &[u8]) {
         // Look for SELF and PARENT names
         match b.len() {
@@ -283,53 +431,11 @@ impl ShortName {
             },
             _ => (),
         }
-        // Extract the extension first, if there is one.
-        // 'n' is the end of the 'name'.
-        let n = match b.iter().rposition(|v| *v == b'.') {
-            Some(i) => {
-                // We drop any chars more than 3 for the extension.
-                match b.len().saturating_sub(i + 1) {
-                    v @ (1 | 2 | 3) => unsafe {
-                        copy_nonoverlapping(
-                            b.as_ptr().add(i + 1),
-                            self.0.as_mut_ptr().add(ShortName::SIZE_NAME),
-                            v,
-                        )
-                    },
-                    _ => (),
-                }
-                i
-            },
-            None => b.len(),
-        };
-        if n == ShortName::SIZE_NAME {
-            unsafe { copy_nonoverlapping(b.as_ptr(), self.0.as_mut_ptr(), ShortName::SIZE_NAME) };
-        } else if n < ShortName::SIZE_NAME {
-            unsafe {
-                copy_nonoverlapping(b.as_ptr(), self.0.as_mut_ptr(), n);
-                write_bytes(self.0.as_mut_ptr().add(n), 0x20, ShortName::SIZE_NAME - n);
-            }
-        } else {
-            unsafe { copy_nonoverlapping(b.as_ptr(), self.0.as_mut_ptr(), ShortName::SIZE_NAME - 2) };
-            // NOTE(sf): We don't really know the "number" of files
-            //           contained, like we could read the dir first before
-            //           setting this last bit (or after setting it).
-            // NOTE(sf): ^ Would be a lot of work tbh.
-            self.0.write_u8(ShortName::SIZE_NAME - 2, b'~');
-            self.0.write_u8(ShortName::SIZE_NAME - 1, b'1');
-        }
-        // Flatten any spaces to '_'. We don't flatten spaces in extensions.
-        // This only goes the length of the original string, so the empty
-        // bytes stay as spaces.
-        for i in 0..n.min(ShortName::SIZE_NAME) {
-            let v = unsafe { self.0.get_unchecked_mut(i) };
-            if *v == 0x20 {
-                *v = b'_';
-            }
-        }
-        // Last, make sure every a-z is capitalized.
-        for i in unsafe { self.0.get_unchecked_mut(0..ShortName::SIZE_NAME) }.iter_mut() {
-            *i = transform(*i)
+        // NOTE(sf): Without the parent Directory we can't tell which tails are
+        //           taken, so this always uses "~1". Volumes pick a free one
+        //           when creating entries.
+        if self.basis(b) {
+            *self = self.with_tail(1, None);
         }
     }
     #[inline]
@@ -345,17 +451,37 @@ impl ShortName {
 impl VolumeName {
     pub const SIZE: usize = 11usize;
 
+    pub const NO_NAME: VolumeName = VolumeName(*b"NO NAME    ");
+
     #[inline]
     pub const fn empty() -> VolumeName {
         VolumeName([0x20u8; VolumeName::SIZE])
     }
 
+    /// Create a VolumeName from the string, any chars not allowed in
+    /// a label are replaced with '_' and the name is truncated to 11 chars.
+    pub fn from_str(v: impl AsRef<str>) -> VolumeName {
+        let mut n = VolumeName::empty();
+        let b = v.as_ref().as_bytes();
+        unsafe { copy_nonoverlapping(b.as_ptr(), n.0.as_mut_ptr(), b.len().min(VolumeName::SIZE)) };
+        for i in unsafe { n.0.get_unchecked_mut(0..b.len().min(VolumeName::SIZE)) }.iter_mut() {
+            *i = match *i {
+                0x20 => 0x20,
+                0x2E => b'_',
+                v => transform(v),
+            }
+        }
+        n
+    }
+
+    /// Returns the length of the label without the trailing spaces, labels
+    /// can have spaces inside them.
     #[inline]
     pub fn len(&self) -> usize {
         self.0
             .iter()
-            .position(|&v| v == 0 || v == 0x20)
-            .unwrap_or(VolumeName::SIZE)
+            .rposition(|&v| v != 0 && v != 0x20)
+            .map_or(0, |i| i + 1)
     }
 
     #[inline]
@@ -368,7 +494,7 @@ impl VolumeName {
     }
     #[inline]
     pub fn is_empty(&self) -> bool {
-        matches!(self.0.read_u8(0), 0 | 0x20)
+        self.len() == 0
     }
     #[inline]
     pub fn as_bytes(&self) -> &[u8] {
@@ -387,6 +513,31 @@ impl VolumeName {
         };
         v
     }
+    /// Write the label into the exFAT Volume Label entry, the entry type is
+    /// left for the caller to set.
+    #[inline]
+    pub(super) fn write_ex(&self, mut b: &mut [u8]) {
+        unsafe { write_bytes(b.as_mut_ptr(), 0, b.len()) };
+        // 0x1 - Character Count
+        // 0x2 - Volume Label
+        b.write_u8(1, self.len() as u8);
+        for (i, c) in self.as_bytes().iter().enumerate() {
+            b.write_u16(2 + (i * 2), *c as u16);
+        }
+    }
+    #[inline]
+    pub(super) fn new_ex(b: &[u8]) -> VolumeName {
+        let mut v = VolumeName::empty();
+        // 0x1 - Character Count
+        // 0x2 - Volume Label
+        for i in 0..(b.read_u8(1) as usize).min(VolumeName::SIZE) {
+            v.0.write_u8(i, match b.read_u16(2 + (i * 2)) {
+                c @ 0x20..=0x7E => c as u8,
+                _ => b'_',
+            });
+        }
+        v
+    }
 }
 
 impl Clone for LongName {
@@ -546,6 +697,82 @@ pub(super) fn to_lfn(v: usize) -> usize {
     }
 }
 
+/// Returns true if the name is a valid 8.3 name, which can be stored without
+/// any LFN entries. Lower case chars are only allowed when 'lower' is true.
+pub(super) fn is_short(v: &[u8], lower: bool) -> bool {
+    let (n, e) = match v.iter().position(|c| *c == b'.') {
+        Some(i) => unsafe { (v.get_unchecked(0..i), v.get_unchecked(i + 1..)) },
+        None => (v, &[][..]),
+    };
+    if n.is_empty() || n.len() > ShortName::SIZE_NAME || e.len() > ShortName::SIZE_EXT || (e.is_empty() && n.len() != v.len()) {
+        return false;
+    }
+    n.iter()
+        .chain(e.iter())
+        .all(|c| *c != b'.' && (transform(*c) == *c || (lower && c.is_ascii_lowercase())))
+}
+
+/// Hash of the long name used for the short name tails after "~4", based on
+/// the one used by Windows.
+pub(super) fn sfn_hash(v: &str) -> u16 {
+    let mut h = 0u16;
+    for c in v.encode_utf16() {
+        h = h.wrapping_mul(0x25).wrapping_add(c);
+    }
+    let mut t = (h as i32).wrapping_mul(0x12B9B0A5);
+    if t < 0 {
+        t = t.wrapping_neg();
+    }
+    // NOTE(sf): This is 't % 1000000007', the multiply and shift is exact for
+    //           every positive 'i32'.
+    t = t.wrapping_sub(unsafe { ((t as i64 * 0x44B82FA9).unchecked_shr(60) as i32).wrapping_mul(0x3B9ACA07) });
+    let v = t as u16;
+    unsafe { (v & 0xF000).unchecked_shr(12) | (v & 0x0F00).unchecked_shr(4) | (v & 0x00F0).unchecked_shl(4) | (v & 0x000F).unchecked_shl(12) }
+}
+
+#[inline]
+fn fold(c: char) -> char {
+    // NOTE(sf): The FAT up-case tables only map a char to a single char, so
+    //           chars with longer upper case forms (like 'ß') are kept as is.
+    let mut u = c.to_uppercase();
+    match (u.next(), u.next()) {
+        (Some(v), None) => v,
+        _ => c,
+    }
+}
+#[inline]
+fn units(v: &[u8]) -> usize {
+    match from_utf8(v) {
+        Ok(s) => s.encode_utf16().count(),
+        Err(_) => v.len(),
+    }
+}
+/// Returns the length of 'v' in bytes that fits in a LongName, without
+/// splitting a char.
+#[inline]
+fn limit(v: &[u8]) -> usize {
+    let s = match from_utf8(v) {
+        Ok(s) => s,
+        Err(_) => return v.len().min(LongName::SIZE),
+    };
+    let mut n = 0usize;
+    for (i, c) in s.char_indices() {
+        n += c.len_utf16();
+        if n > LongName::UNITS {
+            return i;
+        }
+    }
+    s.len()
+}
+#[inline]
+fn sfn_chars(v: &[u8]) -> impl Iterator<Item = u8> + '_ {
+    v.utf8_chunks().flat_map(|c| {
+        c.valid()
+            .chars()
+            .map(|x| if x.is_ascii() { transform(x as u8) } else { b'_' })
+            .chain(c.invalid().iter().map(|_| b'_'))
+    })
+}
 #[inline]
 fn transform(v: u8) -> u8 {
     match v {
@@ -35,6 +35,10 @@ pub struct FatVersion(u32);
 pub struct ClusterIndex(NonZeroU32);
 
 impl FatVersion {
+    #[inline]
+    pub(super) const fn new_12(v: u16) -> FatVersion {
+        FatVersion(v as u32 | 0xC0000000)
+    }
     #[inline]
     pub(super) const fn new_16(v: u16) -> FatVersion {
         FatVersion(v as u32 | 0x80000000)
@@ -43,14 +47,30 @@ impl FatVersion {
     pub(super) const fn new_32(v: u32) -> FatVersion {
         FatVersion(v)
     }
+    #[inline]
+    pub(super) const fn new_ex() -> FatVersion {
+        FatVersion(0x40000000)
+    }
 
     #[inline]
     pub const fn sector(&self) -> u32 {
-        self.0 & 0x7FFFFFFF
+        self.0 & 0x3FFFFFFF
+    }
+    #[inline]
+    pub const fn is_fat12(&self) -> bool {
+        self.0 & 0xC0000000 == 0xC0000000
+    }
+    #[inline]
+    pub const fn is_fat16(&self) -> bool {
+        self.0 & 0xC0000000 == 0x80000000
     }
     #[inline]
     pub const fn is_fat32(&self) -> bool {
-        self.0 & 0x80000000 == 0
+        self.0 & 0xC0000000 == 0
+    }
+    #[inline]
+    pub const fn is_exfat(&self) -> bool {
+        self.0 & 0xC0000000 == 0x40000000
     }
 }
 impl ClusterIndex {
@@ -77,25 +97,10 @@ impl ClusterIndex {
     pub const fn is_empty(&self) -> bool {
         self.0.get() == 0xFFFFFFFCu32
     }
+    /// Clusters 0 and 1 are reserved, so the first data Cluster is 2.
     #[inline]
     pub const fn is_valid(&self) -> bool {
-        self.0.get() > 2
-    }
-
-    #[inline]
-    pub(super) fn sub_one(v: &mut Cluster) {
-        *v = match v.as_mut() {
-            Some(n) if n.0.get() == 1 => None,
-            Some(n) => Some(unsafe { ClusterIndex::new_unchecked(n.0.get().saturating_sub(1)) }),
-            None => None,
-        }
-    }
-    #[inline]
-    pub(super) fn add_one(v: &mut Cluster) {
-        *v = match v.as_mut() {
-            Some(n) => Some(ClusterIndex(n.0.saturating_add(1))),
-            None => Some(unsafe { ClusterIndex::new_unchecked(1) }),
-        };
+        self.0.get() >= 2
     }
 }
 
@@ -28,13 +28,19 @@ extern crate core;
 use core::ptr::copy_nonoverlapping;
 use core::slice::from_raw_parts;
 
+#[cfg(feature = "pico")]
 pub mod frame;
 pub mod fs;
+#[cfg(feature = "pico")]
 pub mod hw;
+#[cfg(feature = "pico")]
 mod inky;
+#[cfg(feature = "pico")]
 pub mod pcf;
+#[cfg(feature = "pico")]
 pub mod sd;
 
+#[cfg(feature = "pico")]
 pub use self::inky::*;
 
 trait Slice {
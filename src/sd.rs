@@ -20,16 +20,23 @@
 #![no_implicit_prelude]
 
 extern crate core;
+#[cfg(feature = "sdmmc")]
+extern crate embedded_sdmmc;
 extern crate rpsp;
 
+use core::cell::UnsafeCell;
 use core::clone::Clone;
 use core::convert::{From, Into};
 use core::fmt::{self, Debug, Formatter};
+#[cfg(feature = "sdmmc")]
+use core::iter::Iterator;
 use core::ops::Deref;
 use core::ptr::{NonNull, write_bytes};
 use core::result::Result::{self, Err, Ok};
 use core::{matches, unreachable};
 
+#[cfg(feature = "sdmmc")]
+use embedded_sdmmc::{Block as SdBlock, BlockCount, BlockDevice as SdBlockDevice, BlockIdx};
 use rpsp::Board;
 use rpsp::clock::Timer;
 use rpsp::pin::gpio::Output;
@@ -76,7 +83,13 @@ pub struct CardInfo {
     v2:  bool,
     buf: [u8; 16],
 }
-pub struct Card<'a> {
+/// SD Card on a SPI bus.
+///
+/// The bus is kept in an UnsafeCell, so the Card can also be used as an
+/// 'embedded-sdmmc' BlockDevice, which only takes shared references.
+pub struct Card<'a>(UnsafeCell<CardIO<'a>>);
+
+struct CardIO<'a> {
     cs:  Pin<Output>,
     clk: Timer,
     crc: bool,
@@ -93,7 +106,7 @@ impl Counter {
     const ATTEMPTS: u16 = 0x5FFFu16;
 
     #[inline]
-    fn new(v: &mut Card<'_>) -> Counter {
+    fn new(v: &mut CardIO<'_>) -> Counter {
         Counter {
             c: Counter::ATTEMPTS,
             t: unsafe { NonNull::new_unchecked(&mut v.clk) },
@@ -172,24 +185,46 @@ impl<'a> Card<'a> {
     }
     #[inline]
     pub fn new_crc(p: &Board, cs: PinID, spi: impl Into<SpiBus<'a>>, crc: bool) -> Card<'a> {
-        Card {
+        Card(UnsafeCell::new(CardIO {
             crc,
             cs: p.pin(cs).output_high(),
             clk: p.timer().clone(),
             spi: spi.into(),
             ver: CardType::None,
-        }
+        }))
     }
 
     #[inline]
-    pub fn bus(&self) -> &Spi {
-        &self.spi
+    pub fn bus(&mut self) -> &Spi {
+        &self.0.get_mut().spi
     }
     #[inline]
     pub fn blocks(&mut self) -> Result<u32, CardError> {
-        Ok(self.info()?.blocks())
+        Ok(self.0.get_mut().info()?.blocks())
     }
+    #[inline]
     pub fn info(&mut self) -> Result<CardInfo, CardError> {
+        self.0.get_mut().info()
+    }
+    #[inline]
+    pub fn write_block(&mut self, b: &Block, start: u32) -> Result<(), CardError> {
+        self.0.get_mut().write_block(b, start)
+    }
+    #[inline]
+    pub fn read_block(&mut self, b: &mut Block, start: u32) -> Result<(), CardError> {
+        self.0.get_mut().read_block(b, start)
+    }
+    #[inline]
+    pub fn write_blocks(&mut self, b: &[Block], start: u32) -> Result<(), CardError> {
+        self.0.get_mut().write_blocks(b, start)
+    }
+    #[inline]
+    pub fn read_blocks(&mut self, b: &mut [Block], start: u32) -> Result<(), CardError> {
+        self.0.get_mut().read_blocks(b, start)
+    }
+}
+impl<'a> CardIO<'a> {
+    fn info(&mut self) -> Result<CardInfo, CardError> {
         if let CardType::None = self.ver {
             let _ = self.init()?;
         }
@@ -211,7 +246,7 @@ impl<'a> Card<'a> {
         r
     }
     #[inline]
-    pub fn write_block(&mut self, b: &Block, start: u32) -> Result<(), CardError> {
+    fn write_block(&mut self, b: &Block, start: u32) -> Result<(), CardError> {
         if let CardType::None = self.ver {
             let _ = self.init()?;
         }
@@ -221,7 +256,7 @@ impl<'a> Card<'a> {
         r
     }
     #[inline]
-    pub fn read_block(&mut self, b: &mut Block, start: u32) -> Result<(), CardError> {
+    fn read_block(&mut self, b: &mut Block, start: u32) -> Result<(), CardError> {
         if let CardType::None = self.ver {
             let _ = self.init()?;
         }
@@ -231,7 +266,7 @@ impl<'a> Card<'a> {
         r
     }
     #[inline]
-    pub fn write_blocks(&mut self, b: &[Block], start: u32) -> Result<(), CardError> {
+    fn write_blocks(&mut self, b: &[Block], start: u32) -> Result<(), CardError> {
         if let CardType::None = self.ver {
             let _ = self.init()?;
         }
@@ -241,7 +276,7 @@ impl<'a> Card<'a> {
         r
     }
     #[inline]
-    pub fn read_blocks(&mut self, b: &mut [Block], start: u32) -> Result<(), CardError> {
+    fn read_blocks(&mut self, b: &mut [Block], start: u32) -> Result<(), CardError> {
         if let CardType::None = self.ver {
             let _ = self.init()?;
         }
@@ -483,6 +518,34 @@ impl BlockDevice for Card<'_> {
     }
 }
 
+/// Card as an 'embedded-sdmmc' BlockDevice, so it can be used by their
+/// VolumeManager directly.
+// NOTE(sf): These calls don't nest, the Card is not 'Sync' and every other
+//           call takes '&mut self', so the bus is only borrowed once.
+#[cfg(feature = "sdmmc")]
+impl SdBlockDevice for Card<'_> {
+    type Error = DeviceError;
+
+    #[inline]
+    fn num_blocks(&self) -> Result<BlockCount, DeviceError> {
+        Ok(BlockCount(unsafe { &mut *self.0.get() }.info()?.blocks()))
+    }
+    fn write(&self, blocks: &[SdBlock], start: BlockIdx) -> Result<(), DeviceError> {
+        let d = unsafe { &mut *self.0.get() };
+        for (i, v) in blocks.iter().enumerate() {
+            d.write_block(Block::from_sd(v), start.0 + i as u32)?;
+        }
+        Ok(())
+    }
+    fn read(&self, blocks: &mut [SdBlock], start: BlockIdx) -> Result<(), DeviceError> {
+        let d = unsafe { &mut *self.0.get() };
+        for (i, v) in blocks.iter_mut().enumerate() {
+            d.read_block(Block::from_sd_mut(v), start.0 + i as u32)?;
+        }
+        Ok(())
+    }
+}
+
 impl From<CardError> for DeviceError {
     #[inline]
     fn from(v: CardError) -> DeviceError {
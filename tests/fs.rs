@@ -0,0 +1,328 @@
+use inky_frame::fs::{Block, BlockBuffer, BlockDevice, DeviceError, FatType, FileDevice, Format, LocalCache, MemoryDevice, Storage, TracingDevice, Volume};
+
+type Image = Storage<MemoryDevice<Vec<u8>>, LocalCache>;
+
+fn image(kind: FatType, mb: usize) -> Image {
+    let s = Storage::with_cache(MemoryDevice::new(vec![0u8; mb << 20]), LocalCache::new());
+    s.format(&Format::new().kind(kind)).unwrap();
+    s
+}
+fn fat16() -> Image {
+    image(FatType::Fat16, 32)
+}
+fn fat32() -> Image {
+    image(FatType::Fat32, 80)
+}
+fn assert_clean<D: BlockDevice>(v: &Volume<'_, D, LocalCache>) {
+    let mut m = [0u8; 0x400];
+    let r = v.check(&mut m, false).unwrap();
+    assert_eq!(r.lost(), 0);
+    assert_eq!(r.cross(), 0);
+    assert_eq!(r.sizes(), 0);
+    assert_eq!(r.chains(), 0);
+    assert_eq!(r.orphans(), 0);
+    assert!(r.is_clean());
+}
+
+#[test]
+fn memory_bounds() {
+    let mut d = MemoryDevice::new(vec![0u8; 0x2000]);
+    let mut b = [Block::new()];
+    assert_eq!(d.blocks().unwrap(), 0x10);
+    assert!(d.read(&mut b, 0xF).is_ok());
+    assert!(matches!(d.read(&mut b, 0x10), Err(DeviceError::InvalidIndex)));
+    assert!(matches!(d.read(&mut b, u32::MAX), Err(DeviceError::InvalidIndex)));
+    assert!(matches!(d.write(&b, 0x800000), Err(DeviceError::InvalidIndex)));
+}
+#[test]
+fn tracing_zero_ops() {
+    let mut d: TracingDevice<_> = TracingDevice::new(MemoryDevice::new(vec![0u8; 0x2000]));
+    let mut b = Block::new();
+    d.read_single(&mut b, 1).unwrap();
+    d.write_single(&b, 2).unwrap();
+    assert_eq!(d.stats().transactions(), 2);
+    assert_eq!(d.ops().count(), 0);
+    #[cfg(feature = "debug")]
+    assert!(format!("{d:?}").contains("ops: []"));
+}
+#[test]
+fn fat16_format_mounts_clean() {
+    let s = fat16();
+    let v = s.root().unwrap();
+    assert!(!v.fat_version().is_fat32());
+    assert_clean(&v);
+}
+#[test]
+fn fat32_format_mounts_clean() {
+    let s = fat32();
+    let v = s.root().unwrap();
+    assert!(v.fat_version().is_fat32());
+    assert_clean(&v);
+}
+#[test]
+fn fat32_repair_keeps_root() {
+    let s = fat32();
+    let v = s.root().unwrap();
+    v.file_create("keep.txt").unwrap().close().unwrap();
+    let mut m = [0u8; 0x400];
+    let r = v.check(&mut m, true).unwrap();
+    assert_eq!(r.lost(), 0);
+    assert_eq!(r.fixed(), 0);
+    v.dir_create("newdir").unwrap();
+    assert!(v.exists("keep.txt").unwrap());
+    assert!(v.exists("newdir").unwrap());
+    assert_clean(&v);
+}
+#[test]
+fn format_label() {
+    let mut b = Block::new();
+    let s = fat16();
+    let v = s.root().unwrap();
+    s.read_single(&mut b, v.pos_lba() + v.pos_root()).unwrap();
+    assert_eq!(b[0], 0);
+    let s = Storage::with_cache(MemoryDevice::new(vec![0u8; 32 << 20]), LocalCache::new());
+    s.format(&Format::new().kind(FatType::Fat16).label("INKY")).unwrap();
+    let v = s.root().unwrap();
+    s.read_single(&mut b, v.pos_lba() + v.pos_root()).unwrap();
+    assert_eq!(&b[0..11], b"INKY       ");
+    assert_eq!(b[11], 0x8);
+}
+#[test]
+fn fat16_write_and_read() {
+    let s = fat16();
+    let v = s.root().unwrap();
+    let d: Vec<u8> = (0..5000u32).map(|i| i as u8).collect();
+    let mut f = v.file_create("data.bin").unwrap();
+    assert_eq!(f.write(&d).unwrap(), d.len());
+    f.close().unwrap();
+    let mut f = v.open("data.bin").unwrap();
+    let mut r = vec![0u8; d.len()];
+    assert_eq!(f.read(&mut r).unwrap(), d.len());
+    assert_eq!(r, d);
+    drop(f);
+    assert_clean(&v);
+}
+#[test]
+fn fat32_write_atomic() {
+    let s = fat32();
+    let v = s.root().unwrap();
+    v.write_atomic("atomic.txt", b"first").unwrap();
+    v.write_atomic("atomic.txt", b"second contents").unwrap();
+    let mut f = v.open("atomic.txt").unwrap();
+    let mut r = [0u8; 32];
+    assert_eq!(f.read(&mut r).unwrap(), 15);
+    assert_eq!(&r[0..15], b"second contents");
+    drop(f);
+    assert!(!v.exists("atomic.txt.tmp").unwrap());
+    assert_clean(&v);
+}
+#[test]
+fn set_len_zero_new_file() {
+    let s = fat16();
+    let v = s.root().unwrap();
+    // Leave old data in the Clusters the new File will get.
+    v.file_create("junk.bin").unwrap().write(&vec![0xA5u8; 0x2000]).unwrap();
+    v.remove("junk.bin").unwrap();
+    let mut f = v.file_create("zero.bin").unwrap();
+    f.write(b"abc").unwrap();
+    f.set_len(4096, true).unwrap();
+    f.close().unwrap();
+    let mut f = v.open("zero.bin").unwrap();
+    let mut r = vec![0xFFu8; 4096];
+    assert_eq!(f.read(&mut r).unwrap(), 4096);
+    assert_eq!(&r[0..3], b"abc");
+    assert!(r[3..].iter().all(|v| *v == 0));
+    drop(f);
+    assert_clean(&v);
+}
+#[test]
+fn reader_claim_in_use() {
+    let s = fat16();
+    let v = s.root().unwrap();
+    v.file_create("read.txt").unwrap().write(b"hello").unwrap();
+    let mut r = unsafe { v.open("read.txt").unwrap().into_reader().unwrap() };
+    assert!(matches!(v.exists("read.txt"), Err(DeviceError::FileInUse)));
+    assert!(matches!(s.root(), Err(DeviceError::FileInUse)));
+    let mut b = [0u8; 5];
+    assert_eq!(r.read(&mut b).unwrap(), 5);
+    assert_eq!(&b, b"hello");
+    drop(r);
+    assert!(v.exists("read.txt").unwrap());
+}
+#[test]
+fn free_space_after_remove() {
+    let s = fat16();
+    let v = s.root().unwrap();
+    let n = v.free_space().unwrap();
+    let mut f = v.file_create("big.bin").unwrap();
+    f.write(&vec![0xA5u8; 0x8000]).unwrap();
+    f.close().unwrap();
+    assert_eq!(v.free_space().unwrap(), n - 0x8000);
+    let mut f = v.file_open("big.bin", inky_frame::fs::Mode::WRITE).unwrap();
+    f.set_len(0x2000, false).unwrap();
+    f.close().unwrap();
+    assert_eq!(v.free_space().unwrap(), n - 0x2000);
+    v.remove("big.bin").unwrap();
+    assert_eq!(v.free_space().unwrap(), n);
+    assert_clean(&v);
+}
+#[test]
+fn dirty_on_first_write() {
+    let s = fat16();
+    let v = s.root().unwrap();
+    v.file_create("a.txt").unwrap().write(b"abc").unwrap();
+    v.unmount().unwrap();
+    assert!(!v.is_dirty());
+    let mut f = v.file_open("a.txt", inky_frame::fs::Mode::WRITE).unwrap();
+    f.set_len(3, true).unwrap();
+    assert!(!v.is_dirty());
+    f.write(b"x").unwrap();
+    assert!(v.is_dirty());
+    f.close().unwrap();
+    assert_clean(&v);
+}
+#[test]
+fn fat32_full_free_count() {
+    let s = fat32();
+    {
+        let v = s.root().unwrap();
+        let mut f = v.file_create("fill.bin").unwrap();
+        let b = vec![0x5Au8; 0x10000];
+        loop {
+            match f.write(&b) {
+                Ok(n) if n == b.len() => (),
+                Ok(_) | Err(DeviceError::NoSpace) => break,
+                Err(e) => panic!("{e:?}"),
+            }
+        }
+        f.close().unwrap();
+        assert_eq!(v.free_space().unwrap(), 0);
+        v.unmount().unwrap();
+    }
+    let v = s.root().unwrap();
+    assert_eq!(v.free_space().unwrap(), 0);
+    assert_clean(&v);
+}
+#[test]
+fn remove_long_name() {
+    let s = fat16();
+    let v = s.root().unwrap();
+    v.file_create("a rather long file name.txt").unwrap().write(b"data").unwrap();
+    v.file_create("another long name here.txt").unwrap().delete().unwrap();
+    v.remove("a rather long file name.txt").unwrap();
+    assert!(!v.exists("a rather long file name.txt").unwrap());
+    assert!(!v.exists("another long name here.txt").unwrap());
+    assert_clean(&v);
+}
+#[test]
+fn delete_root_remounts() {
+    let s = fat32();
+    {
+        let v = s.root().unwrap();
+        v.file_create("top.txt").unwrap().write(b"top").unwrap();
+        v.dir_create("sub").unwrap();
+        v.file_create("sub/inner.txt").unwrap().write(b"inner").unwrap();
+        v.dir_root().delete(true).unwrap();
+        v.unmount().unwrap();
+    }
+    let v = s.root().unwrap();
+    assert!(!v.exists("top.txt").unwrap());
+    assert!(!v.exists("sub").unwrap());
+    v.file_create("after.txt").unwrap().close().unwrap();
+    assert_clean(&v);
+}
+#[test]
+fn alias_hash_tail() {
+    let s = fat16();
+    let v = s.root().unwrap();
+    for i in 1..=5 {
+        v.file_create(format!("longfilename{i}.txt")).unwrap().close().unwrap();
+    }
+    assert_eq!(v.stat("longfilename4.txt").unwrap().shortname().as_raw(), b"LONGFI~4TXT");
+    // Windows switches to the first two chars and the name hash after "~4".
+    assert_eq!(v.stat("longfilename5.txt").unwrap().shortname().as_raw(), b"LO58E4~1TXT");
+    assert_clean(&v);
+}
+#[test]
+fn copy_file() {
+    let s = fat16();
+    let v = s.root().unwrap();
+    let d: Vec<u8> = (0..20000u32).map(|i| (i * 7) as u8).collect();
+    v.file_create("src.bin").unwrap().write(&d).unwrap();
+    let (mut b, mut l) = (BlockBuffer::new(), 0u32);
+    assert_eq!(v.copy("src.bin", "dst.bin", false, &mut b, |p, _| l = p).unwrap(), d.len() as u32);
+    assert_eq!(l, d.len() as u32);
+    assert!(matches!(v.copy("src.bin", "dst.bin", false, &mut b, |_, _| ()), Err(DeviceError::AlreadyExists)));
+    let mut f = v.open("dst.bin").unwrap();
+    let mut r = vec![0u8; d.len()];
+    assert_eq!(f.read(&mut r).unwrap(), d.len());
+    assert_eq!(r, d);
+    drop(f);
+    assert_clean(&v);
+}
+#[cfg(feature = "embedded")]
+#[test]
+fn storage_bounds() {
+    use embedded_storage::{ReadStorage, Storage as WriteStorage};
+    let mut s = Storage::with_cache(MemoryDevice::new(vec![0u8; 0x2000]), LocalCache::new());
+    WriteStorage::write(&mut s, 0x1FFC, b"abcd").unwrap();
+    let mut b = [0u8; 4];
+    ReadStorage::read(&mut s, 0x1FFC, &mut b).unwrap();
+    assert_eq!(&b, b"abcd");
+    assert!(matches!(ReadStorage::read(&mut s, 0x1FFD, &mut b), Err(DeviceError::InvalidIndex)));
+    assert!(matches!(WriteStorage::write(&mut s, u32::MAX, &b), Err(DeviceError::InvalidIndex)));
+}
+#[cfg(feature = "sdmmc")]
+#[test]
+fn sdmmc_blocks() {
+    use embedded_sdmmc::{Block as SdBlock, BlockDevice as SdBlockDevice, BlockIdx};
+    let s = Storage::with_cache(MemoryDevice::new(vec![0u8; 0x4000]), LocalCache::new());
+    let mut b: Vec<SdBlock> = (0..6u8).map(|i| SdBlock { contents: [i; 512] }).collect();
+    SdBlockDevice::write(&&s, &b, BlockIdx(2)).unwrap();
+    for v in b.iter_mut() {
+        v.contents = [0xFF; 512];
+    }
+    SdBlockDevice::read(&&s, &mut b, BlockIdx(2)).unwrap();
+    for (i, v) in b.iter().enumerate() {
+        assert!(v.contents.iter().all(|x| *x == i as u8));
+    }
+    let mut d = inky_frame::fs::SdmmcDevice::new(&s);
+    let mut r = [Block::new(), Block::new(), Block::new()];
+    d.read(&mut r, 3).unwrap();
+    assert!(r.iter().enumerate().all(|(i, v)| v.iter().all(|x| *x == i as u8 + 1)));
+    d.write(&r[0..1], 0).unwrap();
+    assert!(d.read(&mut r, 0x1F).is_err());
+}
+#[cfg(feature = "sdmmc")]
+#[test]
+fn sdmmc_with_reader() {
+    use embedded_sdmmc::{Block as SdBlock, BlockDevice as SdBlockDevice, BlockIdx};
+    let s = fat16();
+    let v = s.root().unwrap();
+    v.file_create("read.txt").unwrap().write(b"hello").unwrap();
+    let r = unsafe { v.open("read.txt").unwrap().into_reader().unwrap() };
+    let mut b = [SdBlock::new()];
+    SdBlockDevice::read(&&s, &mut b, BlockIdx(0)).unwrap();
+    SdBlockDevice::write(&&s, &b, BlockIdx(0)).unwrap();
+    drop(r);
+}
+#[test]
+fn file_device_image() {
+    let p = std::env::temp_dir().join(format!("inky-frame-{}.img", std::process::id()));
+    std::fs::write(&p, vec![0u8; 32 << 20]).unwrap();
+    {
+        let s = Storage::with_cache(FileDevice::open(&p).unwrap(), LocalCache::new());
+        s.format(&Format::new().kind(FatType::Fat16)).unwrap();
+        let v = s.root().unwrap();
+        v.file_create("disk.txt").unwrap().write(b"on disk").unwrap();
+        assert!(matches!(s.read_single(&mut Block::new(), 0x10000), Err(DeviceError::InvalidIndex)));
+    }
+    let s = Storage::with_cache(FileDevice::open(&p).unwrap(), LocalCache::new());
+    let v = s.root().unwrap();
+    let mut r = [0u8; 7];
+    assert_eq!(v.open("disk.txt").unwrap().read(&mut r).unwrap(), 7);
+    assert_eq!(&r, b"on disk");
+    assert_clean(&v);
+    drop(v);
+    std::fs::remove_file(&p).unwrap();
+}